@@ -0,0 +1,287 @@
+use std::sync::Arc;
+#[cfg(feature = "sqlite")]
+use std::sync::Mutex;
+
+use bevy::prelude::*;
+
+use crate::{encryption::decrypt_groups, PreferencesFileFormat};
+
+/// A destination which the serialized preferences are written to in addition to the
+/// preferences file, e.g. a cloud backup. If the preferences file is missing or can't be read,
+/// the preferences are loaded from the first backend which has them.
+pub trait PreferencesBackend: Send + Sync + 'static {
+    /// Name of the backend, used when reporting a failure.
+    fn name(&self) -> &str;
+
+    /// Store the preferences file with the given name, e.g. `prefs.toml`. `contents` is the
+    /// serialized file and `table` is the document it was serialized from.
+    fn write(&self, file_name: &str, contents: &[u8], table: &toml::Table) -> std::io::Result<()>;
+
+    /// Read back the document stored for the given file name, or `None` if there isn't one.
+    /// Backends which can't be read from don't need to implement this.
+    fn read(&self, _file_name: &str) -> std::io::Result<Option<toml::Table>> {
+        Ok(None)
+    }
+}
+
+/// Resource listing the backends which each save is also written to, in order. A backend
+/// which fails doesn't prevent writing the others; each failure is sent as a
+/// [`PreferencesError::BackendFailed`](crate::PreferencesError::BackendFailed).
+#[derive(Resource, Default, Clone)]
+pub struct PreferencesBackends(pub Vec<Arc<dyn PreferencesBackend>>);
+
+impl PreferencesBackends {
+    /// Add a backend after the ones already listed.
+    pub fn with(mut self, backend: impl PreferencesBackend) -> Self {
+        self.0.push(Arc::new(backend));
+        self
+    }
+}
+
+/// Read the main preferences document, or the document for the given profile, from the first
+/// backend which has it.
+pub(crate) fn read_from_backends(world: &World, profile: Option<&str>) -> Option<toml::Table> {
+    let backends = world.get_resource::<PreferencesBackends>()?;
+    let format = world
+        .get_resource::<PreferencesFileFormat>()
+        .cloned()
+        .unwrap_or_default();
+    let file_name = match profile {
+        Some(profile) => format.profile_file_name(profile),
+        None => format.file_name(),
+    };
+    backends
+        .0
+        .iter()
+        .find_map(|backend| match backend.read(&file_name) {
+            Ok(table) => table.map(|mut table| {
+                info!("Preferences: Loaded {} from {}", file_name, backend.name());
+                decrypt_groups(world, &mut table);
+                table
+            }),
+            Err(e) => {
+                warn!("Could not read preferences from {}: {}", backend.name(), e);
+                None
+            }
+        })
+}
+
+/// Stores preferences in a sqlite database, as rows of a `preferences(file, "group", key,
+/// value)` table. Each value is stored as TOML text; preferences without a group have an empty
+/// group. Each save replaces the rows of its file in a single transaction.
+#[cfg(feature = "sqlite")]
+pub struct SqliteBackend {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteBackend {
+    /// Use the given database connection, creating the `preferences` table if needed.
+    pub fn new(connection: rusqlite::Connection) -> rusqlite::Result<Self> {
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS preferences (
+                file TEXT NOT NULL,
+                \"group\" TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (file, \"group\", key)
+            )",
+            (),
+        )?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Open or create the database at the given path.
+    pub fn open(path: impl AsRef<std::path::Path>) -> rusqlite::Result<Self> {
+        Self::new(rusqlite::Connection::open(path)?)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl PreferencesBackend for SqliteBackend {
+    fn name(&self) -> &str {
+        "sqlite"
+    }
+
+    fn write(&self, file_name: &str, _contents: &[u8], table: &toml::Table) -> std::io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(std::io::Error::other)?;
+        transaction
+            .execute("DELETE FROM preferences WHERE file = ?1", [file_name])
+            .map_err(std::io::Error::other)?;
+        for (key, value) in table {
+            let rows: Vec<(&str, &str, &toml::Value)> = match value {
+                toml::Value::Table(group) => group
+                    .iter()
+                    .map(|(name, value)| (key.as_str(), name.as_str(), value))
+                    .collect(),
+                value => vec![("", key.as_str(), value)],
+            };
+            for (group, key, value) in rows {
+                transaction
+                    .execute(
+                        "INSERT INTO preferences (file, \"group\", key, value)
+                            VALUES (?1, ?2, ?3, ?4)",
+                        (file_name, group, key, value.to_string()),
+                    )
+                    .map_err(std::io::Error::other)?;
+            }
+        }
+        transaction.commit().map_err(std::io::Error::other)
+    }
+
+    fn read(&self, file_name: &str) -> std::io::Result<Option<toml::Table>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare("SELECT \"group\", key, value FROM preferences WHERE file = ?1")
+            .map_err(std::io::Error::other)?;
+        let rows = statement
+            .query_map([file_name], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })
+            .map_err(std::io::Error::other)?;
+        let mut table = toml::Table::new();
+        for row in rows {
+            let (group, key, text) = row.map_err(std::io::Error::other)?;
+            // Parse the value as the only entry of a one-line document.
+            let mut entry: toml::Table =
+                toml::from_str(&format!("value = {}", text)).map_err(std::io::Error::other)?;
+            let value = entry.remove("value").unwrap();
+            if group.is_empty() {
+                table.insert(key, value);
+            } else if let Some(group) = table
+                .entry(group)
+                .or_insert(toml::Value::Table(toml::Table::new()))
+                .as_table_mut()
+            {
+                group.insert(key, value);
+            }
+        }
+        Ok((!table.is_empty()).then_some(table))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use bevy::ecs::world::Command;
+
+    use super::*;
+    use crate::{PreferencesError, PreferencesGroup, PreferencesKey, SavePreferences};
+
+    /// Backend which keeps the last document it was given.
+    struct MemoryBackend {
+        name: &'static str,
+        contents: Arc<Mutex<Option<Vec<u8>>>>,
+        fail: bool,
+    }
+
+    impl PreferencesBackend for MemoryBackend {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn write(
+            &self,
+            _file_name: &str,
+            contents: &[u8],
+            _table: &toml::Table,
+        ) -> std::io::Result<()> {
+            if self.fail {
+                return Err(std::io::Error::other("offline"));
+            }
+            *self.contents.lock().unwrap() = Some(contents.to_vec());
+            Ok(())
+        }
+    }
+
+    #[derive(Resource, Reflect)]
+    #[reflect(@PreferencesGroup("audio"), @PreferencesKey("volume"))]
+    struct Volume(f32);
+
+    #[test]
+    fn test_write_to_backends() {
+        let dir = crate::tests::test_dir("backends");
+        let mut world = crate::tests::test_world(&dir);
+        world.init_resource::<Events<PreferencesError>>();
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<Volume>();
+        world.insert_resource(Volume(0.5));
+        let first = Arc::new(Mutex::new(None));
+        let second = Arc::new(Mutex::new(None));
+        world.insert_resource(
+            PreferencesBackends::default()
+                .with(MemoryBackend {
+                    name: "first",
+                    contents: first.clone(),
+                    fail: false,
+                })
+                .with(MemoryBackend {
+                    name: "offline",
+                    contents: Arc::default(),
+                    fail: true,
+                })
+                .with(MemoryBackend {
+                    name: "second",
+                    contents: second.clone(),
+                    fail: false,
+                }),
+        );
+        SavePreferences::Always.apply(&mut world);
+
+        let file = std::fs::read(dir.join("prefs.toml")).unwrap();
+        assert_eq!(first.lock().unwrap().as_ref(), Some(&file));
+        assert_eq!(second.lock().unwrap().as_ref(), Some(&file));
+        let events = world.resource::<Events<PreferencesError>>();
+        let errors: Vec<_> = events.iter_current_update_events().collect();
+        assert!(matches!(
+            errors[..],
+            [PreferencesError::BackendFailed { backend, .. }] if backend == "offline"
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_round_trip() {
+        let dir = crate::tests::test_dir("sqlite");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<Volume>();
+        world.insert_resource(Volume(0.5));
+        let backend =
+            Arc::new(SqliteBackend::new(rusqlite::Connection::open_in_memory().unwrap()).unwrap());
+        world.insert_resource(PreferencesBackends(vec![backend.clone()]));
+        SavePreferences::Always.apply(&mut world);
+
+        let row: (String, String, String) = backend
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT \"group\", key, value FROM preferences WHERE file = 'prefs.toml'",
+                (),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(row, ("audio".into(), "volume".into(), "0.5".into()));
+
+        // Without the preferences file, the preferences are loaded from the database.
+        std::fs::remove_file(dir.join("prefs.toml")).unwrap();
+        world.insert_resource(Volume(1.0));
+        crate::load::load_preferences(&mut world);
+        assert_eq!(world.resource::<Volume>().0, 0.5);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    any::TypeId,
+    sync::{Arc, Mutex},
+};
+
+use bevy::{
+    ecs::component::{ComponentId, Tick},
+    prelude::*,
+    reflect::{ReflectFromPtr, TypeInfo, TypeRegistry},
+};
+
+use crate::{
+    registration::{is_transparent_wrapper, preference_names, PreferencesRegistrations},
+    PreferencesGroup, PreferencesKey,
+};
+
+/// Resource holding the list of preference resources, so that saves don't look up every
+/// resource of the world in the type registry, nor resolve its group and key again. The list is
+/// rebuilt when components or types are registered, or when the [`PreferencesRegistrations`]
+/// change.
+#[derive(Resource, Default)]
+pub(crate) struct PreferencesResourceCache(Mutex<Option<Arc<PreferenceResources>>>);
+
+/// The resources which may be saved as preferences.
+pub(crate) struct PreferenceResources {
+    key: CacheKey,
+    /// Resources whose types are preferences, in component order.
+    pub(crate) resources: Vec<PreferenceResource>,
+    /// Resources which can't be saved, along with the reason.
+    pub(crate) skipped: Vec<(ComponentId, &'static str)>,
+}
+
+/// A preference resource, with everything a save needs to know about its type.
+pub(crate) struct PreferenceResource {
+    pub(crate) id: ComponentId,
+    pub(crate) type_id: TypeId,
+    pub(crate) type_info: &'static TypeInfo,
+    pub(crate) group: Option<PreferencesGroup>,
+    pub(crate) key: Option<PreferencesKey>,
+    pub(crate) reflect_from_ptr: ReflectFromPtr,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct CacheKey {
+    components: usize,
+    types: usize,
+    registrations: Option<Tick>,
+}
+
+/// The preference resources of the world, from the cache if it is up to date.
+pub(crate) fn preference_resources(
+    world: &World,
+    registry: &TypeRegistry,
+) -> Arc<PreferenceResources> {
+    let key = CacheKey {
+        components: world.components().len(),
+        // `TypeRegistry` has no `len`, but its iterator knows its exact size without iterating.
+        types: registry.iter().size_hint().0,
+        registrations: world
+            .get_resource_ref::<PreferencesRegistrations>()
+            .map(|registrations| registrations.last_changed()),
+    };
+    let Some(cache) = world.get_resource::<PreferencesResourceCache>() else {
+        return Arc::new(find_preference_resources(world, registry, key));
+    };
+    let mut cached = cache.0.lock().unwrap();
+    match &*cached {
+        Some(resources) if resources.key == key => resources.clone(),
+        _ => {
+            let resources = Arc::new(find_preference_resources(world, registry, key));
+            *cached = Some(resources.clone());
+            resources
+        }
+    }
+}
+
+fn find_preference_resources(
+    world: &World,
+    registry: &TypeRegistry,
+    key: CacheKey,
+) -> PreferenceResources {
+    let registrations = world.get_resource::<PreferencesRegistrations>();
+    let mut resources = Vec::new();
+    let mut skipped = Vec::new();
+    for info in world.components().iter() {
+        let Some(tid) = info.type_id() else {
+            skipped.push((info.id(), "no type id"));
+            continue;
+        };
+        // Components which aren't resources are never part of the preferences.
+        if world.components().get_resource_id(tid) != Some(info.id()) {
+            continue;
+        }
+        match registry.get(tid) {
+            Some(treg) if is_preference_type(registrations, treg.type_info()) => {
+                let Some(reflect_from_ptr) = treg.data::<ReflectFromPtr>() else {
+                    skipped.push((info.id(), "no ReflectFromPtr"));
+                    continue;
+                };
+                let (group, key) = preference_names(registrations, treg.type_info());
+                resources.push(PreferenceResource {
+                    id: info.id(),
+                    type_id: tid,
+                    type_info: treg.type_info(),
+                    group: group.cloned(),
+                    key: key.cloned(),
+                    reflect_from_ptr: reflect_from_ptr.clone(),
+                });
+            }
+            Some(_) => {}
+            None if registrations.is_some_and(|r| r.get(tid).is_some()) => {
+                skipped.push((info.id(), "not in the type registry"));
+            }
+            None => {}
+        }
+    }
+    PreferenceResources {
+        key,
+        resources,
+        skipped,
+    }
+}
+
+/// Returns true for the types which are saved to the preferences file.
+fn is_preference_type(registrations: Option<&PreferencesRegistrations>, info: &TypeInfo) -> bool {
+    let (group_attr, key_attr) = preference_names(registrations, info);
+    let named = group_attr.is_some() || key_attr.is_some();
+    match info {
+        TypeInfo::Struct(_) | TypeInfo::Enum(_) => named,
+        TypeInfo::TupleStruct(tsty) => named || is_transparent_wrapper(tsty),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::world::Command;
+
+    use super::*;
+    use crate::{PreferencesGroup, SavePreferences};
+
+    #[derive(Resource, Reflect)]
+    #[reflect(@PreferencesGroup("audio"))]
+    struct AudioSettings {
+        volume: f32,
+    }
+
+    #[derive(Resource, Reflect)]
+    #[reflect(@PreferencesGroup("video"))]
+    struct VideoSettings {
+        fullscreen: bool,
+    }
+
+    fn cached(world: &World) -> Arc<PreferenceResources> {
+        world
+            .resource::<PreferencesResourceCache>()
+            .0
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_resource_cache() {
+        let dir = crate::tests::test_dir("resource_cache");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<AudioSettings>();
+        world.insert_resource(AudioSettings { volume: 0.5 });
+        SavePreferences::Always.apply(&mut world);
+        let first = cached(&world);
+        assert_eq!(first.resources.len(), 1);
+        assert_eq!(
+            first.resources[0].group.as_ref().map(|group| group.0),
+            Some("audio")
+        );
+        assert!(first.resources[0].key.is_none());
+
+        // An unchanged world reuses the list.
+        world.resource_mut::<AudioSettings>().volume = 0.25;
+        SavePreferences::Always.apply(&mut world);
+        assert!(Arc::ptr_eq(&first, &cached(&world)));
+        assert_eq!(
+            std::fs::read_to_string(dir.join("prefs.toml")).unwrap(),
+            "[audio]\nvolume = 0.25\n"
+        );
+
+        // Registering a type rebuilds it, even without a new resource.
+        #[derive(Reflect)]
+        struct Unrelated;
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<Unrelated>();
+        SavePreferences::Always.apply(&mut world);
+        assert!(!Arc::ptr_eq(&first, &cached(&world)));
+        let first = cached(&world);
+        assert_eq!(first.resources.len(), 1);
+
+        // A new preference type rebuilds it.
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<VideoSettings>();
+        world.insert_resource(VideoSettings { fullscreen: true });
+        SavePreferences::Always.apply(&mut world);
+        let second = cached(&world);
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(second.resources.len(), 2);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("prefs.toml")).unwrap(),
+            "[audio]\nvolume = 0.25\n\n[video]\nfullscreen = true\n"
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,1045 @@
+//! Conversion between reflected values and TOML values. Nothing in this module touches the
+//! filesystem or the ECS world, so it can be used and tested on its own.
+
+use std::{any::TypeId, sync::Arc, time::Duration};
+use thiserror::Error;
+
+use bevy::{
+    log::warn,
+    reflect::{
+        DynamicArray, DynamicEnum, DynamicList, DynamicStruct, DynamicTuple, DynamicVariant, Enum,
+        EnumInfo, Map, PartialReflect, ReflectDeserialize, ReflectMut, ReflectRef,
+        ReflectSerialize, Struct, Tuple, TypeInfo, TypeRegistry, VariantInfo, VariantType,
+    },
+};
+
+use crate::{
+    keys::normalize_key,
+    references::{is_reference, referenced_group},
+    supported_preference_types, PreferencesArrayTypes, PreferencesDurationFormat,
+    PreferencesEnumIndex, PreferencesNone, PreferencesSkip, PreferencesVariantMatching,
+};
+
+/// Settings for encoding the values of the preference resources.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct EncodeContext<'a> {
+    /// If given, opaque values of types which aren't supported directly are encoded with serde.
+    pub(crate) fallback: Option<&'a TypeRegistry>,
+    /// Struct types which are encoded as an array of their fields.
+    pub(crate) array_types: Option<&'a PreferencesArrayTypes>,
+    /// How durations are written.
+    pub(crate) durations: PreferencesDurationFormat,
+}
+
+/// Settings for decoding the values in a preferences document.
+#[derive(Clone, Copy)]
+pub(crate) struct DecodeContext<'a> {
+    /// The registry holding the types of list elements, map values and variant fields.
+    pub(crate) registry: &'a TypeRegistry,
+    /// How enum variant names are matched.
+    pub(crate) matching: PreferencesVariantMatching,
+}
+
+impl<'a> DecodeContext<'a> {
+    /// A context which matches variant names exactly.
+    #[cfg(test)]
+    pub(crate) fn new(registry: &'a TypeRegistry) -> Self {
+        Self {
+            registry,
+            matching: PreferencesVariantMatching::default(),
+        }
+    }
+}
+
+/// Encode the fields of a struct into the given table.
+pub(crate) fn save_struct(strct: &dyn Struct, table: &mut toml::Table, cx: EncodeContext) {
+    for i in 0..strct.field_len() {
+        if is_skipped(strct, i) {
+            continue;
+        }
+        let field_reflect = strct.field_at(i).unwrap();
+        if is_explicit_none(strct, i, field_reflect) {
+            table.insert(
+                normalize_key(strct.name_at(i).unwrap()).into_owned(),
+                toml::Value::Array(Vec::new()),
+            );
+            continue;
+        }
+        if is_reference(strct, i) {
+            if let Some(group) = referenced_group(field_reflect) {
+                table.insert(
+                    normalize_key(strct.name_at(i).unwrap()).into_owned(),
+                    toml::Value::String(group.to_string()),
+                );
+            }
+            continue;
+        }
+        match field_reflect.reflect_ref() {
+            ReflectRef::TupleStruct(_) => todo!(),
+            ReflectRef::Set(_) => todo!(),
+            ReflectRef::Struct(_)
+            | ReflectRef::Tuple(_)
+            | ReflectRef::Map(_)
+            | ReflectRef::List(_)
+            | ReflectRef::Array(_)
+            | ReflectRef::Enum(_)
+            | ReflectRef::Opaque(_) => {
+                store_prop(field_reflect, strct.name_at(i).unwrap(), table, cx);
+            }
+        }
+    }
+}
+
+/// Returns true if the field of the struct at the given index is marked with
+/// [`PreferencesSkip`].
+pub(crate) fn is_skipped(strct: &dyn Struct, index: usize) -> bool {
+    strct
+        .get_represented_struct_info()
+        .and_then(|info| info.field_at(index))
+        .is_some_and(|field| field.custom_attributes().contains::<PreferencesSkip>())
+}
+
+/// Returns true if the field of the struct at the given index is an `Option` which is `None`,
+/// and is marked with [`PreferencesNone::Explicit`].
+fn is_explicit_none(strct: &dyn Struct, index: usize, field: &dyn PartialReflect) -> bool {
+    let (ReflectRef::Enum(en), Some(ty @ TypeInfo::Enum(enum_ty))) =
+        (field.reflect_ref(), field.get_represented_type_info())
+    else {
+        return false;
+    };
+    if !is_option(ty) || en.variant_name() != "None" {
+        return false;
+    }
+    let Some(field_info) = strct
+        .get_represented_struct_info()
+        .and_then(|info| info.field_at(index))
+    else {
+        return false;
+    };
+    if field_info.custom_attributes().get::<PreferencesNone>() != Some(&PreferencesNone::Explicit) {
+        return false;
+    }
+    let inner = match enum_ty.variant("Some") {
+        Some(VariantInfo::Tuple(variant)) => {
+            variant.field_at(0).and_then(|field| field.type_info())
+        }
+        _ => None,
+    };
+    match inner {
+        // An empty array is the `Some(None)` of an `Option<Option<T>>`.
+        Some(inner) if is_option(inner) => false,
+        // An empty array is also an empty list, so the key is left out instead.
+        Some(TypeInfo::List(_) | TypeInfo::Array(_)) => {
+            warn!(
+                "Preferences: {} can't be written as an explicit None, as [] is an empty list",
+                field_info.name()
+            );
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Encode a reflected property and store it in the table with the given key.
+pub(crate) fn store_prop(
+    value: &dyn PartialReflect,
+    key: &str,
+    table: &mut toml::Table,
+    cx: EncodeContext,
+) {
+    if let Some(v) = encode_prop(value, cx) {
+        table.insert(normalize_key(key).into_owned(), v);
+    }
+}
+
+/// Encode a reflected property as a TOML value. Returns `None` if the property is left out of
+/// the document, either because it's an `Option` which is `None` or because its type is not
+/// supported. Opaque values of other types are encoded with serde if the context has a
+/// fallback and the type registers `ReflectSerialize`.
+pub(crate) fn encode_prop(value: &dyn PartialReflect, cx: EncodeContext) -> Option<toml::Value> {
+    match value.reflect_ref() {
+        ReflectRef::Struct(st)
+            if cx
+                .array_types
+                .is_some_and(|array_types| array_types.contains(value.reflect_type_path())) =>
+        {
+            encode_items(st.iter_fields(), value, cx)
+        }
+        ReflectRef::Struct(st) => {
+            let mut field_table = toml::Table::new();
+            save_struct(st, &mut field_table, cx);
+            Some(toml::Value::Table(field_table))
+        }
+
+        ReflectRef::TupleStruct(_) => todo!(),
+        ReflectRef::Tuple(tuple) => encode_items(tuple.iter_fields(), value, cx),
+        ReflectRef::List(list) => encode_items(list.iter(), value, cx),
+        ReflectRef::Array(array) => encode_items(array.iter(), value, cx),
+        ReflectRef::Map(map) => encode_map(map, cx),
+        ReflectRef::Set(_) => todo!(),
+
+        ReflectRef::Enum(en) => {
+            let type_path = value.get_represented_type_info().unwrap().type_path();
+            if type_path.starts_with("core::option::Option") {
+                // None values just leave out the key.
+                if en.variant_name() == "Some" {
+                    let inner = en.field_at(0).unwrap();
+                    match inner.reflect_ref() {
+                        // The `None` of an `Option<Option<T>>` can't leave out the key too, so
+                        // it gets a value of its own.
+                        ReflectRef::Enum(inner_en)
+                            if is_option(inner.get_represented_type_info().unwrap())
+                                && inner_en.variant_name() == "None" =>
+                        {
+                            Some(toml::Value::Array(Vec::new()))
+                        }
+                        _ => encode_prop(inner, cx),
+                    }
+                } else {
+                    None
+                }
+            } else {
+                encode_enum(en, type_path, cx)
+            }
+        }
+
+        ReflectRef::Opaque(val) => {
+            if let Some(duration) = value.try_downcast_ref::<Duration>() {
+                return Some(encode_duration(*duration, cx.durations));
+            }
+
+            if let Some(f) = value.try_downcast_ref::<f32>() {
+                Some(toml::Value::Float(widen_f32(*f)))
+            } else if let Some(f) = value.try_downcast_ref::<f64>() {
+                Some(toml::Value::Float(*f))
+            } else if let Some(i) = value.try_downcast_ref::<i8>() {
+                Some(toml::Value::Integer(*i as i64))
+            } else if let Some(i) = value.try_downcast_ref::<i16>() {
+                Some(toml::Value::Integer(*i as i64))
+            } else if let Some(i) = value.try_downcast_ref::<i32>() {
+                Some(toml::Value::Integer(*i as i64))
+            } else if let Some(i) = value.try_downcast_ref::<i64>() {
+                Some(toml::Value::Integer(*i))
+            } else if let Some(i) = value.try_downcast_ref::<u8>() {
+                Some(toml::Value::Integer(*i as i64))
+            } else if let Some(i) = value.try_downcast_ref::<u16>() {
+                Some(toml::Value::Integer(*i as i64))
+            } else if let Some(i) = value.try_downcast_ref::<u32>() {
+                Some(toml::Value::Integer(*i as i64))
+            } else if let Some(i) = value.try_downcast_ref::<u64>() {
+                if *i <= i64::MAX as u64 {
+                    Some(toml::Value::Integer(*i as i64))
+                } else {
+                    warn!("Preferences: u64 value too large: {}", i);
+                    None
+                }
+            } else if let Some(i) = value.try_downcast_ref::<usize>() {
+                if *i <= i64::MAX as usize {
+                    Some(toml::Value::Integer(*i as i64))
+                } else {
+                    warn!("Preferences: usize value too large: {}", i);
+                    None
+                }
+            } else if let Some(b) = value.try_downcast_ref::<bool>() {
+                Some(toml::Value::Boolean(*b))
+            } else if let Some(s) = value.try_downcast_ref::<String>() {
+                Some(toml::Value::String(s.clone()))
+            } else if let Some(s) = value.try_downcast_ref::<Arc<str>>() {
+                Some(toml::Value::String(s.to_string()))
+            } else if let Some(s) = value.try_downcast_ref::<Arc<String>>() {
+                Some(toml::Value::String(s.to_string()))
+            } else if let Some(registry) = cx.fallback {
+                encode_serialized(registry, value)
+            } else {
+                warn!("Preferences: Unsupported type: {:?}", val);
+                None
+            }
+        }
+    }
+}
+
+/// Encode an opaque value of a type which isn't supported directly, using its
+/// `ReflectSerialize` registration, e.g. from `#[reflect(opaque, Serialize, Deserialize)]`.
+fn encode_serialized(registry: &TypeRegistry, value: &dyn PartialReflect) -> Option<toml::Value> {
+    let type_path = value.reflect_type_path();
+    let Some(serialize) = value.try_as_reflect().and_then(|value| {
+        registry.get_type_data::<ReflectSerialize>(value.reflect_type_info().type_id())
+    }) else {
+        warn!("Preferences: Unsupported type: {}", type_path);
+        return None;
+    };
+    let serializable = serialize.get_serializable(value.try_as_reflect().unwrap());
+    match toml::Value::try_from(&*serializable) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            warn!("Preferences: Could not serialize {}: {}", type_path, e);
+            None
+        }
+    }
+}
+
+/// Encode the elements of an array or list. An element which can't be encoded would shift the
+/// ones after it, so the whole value is left out instead.
+fn encode_items<'a>(
+    items: impl Iterator<Item = &'a dyn PartialReflect>,
+    value: &dyn PartialReflect,
+    cx: EncodeContext,
+) -> Option<toml::Value> {
+    let items = items
+        .map(|item| encode_prop(item, cx))
+        .collect::<Option<Vec<_>>>();
+    if items.is_none() {
+        warn!(
+            "Preferences: Unsupported element in {}",
+            value.reflect_type_path()
+        );
+    }
+    items.map(toml::Value::Array)
+}
+
+/// Encode a map with string keys as a table. Entries whose value is left out, such as a `None`,
+/// are left out of the table.
+fn encode_map(map: &dyn Map, cx: EncodeContext) -> Option<toml::Value> {
+    let mut table = toml::Table::new();
+    for (key, value) in map.iter() {
+        let Some(key) = key.try_downcast_ref::<String>() else {
+            warn!(
+                "Preferences: Unsupported key type {} in {}",
+                key.reflect_type_path(),
+                map.reflect_type_path()
+            );
+            return None;
+        };
+        if let Some(value) = encode_prop(value, cx) {
+            table.insert(key.clone(), value);
+        }
+    }
+    Some(toml::Value::Table(table))
+}
+
+/// Encode an enum value as the variant name for a unit variant, or as a table with the variant
+/// name as its only key for a newtype variant, e.g. `{ Custom = 0.5 }`.
+fn encode_enum(en: &dyn Enum, type_path: &str, cx: EncodeContext) -> Option<toml::Value> {
+    match en.variant_type() {
+        VariantType::Unit => Some(encode_unit_variant(en)),
+        VariantType::Tuple => {
+            // A newtype variant stores its field directly, and other tuple variants store an
+            // array of their fields.
+            let payload = if en.field_len() == 1 {
+                encode_prop(en.field_at(0).unwrap(), cx)?
+            } else {
+                toml::Value::Array(
+                    en.iter_fields()
+                        .map(|field| encode_prop(field.value(), cx))
+                        .collect::<Option<_>>()?,
+                )
+            };
+            Some(variant_table(en, payload))
+        }
+        VariantType::Struct => {
+            // Fields which are left out, such as `None` options, load as `None` again.
+            let fields = en
+                .iter_fields()
+                .filter_map(|field| {
+                    let name = normalize_key(field.name().unwrap()).into_owned();
+                    Some((name, encode_prop(field.value(), cx)?))
+                })
+                .collect();
+            Some(variant_table(en, toml::Value::Table(fields)))
+        }
+    }
+    .or_else(|| {
+        warn!(
+            "Preferences: Unsupported enum variant: {}::{}",
+            type_path,
+            en.variant_name()
+        );
+        None
+    })
+}
+
+/// A table with the variant's payload under the name of the variant, e.g.
+/// `{ Fade = { duration = 1.5 } }`.
+fn variant_table(en: &dyn Enum, payload: toml::Value) -> toml::Value {
+    toml::Value::Table(toml::Table::from_iter([(
+        en.variant_name().to_string(),
+        payload,
+    )]))
+}
+
+/// Encode a unit variant as its name, or as its index if the enum has the
+/// [`PreferencesEnumIndex`] annotation.
+pub(crate) fn encode_unit_variant(en: &dyn Enum) -> toml::Value {
+    match en.get_represented_type_info() {
+        Some(TypeInfo::Enum(enum_ty))
+            if enum_ty
+                .custom_attributes()
+                .contains::<PreferencesEnumIndex>() =>
+        {
+            toml::Value::Integer(en.variant_index() as i64)
+        }
+        _ => toml::Value::String(en.variant_name().to_string()),
+    }
+}
+
+/// Look up a unit variant which is stored as its index.
+pub(crate) fn unit_variant_at(enum_ty: &EnumInfo, index: i64) -> Option<&VariantInfo> {
+    let variant = enum_ty.variant_at(usize::try_from(index).ok()?)?;
+    matches!(variant, VariantInfo::Unit(_)).then_some(variant)
+}
+
+/// Encode a duration as a number of seconds, or as a human-readable string.
+fn encode_duration(duration: Duration, format: PreferencesDurationFormat) -> toml::Value {
+    match format {
+        PreferencesDurationFormat::Seconds => toml::Value::Float(duration.as_secs_f64()),
+        #[cfg(feature = "humantime")]
+        PreferencesDurationFormat::Human => {
+            toml::Value::String(humantime::format_duration(duration).to_string())
+        }
+    }
+}
+
+/// Parse a human-readable duration, e.g. `"1500ms"` or `"1m 30s"`. Without the `humantime`
+/// feature, durations can only be stored as numbers.
+fn parse_duration(text: &str) -> Option<Duration> {
+    #[cfg(feature = "humantime")]
+    return humantime::parse_duration(text).ok();
+    #[cfg(not(feature = "humantime"))]
+    {
+        let _ = text;
+        None
+    }
+}
+
+/// Widen an `f32` to the `f64` with the same shortest decimal representation, so that e.g.
+/// `0.1f32` is written as `0.1` rather than `0.10000000149011612`. Rust's float formatting
+/// is shortest-round-trip and platform independent, so the output is identical everywhere,
+/// and the value still converts back to exactly the same `f32` on load.
+fn widen_f32(value: f32) -> f64 {
+    if value.is_finite() {
+        value.to_string().parse().unwrap()
+    } else {
+        value as f64
+    }
+}
+
+/// Load a TOML array into a fixed-size array field. If the number of elements doesn't match
+/// the length of the array, or any element can't be decoded, the field is left unchanged.
+pub(crate) fn load_array(field: &mut dyn PartialReflect, value: &toml::Value) {
+    let ReflectMut::Array(array) = field.reflect_mut() else {
+        return;
+    };
+    let Some(items) = value.as_array() else {
+        warn!(
+            "Preferences: Expected an array for {}",
+            array.reflect_type_path()
+        );
+        return;
+    };
+    if items.len() != array.len() {
+        warn!(
+            "Preferences: Expected {} elements for {}, found {}",
+            array.len(),
+            array.reflect_type_path(),
+            items.len()
+        );
+        return;
+    }
+    let mut decoded = Vec::with_capacity(items.len());
+    for (i, item) in items.iter().enumerate() {
+        let ty = array.get(i).unwrap().get_represented_type_info().unwrap();
+        match decode_value_boxed(ty, item) {
+            Ok(value) => decoded.push(value),
+            Err(_) => return,
+        }
+    }
+    for (i, value) in decoded.into_iter().enumerate() {
+        array.get_mut(i).unwrap().apply(value.as_partial_reflect());
+    }
+}
+
+/// Load a TOML array into a tuple field, matching the elements by position; a nested tuple is
+/// a nested array. If the number of elements doesn't match the length of the tuple, or any
+/// element can't be decoded, the field is left unchanged.
+pub(crate) fn load_tuple(field: &mut dyn PartialReflect, value: &toml::Value) {
+    let ReflectRef::Tuple(tuple) = field.reflect_ref() else {
+        return;
+    };
+    if let Some(decoded) = decode_tuple(tuple, value) {
+        field.apply(&decoded);
+    }
+}
+
+fn decode_tuple(tuple: &dyn Tuple, value: &toml::Value) -> Option<DynamicTuple> {
+    let Some(items) = value.as_array() else {
+        warn!(
+            "Preferences: Expected an array for {}",
+            tuple.reflect_type_path()
+        );
+        return None;
+    };
+    if items.len() != tuple.field_len() {
+        warn!(
+            "Preferences: Expected {} elements for {}, found {}",
+            tuple.field_len(),
+            tuple.reflect_type_path(),
+            items.len()
+        );
+        return None;
+    }
+    let mut decoded = DynamicTuple::default();
+    for (field, item) in tuple.iter_fields().zip(items) {
+        match field.reflect_ref() {
+            ReflectRef::Tuple(inner) => decoded.insert(decode_tuple(inner, item)?),
+            _ => {
+                let ty = field.get_represented_type_info()?;
+                decoded.insert_boxed(decode_value_boxed(ty, item).ok()?);
+            }
+        }
+    }
+    Some(decoded)
+}
+
+/// Load a TOML array into a list field, replacing its elements. If any element can't be
+/// decoded, the field is left unchanged.
+pub(crate) fn load_list(cx: DecodeContext, field: &mut dyn PartialReflect, value: &toml::Value) {
+    let Some(TypeInfo::List(list_ty)) = field.get_represented_type_info() else {
+        return;
+    };
+    let ReflectMut::List(list) = field.reflect_mut() else {
+        return;
+    };
+    let Some(items) = value.as_array() else {
+        warn!("Preferences: Expected an array for {}", list_ty.type_path());
+        return;
+    };
+    let Some(item_ty) = cx.registry.get(list_ty.item_ty().id()) else {
+        warn!(
+            "Preferences: Element type of {} is not in the type registry",
+            list_ty.type_path()
+        );
+        return;
+    };
+    // Each element is decoded according to its own kind, so a list of enums may mix unit and
+    // newtype variants.
+    let Some(decoded) = items
+        .iter()
+        .map(|item| decode_element(cx, item_ty.type_info(), item))
+        .collect::<Option<Vec<_>>>()
+    else {
+        return;
+    };
+    list.drain();
+    for value in decoded {
+        list.push(value);
+    }
+}
+
+/// Decode an opaque value. Types which aren't supported directly are decoded with serde if
+/// they register `ReflectDeserialize`, which reads the values written by the serde fallback.
+pub(crate) fn decode_opaque(
+    registry: &TypeRegistry,
+    ty: &TypeInfo,
+    value: &toml::Value,
+) -> Option<Box<dyn PartialReflect>> {
+    if !supported_preference_types().contains(&ty.type_path()) {
+        if let Some(deserialize) = registry.get_type_data::<ReflectDeserialize>(ty.type_id()) {
+            return match deserialize.deserialize(value.clone()) {
+                Ok(value) => Some(value.into_partial_reflect()),
+                Err(e) => {
+                    warn!(
+                        "Preferences: Could not deserialize {}: {}",
+                        ty.type_path(),
+                        e
+                    );
+                    None
+                }
+            };
+        }
+    }
+    decode_value_boxed(ty, value).ok()
+}
+
+/// Decode a TOML value into a new value of the given type, for the elements of lists and the
+/// fields of enum variants. Returns `None` if the value can't be decoded.
+fn decode_element(
+    cx: DecodeContext,
+    ty: &'static TypeInfo,
+    value: &toml::Value,
+) -> Option<Box<dyn PartialReflect>> {
+    match ty {
+        TypeInfo::Enum(_) => {
+            decode_enum(cx, ty, value).map(|value| Box::new(value) as Box<dyn PartialReflect>)
+        }
+        TypeInfo::Opaque(_) => decode_opaque(cx.registry, ty, value),
+        // Lists and arrays as elements, e.g. the value of an `Option<Vec<T>>`.
+        TypeInfo::List(list_ty) => {
+            let items = decode_items(cx, ty, list_ty.item_ty().id(), value)?;
+            let mut list = DynamicList::from_iter(items);
+            list.set_represented_type(Some(ty));
+            Some(Box::new(list))
+        }
+        TypeInfo::Array(array_ty) => {
+            let items = decode_items(cx, ty, array_ty.item_ty().id(), value)?;
+            if items.len() != array_ty.capacity() {
+                warn!(
+                    "Preferences: Expected {} elements for {}, found {}",
+                    array_ty.capacity(),
+                    ty.type_path(),
+                    items.len()
+                );
+                return None;
+            }
+            let mut array = DynamicArray::new(items.into_boxed_slice());
+            array.set_represented_type(Some(ty));
+            Some(Box::new(array))
+        }
+        _ => {
+            warn!("Preferences: Unsupported element type: {}", ty.type_path());
+            None
+        }
+    }
+}
+
+/// Decode each element of a TOML array holding the items of a list or array of type `ty`.
+fn decode_items(
+    cx: DecodeContext,
+    ty: &TypeInfo,
+    item_id: TypeId,
+    value: &toml::Value,
+) -> Option<Vec<Box<dyn PartialReflect>>> {
+    let Some(items) = value.as_array() else {
+        warn!("Preferences: Expected an array for {}", ty.type_path());
+        return None;
+    };
+    let Some(item_ty) = cx.registry.get(item_id) else {
+        warn!(
+            "Preferences: Element type of {} is not in the type registry",
+            ty.type_path()
+        );
+        return None;
+    };
+    items
+        .iter()
+        .map(|item| decode_element(cx, item_ty.type_info(), item))
+        .collect()
+}
+
+/// Returns true if the type is an `Option`.
+pub(crate) fn is_option(ty: &TypeInfo) -> bool {
+    ty.type_path().starts_with("core::option::Option")
+}
+
+/// Decode the value stored for an `Option`, where a missing key is `None`. The `Some(None)` of
+/// an `Option<Option<T>>` is stored as an empty array, `[]`, since TOML has no null; so for an
+/// `Option<Option<Vec<T>>>`, an empty list loads as `Some(None)`.
+pub(crate) fn decode_option(
+    cx: DecodeContext,
+    ty: &'static TypeInfo,
+    value: Option<&toml::Value>,
+) -> Option<DynamicEnum> {
+    let TypeInfo::Enum(enum_ty) = ty else {
+        return None;
+    };
+    let mut dynamic_enum = match value {
+        None => DynamicEnum::new("None", DynamicVariant::Unit),
+        Some(value) => {
+            let Some(VariantInfo::Tuple(variant)) = enum_ty.variant("Some") else {
+                return None;
+            };
+            let field = variant.field_at(0).unwrap();
+            let Some(field_ty) = cx.registry.get(field.type_id()) else {
+                warn!(
+                    "Preferences: {} is not in the type registry",
+                    field.type_path()
+                );
+                return None;
+            };
+            let field_ty = field_ty.type_info();
+            // An empty array is an explicit `None`, unless it can be a value of the field.
+            if value.as_array().is_some_and(|array| array.is_empty())
+                && !is_option(field_ty)
+                && !matches!(field_ty, TypeInfo::List(_) | TypeInfo::Array(_))
+            {
+                let mut dynamic_enum = DynamicEnum::new("None", DynamicVariant::Unit);
+                dynamic_enum.set_represented_type(Some(ty));
+                return Some(dynamic_enum);
+            }
+            let field_value: Box<dyn PartialReflect> = if is_option(field_ty) {
+                let inner = match value {
+                    toml::Value::Array(array) if array.is_empty() => None,
+                    value => Some(value),
+                };
+                Box::new(decode_option(cx, field_ty, inner)?)
+            } else {
+                decode_element(cx, field_ty, value)?
+            };
+            let mut tuple = DynamicTuple::default();
+            tuple.insert_boxed(field_value);
+            DynamicEnum::new("Some", DynamicVariant::Tuple(tuple))
+        }
+    };
+    dynamic_enum.set_represented_type(Some(ty));
+    Some(dynamic_enum)
+}
+
+/// Decode an enum value, which is stored as the variant name for a unit variant, or as a table
+/// with the variant name as its only key for a newtype variant, e.g. `{ Custom = 0.5 }`.
+pub(crate) fn decode_enum(
+    cx: DecodeContext,
+    ty: &'static TypeInfo,
+    value: &toml::Value,
+) -> Option<DynamicEnum> {
+    let TypeInfo::Enum(enum_ty) = ty else {
+        return None;
+    };
+    let mut dynamic_enum = match value {
+        toml::Value::String(name) => match find_variant(enum_ty, name, cx.matching) {
+            Some(VariantInfo::Unit(variant)) => {
+                DynamicEnum::new(variant.name(), DynamicVariant::Unit)
+            }
+            _ => {
+                warn!("Preferences: Unknown variant: {}", name);
+                return None;
+            }
+        },
+        toml::Value::Integer(index) => match unit_variant_at(enum_ty, *index) {
+            Some(variant) => DynamicEnum::new(variant.name(), DynamicVariant::Unit),
+            None => {
+                warn!("Preferences: Unknown variant index: {}", index);
+                return None;
+            }
+        },
+        toml::Value::Table(table) if table.len() == 1 => {
+            let (name, payload) = table.iter().next().unwrap();
+            match (find_variant(enum_ty, name, cx.matching), payload) {
+                (Some(VariantInfo::Tuple(variant)), _) if variant.field_len() == 1 => {
+                    let field = variant.field_at(0).unwrap();
+                    let mut tuple = DynamicTuple::default();
+                    tuple.insert_boxed(decode_variant_field(
+                        cx,
+                        field.type_id(),
+                        field.type_path(),
+                        payload,
+                    )?);
+                    DynamicEnum::new(variant.name(), DynamicVariant::Tuple(tuple))
+                }
+                (Some(VariantInfo::Tuple(variant)), toml::Value::Array(items))
+                    if items.len() == variant.field_len() =>
+                {
+                    let mut tuple = DynamicTuple::default();
+                    for (field, item) in variant.iter().zip(items) {
+                        tuple.insert_boxed(decode_variant_field(
+                            cx,
+                            field.type_id(),
+                            field.type_path(),
+                            item,
+                        )?);
+                    }
+                    DynamicEnum::new(variant.name(), DynamicVariant::Tuple(tuple))
+                }
+                (Some(VariantInfo::Struct(variant)), toml::Value::Table(fields)) => {
+                    let mut strct = DynamicStruct::default();
+                    for field in variant.iter() {
+                        let value = match fields.get(normalize_key(field.name()).as_ref()) {
+                            Some(item) => {
+                                decode_variant_field(cx, field.type_id(), field.type_path(), item)?
+                            }
+                            None => match field.type_info() {
+                                Some(ty) if is_option(ty) => Box::new(decode_option(cx, ty, None)?),
+                                _ => {
+                                    warn!(
+                                        "Preferences: Missing field {} of {}::{}",
+                                        field.name(),
+                                        enum_ty.type_path(),
+                                        name
+                                    );
+                                    return None;
+                                }
+                            },
+                        };
+                        strct.insert_boxed(field.name(), value);
+                    }
+                    DynamicEnum::new(variant.name(), DynamicVariant::Struct(strct))
+                }
+                _ => {
+                    warn!("Preferences: Unknown variant: {}", name);
+                    return None;
+                }
+            }
+        }
+        _ => {
+            warn!("Preferences: Expected a variant of {}", enum_ty.type_path());
+            return None;
+        }
+    };
+    dynamic_enum.set_represented_type(Some(ty));
+    Some(dynamic_enum)
+}
+
+/// Look up an enum variant by name, falling back to a case-insensitive match if enabled.
+pub(crate) fn find_variant<'a>(
+    enum_ty: &'a EnumInfo,
+    name: &str,
+    matching: PreferencesVariantMatching,
+) -> Option<&'a VariantInfo> {
+    if let Some(variant) = enum_ty.variant(name) {
+        return Some(variant);
+    }
+    if matching != PreferencesVariantMatching::CaseInsensitive {
+        return None;
+    }
+    let variant = enum_ty
+        .iter()
+        .find(|variant| variant.name().eq_ignore_ascii_case(name))?;
+    warn!(
+        "Preferences: Matched variant {:?} of {} to {}",
+        name,
+        enum_ty.type_path(),
+        variant.name()
+    );
+    Some(variant)
+}
+
+/// Decode the value of a field of an enum variant, whose type must be in the registry.
+fn decode_variant_field(
+    cx: DecodeContext,
+    type_id: std::any::TypeId,
+    type_path: &str,
+    value: &toml::Value,
+) -> Option<Box<dyn PartialReflect>> {
+    let Some(field_ty) = cx.registry.get(type_id) else {
+        warn!("Preferences: {} is not in the type registry", type_path);
+        return None;
+    };
+    decode_element(cx, field_ty.type_info(), value)
+}
+
+/// Accept booleans stored as the strings `"true"` and `"false"`, as written by some older
+/// preference formats.
+pub(crate) fn parse_bool(s: &str) -> Option<bool> {
+    match s {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error)]
+pub enum DecodeTomlError {
+    #[error("Unsupported type: {0}")]
+    UnsupportedConversion(&'static str),
+    #[error("Unsupported source type: {0}")]
+    UnsupportedSource(&'static str),
+}
+
+pub(crate) fn decode_value_boxed(
+    ty: &TypeInfo,
+    value: &toml::Value,
+) -> Result<Box<dyn PartialReflect>, DecodeTomlError> {
+    match value {
+        toml::Value::Float(float_val) => {
+            if ty.is::<f32>() {
+                Ok((*float_val as f32).clone_value())
+            } else if ty.is::<f64>() {
+                Ok((*float_val).clone_value())
+            } else if ty.is::<Duration>() {
+                match Duration::try_from_secs_f64(*float_val) {
+                    Ok(duration) => Ok(duration.clone_value()),
+                    Err(_) => {
+                        warn!("Preferences: Invalid duration: {}", float_val);
+                        Err(DecodeTomlError::UnsupportedConversion(ty.type_path()))
+                    }
+                }
+            } else {
+                warn!("Preferences: Unsupported conversion: {:?}", ty);
+                Err(DecodeTomlError::UnsupportedConversion(ty.type_path()))
+            }
+        }
+
+        toml::Value::Integer(int_val) => {
+            if ty.is::<f32>() {
+                Ok((*int_val as f32).clone_value())
+            } else if ty.is::<f64>() {
+                Ok((*int_val as f64).clone_value())
+            } else if ty.is::<i8>() {
+                Ok(((*int_val).clamp(i8::MIN as i64, i8::MAX as i64) as i8).clone_value())
+            } else if ty.is::<i16>() {
+                Ok(((*int_val).clamp(i16::MIN as i64, i16::MAX as i64) as i16).clone_value())
+            } else if ty.is::<i32>() {
+                Ok(((*int_val).clamp(i32::MIN as i64, i32::MAX as i64) as i32).clone_value())
+            } else if ty.is::<i64>() {
+                Ok((*int_val).clone_value())
+            } else if ty.is::<u8>() {
+                Ok(((*int_val).clamp(u8::MIN as i64, u8::MAX as i64) as u8).clone_value())
+            } else if ty.is::<u16>() {
+                Ok(((*int_val).clamp(u16::MIN as i64, u16::MAX as i64) as u16).clone_value())
+            } else if ty.is::<u32>() {
+                Ok(((*int_val).clamp(u32::MIN as i64, u32::MAX as i64) as u32).clone_value())
+            } else if ty.is::<u64>() {
+                Ok(((*int_val).max(0) as u64).clone_value())
+            } else if ty.is::<usize>() {
+                Ok(((*int_val).max(0) as usize).clone_value())
+            } else if ty.is::<Duration>() {
+                Ok(Duration::from_secs((*int_val).max(0) as u64).clone_value())
+            } else {
+                warn!("Preferences: Unsupported conversion: {:?}", ty);
+                Err(DecodeTomlError::UnsupportedConversion(ty.type_path()))
+            }
+        }
+
+        toml::Value::String(str_val) => {
+            if ty.is::<String>() {
+                Ok(str_val.clone_value())
+            } else if ty.is::<Arc<str>>() {
+                Ok(Arc::<str>::from(str_val.as_str()).clone_value())
+            } else if ty.is::<Arc<String>>() {
+                Ok(Arc::new(str_val.clone()).clone_value())
+            } else if ty.is::<bool>() {
+                match parse_bool(str_val) {
+                    Some(b) => Ok(b.clone_value()),
+                    None => {
+                        warn!("Preferences: Invalid boolean: {:?}", str_val);
+                        Err(DecodeTomlError::UnsupportedConversion(ty.type_path()))
+                    }
+                }
+            } else if ty.is::<Duration>() {
+                match parse_duration(str_val) {
+                    Some(duration) => Ok(duration.clone_value()),
+                    None => {
+                        warn!("Preferences: Invalid duration: {:?}", str_val);
+                        Err(DecodeTomlError::UnsupportedConversion(ty.type_path()))
+                    }
+                }
+            } else {
+                warn!("Preferences: Unsupported conversion: {:?}", ty);
+                Err(DecodeTomlError::UnsupportedConversion(ty.type_path()))
+            }
+        }
+
+        toml::Value::Boolean(bool_val) => {
+            if ty.is::<bool>() {
+                Ok(bool_val.clone_value())
+            } else {
+                warn!("Preferences: Unsupported conversion: {:?}", ty);
+                Err(DecodeTomlError::UnsupportedConversion(ty.type_path()))
+            }
+        }
+
+        _ => {
+            warn!("Preferences: unsupported source type: {}", ty.type_path());
+            Err(DecodeTomlError::UnsupportedSource(ty.type_path()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::reflect::{Reflect, Typed};
+
+    use super::*;
+
+    #[derive(Reflect)]
+    struct Window {
+        width: u32,
+        title: String,
+        maximized: bool,
+    }
+
+    #[test]
+    fn test_duration_seconds() {
+        let duration = Duration::from_millis(1500);
+        let value = encode_prop(&duration, EncodeContext::default()).unwrap();
+        assert_eq!(value, toml::Value::Float(1.5));
+        let decoded = decode_value_boxed(Duration::type_info(), &value).unwrap();
+        assert_eq!(decoded.try_downcast_ref::<Duration>(), Some(&duration));
+        let decoded = decode_value_boxed(Duration::type_info(), &toml::Value::Integer(2)).unwrap();
+        assert_eq!(
+            decoded.try_downcast_ref::<Duration>(),
+            Some(&Duration::from_secs(2))
+        );
+        assert!(decode_value_boxed(Duration::type_info(), &toml::Value::Float(-1.0)).is_err());
+    }
+
+    #[cfg(feature = "humantime")]
+    #[test]
+    fn test_human_duration_round_trip() {
+        let value = toml::Value::String("1500ms".to_string());
+        let decoded = decode_value_boxed(Duration::type_info(), &value).unwrap();
+        let duration = *decoded.try_downcast_ref::<Duration>().unwrap();
+        assert_eq!(duration, Duration::from_millis(1500));
+
+        let cx = EncodeContext {
+            durations: PreferencesDurationFormat::Human,
+            ..EncodeContext::default()
+        };
+        let encoded = encode_prop(&duration, cx).unwrap();
+        assert_eq!(encoded, toml::Value::String("1s 500ms".to_string()));
+        let decoded = decode_value_boxed(Duration::type_info(), &encoded).unwrap();
+        assert_eq!(decoded.try_downcast_ref::<Duration>(), Some(&duration));
+    }
+
+    #[test]
+    fn test_convert_without_plugin() {
+        assert_eq!(
+            encode_prop(&0.1f32, EncodeContext::default()),
+            Some(toml::Value::Float(0.1))
+        );
+        let window = Window {
+            width: 800,
+            title: "Editor".to_string(),
+            maximized: true,
+        };
+        let Some(toml::Value::Table(table)) = encode_prop(&window, EncodeContext::default()) else {
+            panic!("Expected a table");
+        };
+        assert_eq!(
+            table.to_string(),
+            "maximized = true\ntitle = \"Editor\"\nwidth = 800\n"
+        );
+
+        let width = decode_value_boxed(u32::type_info(), &table["width"]).unwrap();
+        assert_eq!(width.try_downcast_ref::<u32>(), Some(&800));
+        let title = decode_value_boxed(String::type_info(), &table["title"]).unwrap();
+        assert_eq!(
+            title.try_downcast_ref::<String>().map(String::as_str),
+            Some("Editor")
+        );
+    }
+
+    #[test]
+    fn test_four_tuple_round_trip() {
+        let original = (0.25f32, 0.5f32, 0.75f32, 1.0f32);
+        let value = encode_prop(&original, EncodeContext::default()).unwrap();
+        assert_eq!(value.to_string(), "[0.25, 0.5, 0.75, 1.0]");
+
+        let mut loaded = (0.0f32, 0.0f32, 0.0f32, 0.0f32);
+        load_tuple(&mut loaded, &value);
+        assert_eq!(loaded, original);
+
+        // The wrong number of elements leaves the field unchanged.
+        load_tuple(
+            &mut loaded,
+            &toml::Value::Array(vec![toml::Value::Float(2.0)]),
+        );
+        assert_eq!(loaded, original);
+    }
+
+    #[test]
+    fn test_nested_tuple_round_trip() {
+        let original = ((0.0f32, 1.5f32), (-2.0f32, 3.0f32));
+        let value = encode_prop(&original, EncodeContext::default()).unwrap();
+        assert_eq!(value.to_string(), "[[0.0, 1.5], [-2.0, 3.0]]");
+
+        let mut loaded = ((0.0f32, 0.0f32), (0.0f32, 0.0f32));
+        load_tuple(&mut loaded, &value);
+        assert_eq!(loaded, original);
+
+        // An element which can't be decoded leaves the whole field unchanged.
+        let mut invalid = value.clone();
+        invalid.as_array_mut().unwrap()[1] = toml::Value::String("wide".to_string());
+        load_tuple(&mut loaded, &invalid);
+        assert_eq!(loaded, original);
+    }
+}
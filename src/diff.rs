@@ -0,0 +1,118 @@
+use std::fmt;
+
+use bevy::prelude::*;
+
+use crate::{
+    load::read_preferences_file, save::build_preferences_table, PreferencesNamespace,
+    PreferencesTimestamps,
+};
+
+/// The location of a value in the preferences document, as the keys leading to it from the
+/// root of the document, e.g. `["audio", "volume"]`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PreferencePath(pub Vec<String>);
+
+impl fmt::Display for PreferencePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.join("."))
+    }
+}
+
+/// Compare the current preference resources with the contents of the main preferences file,
+/// without writing anything, and return the paths of all values which differ. A value which
+/// is only present on one side counts as a difference. If there is no preferences file, every
+/// current value is listed.
+pub fn preferences_diff(world: &World) -> Vec<PreferencePath> {
+    let current = build_preferences_table(world);
+    let mut saved = read_preferences_file(world, None)
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    // Values of other namespaces sharing the file are not compared.
+    if let Some(namespace) = world.get_resource::<PreferencesNamespace>() {
+        saved.retain(|key, _| *key == namespace.0);
+    }
+    if let Some(timestamps) = world.get_resource::<PreferencesTimestamps>() {
+        saved.remove(&timestamps.0);
+    }
+    let mut paths = Vec::new();
+    diff_tables(&current, &saved, &mut Vec::new(), &mut paths);
+    paths.sort();
+    paths
+}
+
+fn diff_tables(
+    current: &toml::Table,
+    saved: &toml::Table,
+    prefix: &mut Vec<String>,
+    paths: &mut Vec<PreferencePath>,
+) {
+    let keys = current
+        .keys()
+        .chain(saved.keys().filter(|key| !current.contains_key(*key)));
+    for key in keys {
+        prefix.push(key.clone());
+        // A table which is missing on one side is compared as empty, so that each of its
+        // values is listed individually.
+        let empty = toml::Table::new();
+        match (current.get(key), saved.get(key)) {
+            (Some(toml::Value::Table(current)), Some(toml::Value::Table(saved))) => {
+                diff_tables(current, saved, prefix, paths);
+            }
+            (Some(toml::Value::Table(current)), None) => {
+                diff_tables(current, &empty, prefix, paths);
+            }
+            (None, Some(toml::Value::Table(saved))) => {
+                diff_tables(&empty, saved, prefix, paths);
+            }
+            (current, saved) if current != saved => paths.push(PreferencePath(prefix.clone())),
+            _ => {}
+        }
+        prefix.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::world::Command;
+
+    use super::*;
+    use crate::{PreferencesGroup, SavePreferences};
+
+    #[derive(Resource, Reflect)]
+    #[reflect(@PreferencesGroup("audio"))]
+    struct AudioSettings {
+        volume: f32,
+        device: String,
+    }
+
+    #[test]
+    fn test_preferences_diff() {
+        let dir = crate::tests::test_dir("preferences_diff");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<AudioSettings>();
+        world.insert_resource(AudioSettings {
+            volume: 0.5,
+            device: "default".to_string(),
+        });
+        assert_eq!(preferences_diff(&world).len(), 2);
+
+        SavePreferences::Always.apply(&mut world);
+        assert!(preferences_diff(&world).is_empty());
+
+        world.resource_mut::<AudioSettings>().volume = 0.25;
+        let diff = preferences_diff(&world);
+        assert_eq!(
+            diff,
+            [PreferencePath(vec![
+                "audio".to_string(),
+                "volume".to_string()
+            ])]
+        );
+        assert_eq!(diff[0].to_string(), "audio.volume");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
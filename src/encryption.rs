@@ -0,0 +1,255 @@
+use std::{io, sync::Arc};
+
+use bevy::prelude::*;
+
+use crate::keys::group_path;
+
+/// A cipher used to encrypt the contents of selected groups of the preferences file.
+pub trait PreferencesCipher: Send + Sync + 'static {
+    /// Encrypt the serialized contents of a group.
+    fn encrypt(&self, plaintext: &[u8]) -> Vec<u8>;
+
+    /// Decrypt the contents of a group. An error causes the group to be ignored, so its
+    /// preferences keep their default values.
+    fn decrypt(&self, ciphertext: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// Resource which selects groups of the preferences file to encrypt, leaving the rest of the
+/// file readable. Each selected group is serialized as a TOML document, encrypted with the
+/// cipher and stored in place of the group as a base64 string:
+///
+/// ```toml
+/// account = "c2VjcmV0IGFjY291bnQgZGV0YWlscw=="
+///
+/// [audio]
+/// volume = 0.5
+/// ```
+///
+/// Groups are named as they appear in the file, with `/` separating nested groups.
+#[derive(Resource, Clone)]
+pub struct PreferencesEncryptedGroups {
+    cipher: Arc<dyn PreferencesCipher>,
+    groups: Vec<String>,
+}
+
+impl PreferencesEncryptedGroups {
+    pub fn new(cipher: impl PreferencesCipher) -> Self {
+        Self {
+            cipher: Arc::new(cipher),
+            groups: Vec::new(),
+        }
+    }
+
+    /// Add a group to encrypt.
+    pub fn with_group(mut self, group: &str) -> Self {
+        self.groups.push(group.to_string());
+        self
+    }
+}
+
+/// Replace each encrypted group of the document with its ciphertext, before it is written.
+pub(crate) fn encrypt_groups(world: &World, table: &mut toml::Table) {
+    let Some(encrypted) = world.get_resource::<PreferencesEncryptedGroups>() else {
+        return;
+    };
+    for group in &encrypted.groups {
+        let Some(value) = find_group(table, group) else {
+            continue;
+        };
+        let toml::Value::Table(contents) = value else {
+            continue;
+        };
+        let ciphertext = encrypted.cipher.encrypt(contents.to_string().as_bytes());
+        *value = toml::Value::String(base64_encode(&ciphertext));
+    }
+}
+
+/// Replace each encrypted group of a document which has been read with its plaintext. Groups
+/// which can't be decrypted are left empty, with a warning.
+pub(crate) fn decrypt_groups(world: &World, table: &mut toml::Table) {
+    let Some(encrypted) = world.get_resource::<PreferencesEncryptedGroups>() else {
+        return;
+    };
+    for group in &encrypted.groups {
+        let Some(value) = find_group(table, group) else {
+            continue;
+        };
+        let toml::Value::String(text) = value else {
+            continue;
+        };
+        let contents = base64_decode(text)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid base64"))
+            .and_then(|ciphertext| encrypted.cipher.decrypt(&ciphertext))
+            .and_then(|plaintext| String::from_utf8(plaintext).map_err(io::Error::other))
+            .and_then(|plaintext| plaintext.parse::<toml::Table>().map_err(io::Error::other));
+        *value = match contents {
+            Ok(contents) => toml::Value::Table(contents),
+            Err(e) => {
+                warn!("Could not decrypt preferences group {}: {}", group, e);
+                toml::Value::Table(toml::Table::new())
+            }
+        };
+    }
+}
+
+/// Find the value at the path of a group, e.g. `game/account`.
+fn find_group<'a>(table: &'a mut toml::Table, group: &str) -> Option<&'a mut toml::Value> {
+    let mut segments = group_path(group);
+    let mut value = table.get_mut(segments.next()?.as_ref())?;
+    for segment in segments {
+        value = value.as_table_mut()?.get_mut(segment.as_ref())?;
+    }
+    Some(value)
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64, with padding.
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, byte)| n | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=').as_bytes();
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut n = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let digit = BASE64_ALPHABET.iter().position(|a| a == c)? as u32;
+            n |= digit << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            bytes.push((n >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::world::Command;
+
+    use super::*;
+    use crate::{PreferencesGroup, SavePreferences};
+
+    /// Not a real cipher: flips the bits of each byte.
+    struct InvertCipher;
+
+    impl PreferencesCipher for InvertCipher {
+        fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+            plaintext.iter().map(|byte| !byte).collect()
+        }
+
+        fn decrypt(&self, ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+            Ok(self.encrypt(ciphertext))
+        }
+    }
+
+    #[derive(Resource, Reflect)]
+    #[reflect(@PreferencesGroup("account"))]
+    struct Account {
+        user_name: String,
+    }
+
+    #[derive(Resource, Reflect)]
+    #[reflect(@PreferencesGroup("audio"))]
+    struct Audio {
+        volume: f32,
+    }
+
+    #[test]
+    fn test_base64() {
+        for (bytes, text) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"\xff\x00\xfe", "/wD+"),
+        ] {
+            assert_eq!(base64_encode(bytes), text);
+            assert_eq!(base64_decode(text).as_deref(), Some(bytes));
+        }
+        assert_eq!(base64_decode("Z"), None);
+        assert_eq!(base64_decode("Zm9*"), None);
+    }
+
+    #[test]
+    fn test_encrypted_group() {
+        let dir = crate::tests::test_dir("encrypted_group");
+        let mut world = crate::tests::test_world(&dir);
+        {
+            let mut registry = world.resource::<AppTypeRegistry>().write();
+            registry.register::<Account>();
+            registry.register::<Audio>();
+        }
+        world.insert_resource(PreferencesEncryptedGroups::new(InvertCipher).with_group("account"));
+        world.insert_resource(Account {
+            user_name: "alice".to_string(),
+        });
+        world.insert_resource(Audio { volume: 0.5 });
+        SavePreferences::Always.apply(&mut world);
+
+        let file = std::fs::read_to_string(dir.join("prefs.toml")).unwrap();
+        let table: toml::Table = file.parse().unwrap();
+        let account = table["account"].as_str().unwrap();
+        assert!(!file.contains("alice"));
+        assert_eq!(
+            InvertCipher
+                .decrypt(&base64_decode(account).unwrap())
+                .unwrap(),
+            b"user_name = \"alice\"\n"
+        );
+        assert!(file.contains("[audio]\nvolume = 0.5\n"));
+
+        world.insert_resource(Account {
+            user_name: String::new(),
+        });
+        world.insert_resource(Audio { volume: 1.0 });
+        crate::load::load_preferences(&mut world);
+        assert_eq!(world.resource::<Account>().user_name, "alice");
+        assert_eq!(world.resource::<Audio>().volume, 0.5);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_encrypted_group_from_bytes() {
+        let dir = crate::tests::test_dir("encrypted_group_from_bytes");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<Account>();
+        world.insert_resource(PreferencesEncryptedGroups::new(InvertCipher).with_group("account"));
+        world.insert_resource(Account {
+            user_name: "alice".to_string(),
+        });
+        SavePreferences::Always.apply(&mut world);
+        let bytes = std::fs::read(dir.join("prefs.toml")).unwrap();
+
+        world.insert_resource(Account {
+            user_name: String::new(),
+        });
+        crate::load::load_preferences_from_bytes(&mut world, &bytes).unwrap();
+        assert_eq!(world.resource::<Account>().user_name, "alice");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
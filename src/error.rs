@@ -0,0 +1,47 @@
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+use thiserror::Error;
+
+/// Event sent when there is a problem reading or writing the preferences file which the user
+/// can act on. The error is also logged.
+#[non_exhaustive]
+#[derive(Event, Debug, Error)]
+pub enum PreferencesError {
+    #[error(
+        "Preferences path is a directory, remove or rename it so the preferences can be saved: {0:?}"
+    )]
+    PathIsDirectory(PathBuf),
+    #[error(
+        "Preferences directory can't be created because {file:?} is a file, remove or rename it: {dir:?}"
+    )]
+    PathComponentIsFile { dir: PathBuf, file: PathBuf },
+    #[error("Could not write preferences to {path:?}: {error}")]
+    WriteFailed {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    #[error("Preferences are {size} bytes, which is more than the maximum of {max} bytes")]
+    TooLarge { size: usize, max: usize },
+    #[error("Unknown preference key in preferences file: {0}")]
+    UnknownKey(String),
+    #[error("Preferences failed validation: {0}")]
+    ValidationFailed(String),
+    #[error("Preferences of {resource} were not saved, serializing them panicked: {message}")]
+    SerializationPanicked { resource: String, message: String },
+    #[error("Preferences file doesn't match what was written to it: {0:?}")]
+    VerificationFailed(PathBuf),
+    #[error("Could not write preferences to {backend}: {error}")]
+    BackendFailed {
+        backend: String,
+        error: std::io::Error,
+    },
+}
+
+/// Log the error, and send it as an event if the app has registered the event.
+pub(crate) fn report_error(world: &mut World, error: PreferencesError) {
+    error!("{}", error);
+    if world.contains_resource::<Events<PreferencesError>>() {
+        world.send_event(error);
+    }
+}
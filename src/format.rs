@@ -0,0 +1,674 @@
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use thiserror::Error;
+
+use crate::PreferencesTableForm;
+
+/// A file format used to encode the preferences document on disk.
+///
+/// The preferences are always assembled into a [`toml::Table`] first; a format is only
+/// responsible for turning that document tree into bytes and back again.
+pub trait PreferencesFormat: Send + Sync + 'static {
+    /// File extension (without the leading dot) used for the preferences file.
+    fn extension(&self) -> &'static str;
+
+    /// Encode the preferences document.
+    fn serialize(&self, table: &toml::Table) -> Result<Vec<u8>, FormatError>;
+
+    /// Encode the preferences document along with comments describing its keys. Formats which
+    /// can't represent comments ignore them, which is the default behavior.
+    fn serialize_with_comments(
+        &self,
+        table: &toml::Table,
+        comments: &[PreferencesComment],
+    ) -> Result<Vec<u8>, FormatError> {
+        let _ = comments;
+        self.serialize(table)
+    }
+
+    /// Encode the preferences document along with comments, arranging its keys and tables as
+    /// the layout declares. Formats which don't control the arrangement of their keys ignore
+    /// the layout, which is the default behavior.
+    fn serialize_with_layout(
+        &self,
+        table: &toml::Table,
+        comments: &[PreferencesComment],
+        layout: &PreferencesLayout,
+    ) -> Result<Vec<u8>, FormatError> {
+        let _ = layout;
+        self.serialize_with_comments(table, comments)
+    }
+
+    /// Add a comment to the top of an encoded preferences document. Formats which can't
+    /// represent comments return the document unchanged, which is the default behavior.
+    fn add_header(&self, contents: Vec<u8>, header: &str) -> Vec<u8> {
+        let _ = header;
+        contents
+    }
+
+    /// Decode a preferences document that was previously written by [`Self::serialize`].
+    fn deserialize(&self, bytes: &[u8]) -> Result<toml::Table, FormatError>;
+}
+
+/// A comment attached to a key or table in the preferences document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreferencesComment {
+    /// The keys leading from the root of the document to the commented item.
+    pub path: Vec<String>,
+    pub text: &'static str,
+}
+
+/// The declared position of a key or table within its parent table in the preferences
+/// document. Lower orders are written first, and keys without an order follow in alphabetical
+/// order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreferencesOrdering {
+    /// The keys leading from the root of the document to the ordered item.
+    pub path: Vec<String>,
+    pub order: i32,
+}
+
+/// The declared form of a table in the preferences document, which takes precedence over the
+/// format's own choice, such as [`TomlFormat::dotted_depth`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreferencesTableStyle {
+    /// The keys leading from the root of the document to the table.
+    pub path: Vec<String>,
+    pub form: PreferencesTableForm,
+}
+
+/// How the keys and tables of a preferences document are arranged, as declared by the
+/// [`PreferencesOrder`](crate::PreferencesOrder) and [`PreferencesTableForm`] annotations.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PreferencesLayout {
+    pub order: Vec<PreferencesOrdering>,
+    pub tables: Vec<PreferencesTableStyle>,
+}
+
+impl PreferencesLayout {
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty() && self.tables.is_empty()
+    }
+
+    /// Add the entries of another layout.
+    pub(crate) fn extend(&mut self, other: PreferencesLayout) {
+        self.order.extend(other.order);
+        self.tables.extend(other.tables);
+    }
+
+    /// The paths of all of the entries, e.g. to rename their keys.
+    pub(crate) fn paths_mut(&mut self) -> impl Iterator<Item = &mut Vec<String>> {
+        self.order
+            .iter_mut()
+            .map(|item| &mut item.path)
+            .chain(self.tables.iter_mut().map(|style| &mut style.path))
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error)]
+pub enum FormatError {
+    #[error("Could not encode TOML: {0}")]
+    TomlEncode(#[from] toml::ser::Error),
+    #[error("Could not parse TOML: {0}")]
+    TomlDecode(#[from] toml::de::Error),
+    #[error("Preferences file is not valid UTF-8: {0}")]
+    Utf8(#[from] std::str::Utf8Error),
+    #[cfg(feature = "postcard")]
+    #[error("Could not encode or decode binary preferences: {0}")]
+    Binary(#[from] postcard::Error),
+    #[error("Invalid datetime in preferences file: {0}")]
+    Datetime(#[from] toml::value::DatetimeParseError),
+}
+
+/// Human-readable TOML format. This is the default.
+///
+/// The output always uses `\n` line endings, regardless of platform. A list of structs is
+/// written as an array of tables, with a `[[key]]` header for each element, also when its group
+/// is written as dotted keys.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TomlFormat {
+    /// Tables which nest no more than this many levels deep are written as dotted keys, e.g.
+    /// `window.width = 800`, within their parent; deeper tables are written as sections, e.g.
+    /// `[window]`. The default of 0 writes every table as a section.
+    pub dotted_depth: usize,
+}
+
+impl TomlFormat {
+    /// Write tables which nest no more than `depth` levels deep as dotted keys.
+    pub fn with_dotted_depth(mut self, depth: usize) -> Self {
+        self.dotted_depth = depth;
+        self
+    }
+}
+
+impl PreferencesFormat for TomlFormat {
+    fn extension(&self) -> &'static str {
+        "toml"
+    }
+
+    fn serialize(&self, table: &toml::Table) -> Result<Vec<u8>, FormatError> {
+        self.serialize_with_comments(table, &[])
+    }
+
+    fn serialize_with_comments(
+        &self,
+        table: &toml::Table,
+        comments: &[PreferencesComment],
+    ) -> Result<Vec<u8>, FormatError> {
+        self.serialize_with_layout(table, comments, &PreferencesLayout::default())
+    }
+
+    fn serialize_with_layout(
+        &self,
+        table: &toml::Table,
+        comments: &[PreferencesComment],
+        layout: &PreferencesLayout,
+    ) -> Result<Vec<u8>, FormatError> {
+        if comments.is_empty() && layout.is_empty() && self.dotted_depth == 0 {
+            return Ok(toml::to_string(table)?.into_bytes());
+        }
+        // `toml` can't write comments, choose dotted keys or inline tables, or order keys other
+        // than alphabetically, so re-parse the output as an editable document and adjust that.
+        let mut document = toml::to_string(table)?
+            .parse::<toml_edit::DocumentMut>()
+            .expect("toml output should be a valid document");
+        // Convert the innermost tables first, so that an inline table can contain another.
+        let mut tables = layout.tables.iter().collect::<Vec<_>>();
+        tables.sort_by_key(|style| std::cmp::Reverse(style.path.len()));
+        for style in tables
+            .iter()
+            .filter(|style| style.form == PreferencesTableForm::Inline)
+        {
+            set_table_form(document.as_table_mut(), style);
+        }
+        if !layout.order.is_empty() {
+            let root = document.as_table_mut();
+            // A blank line separates each section from whatever comes before it.
+            let mut blank_line = root.iter().any(|(_, item)| !item.is_table());
+            apply_order(
+                root,
+                &mut Vec::new(),
+                &layout.order,
+                &mut 1,
+                &mut blank_line,
+            );
+        }
+        if self.dotted_depth > 0 {
+            set_dotted(document.as_table_mut(), self.dotted_depth);
+        }
+        // Sections take precedence over dotted keys, so they are only set once those are chosen.
+        for style in tables
+            .iter()
+            .filter(|style| style.form == PreferencesTableForm::Section)
+        {
+            set_table_form(document.as_table_mut(), style);
+        }
+        for comment in comments {
+            add_comment(document.as_table_mut(), comment);
+        }
+        Ok(document.to_string().into_bytes())
+    }
+
+    fn add_header(&self, contents: Vec<u8>, header: &str) -> Vec<u8> {
+        let mut text = comment_lines(header).into_bytes();
+        if !contents.is_empty() {
+            text.push(b'\n');
+        }
+        text.extend(contents);
+        text
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<toml::Table, FormatError> {
+        // Some editors start the file with a byte order mark, which isn't valid TOML.
+        let text = std::str::from_utf8(bytes)?;
+        Ok(toml::from_str(
+            text.strip_prefix('\u{feff}').unwrap_or(text),
+        )?)
+    }
+}
+
+/// Sort the keys of `table` and of its child tables by their declared order, and number the
+/// tables in that order, since sections are written in the order of their positions.
+fn apply_order(
+    table: &mut toml_edit::Table,
+    path: &mut Vec<String>,
+    order: &[PreferencesOrdering],
+    position: &mut usize,
+    blank_line: &mut bool,
+) {
+    let rank = |key: &toml_edit::Key| {
+        order
+            .iter()
+            .find(|item| {
+                item.path.len() == path.len() + 1
+                    && item.path.starts_with(path)
+                    && item.path.last().map(String::as_str) == Some(key.get())
+            })
+            .map(|item| item.order)
+    };
+    table.sort_values_by(|key1, _, key2, _| {
+        let by_order = match (rank(key1), rank(key2)) {
+            (Some(order1), Some(order2)) => order1.cmp(&order2),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal,
+        };
+        by_order.then_with(|| key1.get().cmp(key2.get()))
+    });
+    for (key, item) in table.iter_mut() {
+        if let Some(child) = item.as_table_mut() {
+            child.set_position(*position);
+            *position += 1;
+            if !child.is_implicit() {
+                child
+                    .decor_mut()
+                    .set_prefix(if *blank_line { "\n" } else { "" });
+                *blank_line = true;
+            }
+            path.push(key.get().to_string());
+            apply_order(child, path, order, position, blank_line);
+            path.pop();
+        }
+    }
+}
+
+/// Write the table at the style's path inline, e.g. `size = { width = 800, height = 600 }`, or
+/// as a section. Paths which don't lead to a table are ignored.
+fn set_table_form(root: &mut toml_edit::Table, style: &PreferencesTableStyle) {
+    let Some((last, parents)) = style.path.split_last() else {
+        return;
+    };
+    let mut table = root;
+    for key in parents {
+        match table.get_mut(key).and_then(toml_edit::Item::as_table_mut) {
+            Some(child) => table = child,
+            None => return,
+        }
+    }
+    let Some(item) = table.get_mut(last) else {
+        return;
+    };
+    let Some(child) = item.as_table_mut() else {
+        return;
+    };
+    match style.form {
+        PreferencesTableForm::Inline => {
+            let inline = std::mem::take(child).into_inline_table();
+            *item = toml_edit::value(inline);
+            if let Some(mut key) = table.key_mut(last) {
+                key.leaf_decor_mut().clear();
+            }
+            // Values are written before tables, and both in alphabetical order.
+            table.sort_values_by(|key1, item1, key2, item2| {
+                (item1.is_table(), key1.get()).cmp(&(item2.is_table(), key2.get()))
+            });
+        }
+        PreferencesTableForm::Section if child.is_dotted() => {
+            child.set_dotted(false);
+            child.set_implicit(false);
+            child.decor_mut().set_prefix("\n");
+        }
+        PreferencesTableForm::Section => {}
+    }
+}
+
+/// Write each child table of `table` which nests no more than `depth` levels deep as dotted
+/// keys, and look for such tables within the deeper ones.
+fn set_dotted(table: &mut toml_edit::Table, depth: usize) {
+    for (_, item) in table.iter_mut() {
+        if let Some(child) = item.as_table_mut() {
+            if nesting_depth(child) <= depth {
+                set_dotted_all(child);
+            } else {
+                set_dotted(child, depth);
+            }
+        }
+    }
+}
+
+fn set_dotted_all(table: &mut toml_edit::Table) {
+    table.set_dotted(true);
+    for (_, item) in table.iter_mut() {
+        if let Some(child) = item.as_table_mut() {
+            set_dotted_all(child);
+        }
+    }
+}
+
+/// The number of levels of tables in a table, counting the table itself.
+fn nesting_depth(table: &toml_edit::Table) -> usize {
+    1 + table
+        .iter()
+        .filter_map(|(_, item)| item.as_table())
+        .map(nesting_depth)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Insert a comment above the key or table header that the comment's path refers to. Paths
+/// which don't exist in the document are ignored.
+fn add_comment(root: &mut toml_edit::Table, comment: &PreferencesComment) {
+    let Some((last, parents)) = comment.path.split_last() else {
+        return;
+    };
+    let mut table = root;
+    for key in parents {
+        match table.get_mut(key).and_then(toml_edit::Item::as_table_mut) {
+            Some(child) => table = child,
+            None => return,
+        }
+    }
+    let text = comment_lines(comment.text);
+    if let Some(child) = table.get_mut(last).and_then(toml_edit::Item::as_table_mut) {
+        // Keep whatever blank lines separate the table header from the previous table.
+        let decor = child.decor_mut();
+        let prefix = decor.prefix().and_then(|p| p.as_str()).unwrap_or("");
+        let prefix = format!("{}{}", prefix, text);
+        decor.set_prefix(prefix);
+    } else if let Some(mut key) = table.key_mut(last) {
+        let decor = key.leaf_decor_mut();
+        let prefix = decor.prefix().and_then(|p| p.as_str()).unwrap_or("");
+        let prefix = format!("{}{}", prefix, text);
+        decor.set_prefix(prefix);
+    }
+}
+
+/// Format text as TOML comment lines, each starting with `#`.
+fn comment_lines(text: &str) -> String {
+    // Comments are the only text which isn't escaped by the encoder, so this is where a stray
+    // carriage return could make it into the file.
+    text.replace("\r\n", "\n")
+        .replace('\r', "\n")
+        .lines()
+        .map(|line| match line {
+            "" => "#\n".to_string(),
+            line => format!("# {}\n", line),
+        })
+        .collect()
+}
+
+/// Compact binary format, encoded with `postcard`. Much faster to load than TOML for large
+/// preference files, at the cost of no longer being editable by hand.
+#[cfg(feature = "postcard")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BinaryFormat;
+
+#[cfg(feature = "postcard")]
+impl PreferencesFormat for BinaryFormat {
+    fn extension(&self) -> &'static str {
+        "bin"
+    }
+
+    fn serialize(&self, table: &toml::Table) -> Result<Vec<u8>, FormatError> {
+        Ok(postcard::to_allocvec(&binary::BinaryTable::from(table))?)
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<toml::Table, FormatError> {
+        postcard::from_bytes::<binary::BinaryTable>(bytes)?.try_into()
+    }
+}
+
+/// `postcard` is not a self-describing format, so it can't decode `toml::Value` directly.
+/// Instead the document is mirrored into an explicitly-tagged tree.
+#[cfg(feature = "postcard")]
+mod binary {
+    use serde::{Deserialize, Serialize};
+
+    use super::FormatError;
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct BinaryTable(Vec<(String, BinaryValue)>);
+
+    #[derive(Serialize, Deserialize)]
+    enum BinaryValue {
+        String(String),
+        Integer(i64),
+        Float(f64),
+        Boolean(bool),
+        Datetime(String),
+        Array(Vec<BinaryValue>),
+        Table(BinaryTable),
+    }
+
+    impl From<&toml::Table> for BinaryTable {
+        fn from(table: &toml::Table) -> Self {
+            Self(
+                table
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.into()))
+                    .collect(),
+            )
+        }
+    }
+
+    impl TryFrom<BinaryTable> for toml::Table {
+        type Error = FormatError;
+
+        fn try_from(table: BinaryTable) -> Result<Self, FormatError> {
+            table
+                .0
+                .into_iter()
+                .map(|(key, value)| Ok((key, value.try_into()?)))
+                .collect()
+        }
+    }
+
+    impl From<&toml::Value> for BinaryValue {
+        fn from(value: &toml::Value) -> Self {
+            match value {
+                toml::Value::String(s) => Self::String(s.clone()),
+                toml::Value::Integer(i) => Self::Integer(*i),
+                toml::Value::Float(f) => Self::Float(*f),
+                toml::Value::Boolean(b) => Self::Boolean(*b),
+                toml::Value::Datetime(dt) => Self::Datetime(dt.to_string()),
+                toml::Value::Array(array) => Self::Array(array.iter().map(Into::into).collect()),
+                toml::Value::Table(table) => Self::Table(table.into()),
+            }
+        }
+    }
+
+    impl TryFrom<BinaryValue> for toml::Value {
+        type Error = FormatError;
+
+        fn try_from(value: BinaryValue) -> Result<Self, FormatError> {
+            Ok(match value {
+                BinaryValue::String(s) => Self::String(s),
+                BinaryValue::Integer(i) => Self::Integer(i),
+                BinaryValue::Float(f) => Self::Float(f),
+                BinaryValue::Boolean(b) => Self::Boolean(b),
+                // A damaged or hand-crafted file may hold a string which isn't a datetime.
+                BinaryValue::Datetime(dt) => Self::Datetime(dt.parse()?),
+                BinaryValue::Array(array) => Self::Array(
+                    array
+                        .into_iter()
+                        .map(TryInto::try_into)
+                        .collect::<Result<_, _>>()?,
+                ),
+                BinaryValue::Table(table) => Self::Table(table.try_into()?),
+            })
+        }
+    }
+}
+
+/// Resource which selects the [`PreferencesFormat`] used to read and write the preferences
+/// file. Defaults to [`TomlFormat`].
+#[derive(Resource, Clone)]
+pub struct PreferencesFileFormat(pub Arc<dyn PreferencesFormat>);
+
+impl PreferencesFileFormat {
+    pub fn new(format: impl PreferencesFormat) -> Self {
+        Self(Arc::new(format))
+    }
+
+    /// Name of the preferences file, e.g. `prefs.toml`.
+    pub fn file_name(&self) -> String {
+        format!("prefs.{}", self.0.extension())
+    }
+
+    /// Name of the preferences file for a profile, e.g. `prefs.player1.toml`.
+    pub fn profile_file_name(&self, profile: &str) -> String {
+        format!("prefs.{}.{}", profile, self.0.extension())
+    }
+}
+
+impl Default for PreferencesFileFormat {
+    fn default() -> Self {
+        Self::new(TomlFormat::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_table() -> toml::Table {
+        toml::from_str(
+            r#"
+            top = "level"
+
+            [audio]
+            volume = 0.5
+            muted = false
+            channels = [1, 2, 3]
+
+            [window.size]
+            width = 800
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_toml_format_round_trip() {
+        let table = sample_table();
+        let bytes = TomlFormat::default().serialize(&table).unwrap();
+        assert_eq!(TomlFormat::default().deserialize(&bytes).unwrap(), table);
+    }
+
+    #[test]
+    fn test_toml_dotted_depth() {
+        let table: toml::Table = toml::from_str(
+            r#"
+            [window]
+            width = 800
+
+            [game.graphics]
+            quality = 2
+            "#,
+        )
+        .unwrap();
+        let format = TomlFormat::default().with_dotted_depth(1);
+        let bytes = format.serialize(&table).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&bytes).unwrap(),
+            "window.width = 800\n\n[game]\ngraphics.quality = 2\n"
+        );
+        assert_eq!(format.deserialize(&bytes).unwrap(), table);
+    }
+
+    #[test]
+    fn test_toml_format_comments() {
+        let table = sample_table();
+        let comments = [
+            PreferencesComment {
+                path: vec!["audio".to_string()],
+                text: "Sound settings.",
+            },
+            PreferencesComment {
+                path: vec!["audio".to_string(), "volume".to_string()],
+                text: "Master volume,\nfrom 0 to 1.",
+            },
+            PreferencesComment {
+                path: vec!["missing".to_string()],
+                text: "Ignored.",
+            },
+        ];
+        let bytes = TomlFormat::default()
+            .serialize_with_comments(&table, &comments)
+            .unwrap();
+        let text = std::str::from_utf8(&bytes).unwrap();
+        assert!(text.contains("\n# Sound settings.\n[audio]\n"));
+        assert!(text.contains("# Master volume,\n# from 0 to 1.\nvolume = 0.5\n"));
+        assert!(!text.contains("Ignored"));
+        assert_eq!(TomlFormat::default().deserialize(&bytes).unwrap(), table);
+    }
+
+    #[test]
+    fn test_toml_format_line_endings() {
+        let mut table = sample_table();
+        table.insert(
+            "motd".to_string(),
+            toml::Value::String("line 1\r\nline 2\r\n".to_string()),
+        );
+        let comments = [PreferencesComment {
+            path: vec!["top".to_string()],
+            text: "Written\r\non Windows.\rOld Mac.",
+        }];
+        let bytes = TomlFormat::default()
+            .serialize_with_comments(&table, &comments)
+            .unwrap();
+        assert!(!bytes.contains(&b'\r'));
+        let text = std::str::from_utf8(&bytes).unwrap();
+        assert!(text.contains("# Written\n# on Windows.\n# Old Mac.\ntop = "));
+        assert_eq!(TomlFormat::default().deserialize(&bytes).unwrap(), table);
+    }
+
+    #[test]
+    fn test_toml_format_invalid() {
+        assert!(TomlFormat::default().deserialize(b"[audio").is_err());
+    }
+
+    #[test]
+    fn test_default_file_name() {
+        assert_eq!(PreferencesFileFormat::default().file_name(), "prefs.toml");
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn test_binary_format_round_trip() {
+        let table = sample_table();
+        let bytes = BinaryFormat.serialize(&table).unwrap();
+        assert_eq!(BinaryFormat.deserialize(&bytes).unwrap(), table);
+        assert_eq!(
+            PreferencesFileFormat::new(BinaryFormat).file_name(),
+            "prefs.bin"
+        );
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn test_binary_format_large_document() {
+        let mut table = toml::Table::new();
+        for i in 0..1000 {
+            let mut group = toml::Table::new();
+            group.insert("index".to_string(), toml::Value::Integer(i));
+            group.insert("scale".to_string(), toml::Value::Float(i as f64 * 0.25));
+            group.insert("name".to_string(), toml::Value::String(format!("item{i}")));
+            table.insert(format!("group{i}"), toml::Value::Table(group));
+        }
+
+        let binary = BinaryFormat.serialize(&table).unwrap();
+        let text = TomlFormat::default().serialize(&table).unwrap();
+        assert!(binary.len() < text.len());
+        assert_eq!(BinaryFormat.deserialize(&binary).unwrap(), table);
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn test_binary_format_corrupt_datetime() {
+        let mut table = toml::Table::new();
+        table.insert(
+            "installed".to_string(),
+            toml::Value::Datetime("1979-05-27T07:32:00Z".parse().unwrap()),
+        );
+        let mut bytes = BinaryFormat.serialize(&table).unwrap();
+        let start = bytes.windows(4).position(|w| w == b"1979").unwrap();
+        bytes[start..start + 4].copy_from_slice(b"19x9");
+        assert!(matches!(
+            BinaryFormat.deserialize(&bytes),
+            Err(FormatError::Datetime(_))
+        ));
+    }
+}
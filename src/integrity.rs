@@ -0,0 +1,137 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+
+/// Resource which enables a checksum of the preferences file, used to detect corruption.
+///
+/// When enabled, a CRC-32 of the file contents is written to a sidecar file next to the
+/// preferences file (e.g. `prefs.toml.crc32`). The sidecar is replaced by renaming, like the
+/// preferences file, and while the preferences file is being replaced it accepts both the old
+/// and the new contents, so an interrupted save never leaves a file which doesn't match.
+///
+/// On load, a file whose contents don't match the checksum is ignored with a warning. The
+/// backups of the file, `prefs.toml.bak`, `prefs.toml.bak.1` and so on, are then tried in turn,
+/// each verified against its own sidecar if it has one, and the first which can be loaded is
+/// used. If none can, the preferences keep their default values.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PreferencesIntegrity {
+    /// No checksum is written or verified. This is the default.
+    #[default]
+    Disabled,
+    /// Write and verify a CRC-32 checksum.
+    Crc32,
+}
+
+/// Path of the checksum sidecar for the given preferences file.
+pub(crate) fn checksum_path(prefs_file: &Path) -> PathBuf {
+    let mut path = prefs_file.as_os_str().to_owned();
+    path.push(".crc32");
+    PathBuf::from(path)
+}
+
+/// Write the checksum sidecar for the given file contents.
+pub(crate) fn write_checksum(prefs_file: &Path, contents: &[u8]) -> io::Result<()> {
+    write_checksums(prefs_file, &[contents])
+}
+
+/// Write a checksum sidecar which accepts any of the given file contents, one checksum per
+/// line. The sidecar is written to a temporary file first and then renamed into place.
+pub(crate) fn write_checksums(prefs_file: &Path, contents: &[&[u8]]) -> io::Result<()> {
+    let path = checksum_path(prefs_file);
+    let mut path_new = path.clone().into_os_string();
+    path_new.push(".new");
+    let lines = contents
+        .iter()
+        .map(|contents| format!("{:08x}\n", crc32(contents)))
+        .collect::<String>();
+    fs::write(&path_new, lines)?;
+    fs::rename(&path_new, path)
+}
+
+/// Returns false if a checksum sidecar exists and doesn't match the given file contents.
+/// A missing sidecar is not an error, since the file may have been written before
+/// integrity checking was enabled.
+pub(crate) fn verify_checksum(prefs_file: &Path, contents: &[u8]) -> bool {
+    match fs::read_to_string(checksum_path(prefs_file)) {
+        Ok(expected) => checksums_match(&expected, contents),
+        Err(_) => true,
+    }
+}
+
+/// Returns true if the checksum sidecar exists and matches the given file contents.
+pub(crate) fn checksum_is_current(prefs_file: &Path, contents: &[u8]) -> bool {
+    fs::read_to_string(checksum_path(prefs_file))
+        .is_ok_and(|expected| checksums_match(&expected, contents))
+}
+
+/// Returns true if any line of the sidecar is the checksum of the contents.
+fn checksums_match(sidecar: &str, contents: &[u8]) -> bool {
+    let crc = crc32(contents);
+    sidecar
+        .lines()
+        .any(|line| u32::from_str_radix(line.trim(), 16) == Ok(crc))
+}
+
+/// CRC-32 (IEEE 802.3 polynomial), as used by zip and png.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// 64-bit FNV-1a hash, which unlike the standard library's hasher is stable across Rust
+/// versions and platforms.
+pub(crate) fn fnv1a64(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_fnv1a64() {
+        assert_eq!(fnv1a64(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a64(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn test_checksums_match() {
+        let sidecar = format!("{:08x}\n{:08x}\n", crc32(b"new"), crc32(b"old"));
+        assert!(checksums_match(&sidecar, b"new"));
+        assert!(checksums_match(&sidecar, b"old"));
+        assert!(!checksums_match(&sidecar, b"other"));
+        assert!(!checksums_match("", b""));
+    }
+
+    #[test]
+    fn test_checksum_path() {
+        assert_eq!(
+            checksum_path(Path::new("dir/prefs.toml")),
+            Path::new("dir/prefs.toml.crc32")
+        );
+    }
+}
@@ -0,0 +1,158 @@
+use std::borrow::Cow;
+
+use bevy::prelude::*;
+
+#[cfg(feature = "unicode-normalization")]
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+
+/// Normalize a group or key name before it is used to index a preferences table.
+///
+/// With the `unicode-normalization` feature enabled, names are converted to Unicode NFC so
+/// that visually identical names with different encodings (e.g. a precomposed "é" versus "e"
+/// followed by a combining accent) map to the same table entry. Otherwise the name is returned
+/// unchanged.
+pub(crate) fn normalize_key(key: &str) -> Cow<'_, str> {
+    #[cfg(feature = "unicode-normalization")]
+    if is_nfc_quick(key.chars()) != IsNormalized::Yes {
+        return Cow::Owned(key.nfc().collect());
+    }
+    Cow::Borrowed(key)
+}
+
+/// Resource which selects the case style of the keys in the preferences file. Rust names are
+/// snake_case, and are converted to this style when saving and back to snake_case on load.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PreferencesKeyStyle {
+    /// Keys are written as they are named in Rust. This is the default.
+    #[default]
+    AsIs,
+    /// Keys are written in kebab-case, e.g. `master-volume`.
+    Kebab,
+    /// Keys are written in camelCase, e.g. `masterVolume`.
+    Camel,
+}
+
+impl PreferencesKeyStyle {
+    /// Convert a snake_case name to this style.
+    pub fn apply<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match self {
+            Self::AsIs => Cow::Borrowed(key),
+            Self::Kebab => Cow::Owned(key.replace('_', "-")),
+            Self::Camel => {
+                let mut result = String::with_capacity(key.len());
+                let mut upper = false;
+                for c in key.chars() {
+                    if c == '_' && !result.is_empty() {
+                        upper = true;
+                    } else if upper {
+                        result.extend(c.to_uppercase());
+                        upper = false;
+                    } else {
+                        result.push(c);
+                    }
+                }
+                Cow::Owned(result)
+            }
+        }
+    }
+
+    /// Convert a key written in this style back to snake_case.
+    pub fn invert<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match self {
+            Self::AsIs => Cow::Borrowed(key),
+            Self::Kebab => Cow::Owned(key.replace('-', "_")),
+            Self::Camel => {
+                let mut result = String::with_capacity(key.len() + 4);
+                for c in key.chars() {
+                    if c.is_uppercase() {
+                        result.push('_');
+                        result.extend(c.to_lowercase());
+                    } else {
+                        result.push(c);
+                    }
+                }
+                Cow::Owned(result)
+            }
+        }
+    }
+}
+
+/// Rename every key in the document, including those of nested tables and of tables within
+/// arrays.
+pub(crate) fn rename_keys(table: &toml::Table, rename: &impl Fn(&str) -> String) -> toml::Table {
+    table
+        .iter()
+        .map(|(key, value)| (rename(key), rename_value(value, rename)))
+        .collect()
+}
+
+fn rename_value(value: &toml::Value, rename: &impl Fn(&str) -> String) -> toml::Value {
+    match value {
+        toml::Value::Table(table) => toml::Value::Table(rename_keys(table, rename)),
+        toml::Value::Array(array) => toml::Value::Array(
+            array
+                .iter()
+                .map(|item| rename_value(item, rename))
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+/// Separator between the segments of a nested group name, e.g. `"game/graphics/shadows"`.
+pub(crate) const GROUP_SEPARATOR: char = '/';
+
+/// Split a group name into the normalized keys of the nested tables it refers to.
+pub(crate) fn group_path(group: &str) -> impl Iterator<Item = Cow<'_, str>> {
+    group.split(GROUP_SEPARATOR).map(normalize_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_ascii_key() {
+        assert!(matches!(normalize_key("volume"), Cow::Borrowed("volume")));
+    }
+
+    #[test]
+    fn test_group_path() {
+        assert_eq!(
+            group_path("game/graphics/shadows").collect::<Vec<_>>(),
+            ["game", "graphics", "shadows"]
+        );
+        assert_eq!(group_path("audio").collect::<Vec<_>>(), ["audio"]);
+    }
+
+    #[test]
+    fn test_key_style() {
+        assert_eq!(
+            PreferencesKeyStyle::AsIs.apply("master_volume"),
+            "master_volume"
+        );
+        assert_eq!(
+            PreferencesKeyStyle::Kebab.apply("master_volume"),
+            "master-volume"
+        );
+        assert_eq!(
+            PreferencesKeyStyle::Camel.apply("master_volume"),
+            "masterVolume"
+        );
+        assert_eq!(
+            PreferencesKeyStyle::Kebab.invert("master-volume"),
+            "master_volume"
+        );
+        assert_eq!(
+            PreferencesKeyStyle::Camel.invert("masterVolume"),
+            "master_volume"
+        );
+    }
+
+    #[cfg(feature = "unicode-normalization")]
+    #[test]
+    fn test_normalize_combining_key() {
+        assert_eq!(normalize_key("cafe\u{301}"), "caf\u{e9}");
+        assert_eq!(normalize_key("caf\u{e9}"), "caf\u{e9}");
+    }
+}
@@ -1,17 +1,64 @@
+mod backend;
+mod cache;
+mod convert;
+mod diff;
+mod encryption;
+mod error;
+mod format;
+mod integrity;
+mod keys;
 mod load;
+mod references;
+mod registration;
 mod save;
+mod secrets;
+mod strict;
+mod support;
+#[cfg(feature = "test-util")]
+pub mod test_support;
+mod timestamps;
+mod validate;
 mod watch;
 
+#[cfg(feature = "sqlite")]
+pub use backend::SqliteBackend;
+pub use backend::{PreferencesBackend, PreferencesBackends};
 use bevy::{
+    app::{AppLabel, InternedAppLabel},
     ecs::{component::Tick, world::Command},
     prelude::*,
+    utils::HashSet,
 };
+pub use diff::{preferences_diff, PreferencePath};
 use directories::BaseDirs;
-pub use save::SavePreferences;
+pub use encryption::{PreferencesCipher, PreferencesEncryptedGroups};
+pub use error::PreferencesError;
+#[cfg(feature = "postcard")]
+pub use format::BinaryFormat;
+pub use format::{
+    FormatError, PreferencesComment, PreferencesFileFormat, PreferencesFormat, PreferencesLayout,
+    PreferencesOrdering, PreferencesTableStyle, TomlFormat,
+};
+pub use integrity::PreferencesIntegrity;
+pub use keys::PreferencesKeyStyle;
+pub use load::{load_lazy_preference, load_preferences_from_bytes, PreferencesMigrations};
+pub use registration::{PreferenceRegistration, PreferencesAppExt, PreferencesRegistrations};
+pub use save::{
+    dump_preferences, export_as_env, generate_default_document, preferences_hash,
+    save_app_preferences, DumpPreferences, PreferencesHeader, PreferencesPreWrite,
+    PreferencesSubApps, SavePreferences,
+};
+#[cfg(feature = "keyring")]
+pub use secrets::KeyringStore;
+pub use secrets::{PreferencesSecrets, SecretStore};
+pub use support::{enum_variants, is_supported_preference_type, supported_preference_types};
+pub use timestamps::PreferencesTimestamps;
+pub use validate::PreferencesPostLoadValidator;
 pub use watch::watch_prefs_changes;
 
 /// Annotation for a type which causes the type's contents to be placed in a named table
-/// in the preferences file.
+/// in the preferences file. Segments separated by `/`, e.g. `"game/graphics/shadows"`, place
+/// the contents in nested tables.
 #[derive(Debug, Clone, Reflect)]
 pub struct PreferencesGroup(pub &'static str);
 
@@ -21,26 +68,368 @@ pub struct PreferencesGroup(pub &'static str);
 #[derive(Debug, Clone, Reflect)]
 pub struct PreferencesKey(pub &'static str);
 
+/// Annotation for a preference type or field which is written as a `#` comment above its key
+/// in the preferences file, for users who edit the file by hand. Only text formats such as
+/// TOML emit comments.
+#[derive(Debug, Clone, Reflect)]
+pub struct PreferencesDoc(pub &'static str);
+
+/// Annotation for a preference type or field which sets its position in the preferences file,
+/// e.g. to match the order of a settings menu: a group's table, or a key within its table. Items
+/// with lower orders are written first, and items without an order follow in alphabetical order.
+/// Only text formats such as TOML control the order of their keys.
+#[derive(Debug, Clone, Reflect)]
+pub struct PreferencesOrder(pub i32);
+
+/// Annotation for a struct type or field which chooses how its table is written in the
+/// preferences file, in place of the format's own choice, e.g. to keep a small struct on one
+/// line while a large one gets a section of its own. Only text formats such as TOML choose the
+/// form of their tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Reflect)]
+pub enum PreferencesTableForm {
+    /// Write the table on one line, e.g. `size = { width = 800, height = 600 }`.
+    Inline,
+    /// Write the table as a section with a header, e.g. `[window.size]`.
+    Section,
+}
+
+/// Annotation for an `Option` field which chooses how `None` is written in the preferences
+/// file. Either way, a missing key loads as `None`, and so does an empty array unless the
+/// `Option` holds a list, an array or another `Option`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Reflect)]
+pub enum PreferencesNone {
+    /// Leave the key out of the file. This is the default.
+    #[default]
+    Omit,
+    /// Write the key with an empty array, e.g. `nickname = []`, so that tools reading the file
+    /// see the key. This doesn't apply to an `Option<Option<T>>`, where `[]` is `Some(None)`,
+    /// nor to an `Option` of a list or array, where `[]` is an empty list; for these the key is
+    /// left out, with a warning.
+    Explicit,
+}
+
+/// Annotation for a `String` field which holds a secret, such as an API token. Secret fields
+/// are never written to the preferences file; instead they are kept in the
+/// [`PreferencesSecrets`] store, if there is one.
+#[derive(Debug, Clone, Reflect)]
+pub struct PreferencesSecret;
+
+/// Annotation for a struct field whose type is another preference resource, e.g. a shared
+/// theme which several settings use. The field is stored as the group name of that resource,
+/// `theme = "ui/theme"`, rather than inlined, and when loading it is set to the value of the
+/// resource the file refers to. Only the top-level fields of a grouped struct resource are
+/// linked, and the referenced type must declare its group with [`PreferencesGroup`].
+#[derive(Debug, Clone, Reflect)]
+pub struct PreferencesRef;
+
+/// Annotation for a field which isn't a preference, such as a cache or a handle. Skipped fields
+/// are never written to the preferences file, and loading leaves them unchanged.
+#[derive(Debug, Clone, Reflect)]
+pub struct PreferencesSkip;
+
+/// Annotation for an enum whose unit variants are stored as their index, in declaration order,
+/// rather than their name, e.g. for compactness or so that variants can be renamed. The index
+/// is the same as the discriminant for enums without explicit discriminants. Either form is
+/// accepted when loading.
+#[derive(Debug, Clone, Reflect)]
+pub struct PreferencesEnumIndex;
+
+/// Annotation for a single-field tuple struct resource which wraps a preference type, e.g.
+/// `Prefs<AudioSettings>`. The wrapper is left out of the preferences file and its field is
+/// stored in its place, using the group and key of the field's type, the same way as the state
+/// of a `State<S>` resource.
+#[derive(Debug, Clone, Reflect)]
+pub struct PreferencesTransparent;
+
 /// Resource for tracking the last tick at which preferences were saved.
 #[derive(Debug, Clone, Resource)]
 pub struct PreferencesSaveTick(pub Tick);
 
 pub struct PreferencesPlugin {
     pub app_name: String,
+    pub format: PreferencesFileFormat,
+    pub registrations: Vec<PreferenceRegistration>,
+    pub sub_apps: Vec<InternedAppLabel>,
 }
 
 impl PreferencesPlugin {
     pub fn new(app_name: &str) -> Self {
         Self {
             app_name: app_name.to_string(),
+            format: PreferencesFileFormat::default(),
+            registrations: Vec::new(),
+            sub_apps: Vec::new(),
         }
     }
+
+    /// Register a type as a preference without requiring reflect attributes.
+    pub fn with_type(mut self, registration: impl Into<PreferenceRegistration>) -> Self {
+        self.registrations.push(registration.into());
+        self
+    }
+
+    /// Register several types as preferences at once, e.g.
+    /// `with_types([(TypeId::of::<Volume>(), Some("audio"), Some("volume"))])`.
+    pub fn with_types<R: Into<PreferenceRegistration>>(
+        mut self,
+        registrations: impl IntoIterator<Item = R>,
+    ) -> Self {
+        self.registrations
+            .extend(registrations.into_iter().map(Into::into));
+        self
+    }
+
+    /// Include the preferences of the given sub-app when saving with [`save_app_preferences`].
+    pub fn with_sub_app(mut self, label: impl AppLabel) -> Self {
+        self.sub_apps.push(label.intern());
+        self
+    }
+
+    /// Use the given file format instead of TOML.
+    pub fn with_format(mut self, format: impl PreferencesFormat) -> Self {
+        self.format = PreferencesFileFormat::new(format);
+        self
+    }
 }
 
 impl Default for PreferencesPlugin {
     fn default() -> Self {
+        Self::new("bevy_app")
+    }
+}
+
+/// Resource which controls what happens to a preference resource's fields when their keys are
+/// missing from the preferences file.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PreferencesLoadMode {
+    /// Leave the field at its current value. This is the default.
+    #[default]
+    KeepOnMissing,
+    /// Reset the resource to its `Default` value before applying the file contents, so that
+    /// missing keys end up at their default values. This requires the resource type to
+    /// register `ReflectDefault`, e.g. `#[reflect(Default)]`.
+    ResetOnMissing,
+}
+
+/// Resource which, when set to false, turns off the persistence of `State<S>` resources, so
+/// that every run starts in the default state: states are neither written to the preferences
+/// file nor loaded into their `NextState<S>`. States are persisted by default.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PreferencesPersistState(pub bool);
+
+impl Default for PreferencesPersistState {
+    fn default() -> Self {
+        Self(true)
+    }
+}
+
+impl PreferencesPersistState {
+    pub(crate) fn enabled(world: &World) -> bool {
+        world
+            .get_resource::<PreferencesPersistState>()
+            .is_none_or(|persist| persist.0)
+    }
+}
+
+/// Resource which controls which values a save writes to the preferences file.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PreferencesSaveMode {
+    /// Write the current value of every preference, replacing the file. This is the default.
+    #[default]
+    Full,
+    /// Only write the values which differ from the ones loaded at startup, and keep the rest
+    /// of the file as it is, e.g. so that hand edits made while the app is running aren't
+    /// overwritten. Values which were removed since loading, such as an `Option` set to
+    /// `None`, are not removed from the file. This must be inserted before the preferences are
+    /// loaded.
+    ChangedSinceLoad,
+}
+
+/// Resource which, when set to true, reports each key or group in the preferences file which
+/// doesn't belong to a registered preference as a [`PreferencesError::UnknownKey`] event,
+/// rather than only logging it at debug level. This is useful in tests, to catch typos in a
+/// file of bundled defaults.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PreferencesLoadStrict(pub bool);
+
+/// Resource listing struct types which are stored as an array of their fields, in order, rather
+/// than as a table, e.g. `position = [1.0, 2.0]` for a 2D vector type from a math crate. Any
+/// struct stored as an array in the file is loaded by position, whether or not it is listed.
+#[derive(Resource, Debug, Default, Clone)]
+pub struct PreferencesArrayTypes(HashSet<String>);
+
+impl PreferencesArrayTypes {
+    /// Store the struct type `T` as an array.
+    pub fn with_type<T: TypePath>(self) -> Self {
+        self.with_type_path(T::type_path())
+    }
+
+    /// Store the struct type with the given type path as an array.
+    pub fn with_type_path(mut self, type_path: &str) -> Self {
+        self.0.insert(type_path.to_string());
+        self
+    }
+
+    pub(crate) fn contains(&self, type_path: &str) -> bool {
+        self.0.contains(type_path)
+    }
+}
+
+/// Resource which selects how `Duration` values are written to the preferences file. Either
+/// form is accepted when loading, as long as the `humantime` feature is enabled for strings.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PreferencesDurationFormat {
+    /// A number of seconds, e.g. `1.5`. This is the default.
+    #[default]
+    Seconds,
+    /// A human-readable string in the format of the `humantime` crate, e.g. `"1s 500ms"`.
+    #[cfg(feature = "humantime")]
+    Human,
+}
+
+/// Resource which, when set to true, catches a panic while serializing a preference resource,
+/// such as one with a field of a type which isn't supported yet. The resource is left out of
+/// the file and reported as a [`PreferencesError::SerializationPanicked`] event, and the other
+/// resources are still saved. The panic is still printed by the panic hook.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PreferencesCatchPanics(pub bool);
+
+/// Resource which, when set to true, reads the preferences file back after each save and checks
+/// that it parses to the values which were written. A mismatch is reported as a
+/// [`PreferencesError::VerificationFailed`] event.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PreferencesVerifyWrites(pub bool);
+
+/// Resource which sets how many backups of the preferences file are kept. Before each save
+/// which changes the file, the previous file is copied to `prefs.toml.bak`, and the older
+/// backups move along to `prefs.toml.bak.1`, `prefs.toml.bak.2` and so on, keeping at most this
+/// many. A file which can't be loaded falls back to these backups. The default of 0 keeps no
+/// backups.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PreferencesBackups(pub usize);
+
+/// Resource which controls how enum variant names in the preferences file are matched to the
+/// variants of the enum when loading.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PreferencesVariantMatching {
+    /// The name must match exactly. This is the default.
+    #[default]
+    Exact,
+    /// If there is no exact match, ignore ASCII case, so that a hand-edited `"dark"` loads as
+    /// `Dark`. A warning is logged whenever the fallback is used.
+    CaseInsensitive,
+}
+
+/// Resource which controls what happens to values of opaque types which aren't supported
+/// directly, such as a custom ID type.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PreferencesOpaqueFallback {
+    /// Leave the value out of the preferences file with a warning. This is the default.
+    #[default]
+    Disabled,
+    /// Encode the value with serde if its type registers `ReflectSerialize`, e.g. with
+    /// `#[reflect(opaque, Serialize, Deserialize)]`. Values of types which register
+    /// `ReflectDeserialize` are always decoded with serde, so they still load if this is turned
+    /// off again.
+    Serde,
+}
+
+/// Resource which controls whether tables with nothing in them are written, such as the group
+/// of a struct whose fields are all unsupported or secret.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PreferencesEmptyTables {
+    /// Write empty tables, e.g. a bare `[group]` header. This is the default.
+    #[default]
+    Keep,
+    /// Leave empty tables out of the output, including tables which only contained empty
+    /// tables.
+    Omit,
+}
+
+/// Resource which places all of the preferences of this world under a top-level table of the
+/// given name, e.g. `[editor.audio]` instead of `[audio]`. Saving replaces only that table and
+/// keeps the other top-level tables of the file, so that worlds with different namespaces, such
+/// as the main world and a sub-app, can share one preferences file without collisions.
+#[derive(Resource, Debug, Clone, PartialEq, Eq)]
+pub struct PreferencesNamespace(pub String);
+
+/// Resource naming a directory of `.toml` fragments, e.g. `prefs.d`, which are merged over the
+/// main preferences file when it is loaded. Fragments are applied in alphabetical order of their
+/// file names, so later fragments override earlier ones. A relative path is resolved against
+/// the [`PreferencesDir`]. Saving still writes only the main file.
+#[derive(Resource, Debug, Default, Clone, PartialEq, Eq)]
+pub struct PreferencesFragmentsDir(pub Option<std::path::PathBuf>);
+
+/// Resource which limits the size of the serialized preferences, in bytes, as an early warning
+/// that something is being saved which shouldn't be, such as a list which keeps growing. Larger
+/// output is logged as a warning and written anyway, or in strict mode, it isn't written and a
+/// [`PreferencesError`] is sent.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PreferencesMaxSize {
+    pub bytes: usize,
+    pub strict: bool,
+}
+
+impl PreferencesMaxSize {
+    /// Warn about output larger than `bytes`, but still write it.
+    pub fn warn(bytes: usize) -> Self {
         Self {
-            app_name: "bevy_app".to_string(),
+            bytes,
+            strict: false,
+        }
+    }
+
+    /// Refuse to write output larger than `bytes`.
+    pub fn strict(bytes: usize) -> Self {
+        Self {
+            bytes,
+            strict: true,
+        }
+    }
+
+    /// Check the size of the serialized output against the limit.
+    pub(crate) fn check(&self, size: usize) -> Result<(), PreferencesError> {
+        if size > self.bytes {
+            Err(PreferencesError::TooLarge {
+                size,
+                max: self.bytes,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Resource which controls where the temporary file is written before it is renamed over the
+/// preferences file, so that a crash while saving never leaves a partial file behind.
+#[derive(Resource, Debug, Default, Clone, PartialEq, Eq)]
+pub enum PreferencesTempStrategy {
+    /// Write `prefs.toml.new` next to the preferences file. This is the default.
+    #[default]
+    Suffix,
+    /// Write the temporary file next to the preferences file with a random suffix, e.g.
+    /// `prefs.toml.3fa2c1.new`, so that processes sharing the directory don't overwrite each
+    /// other's temporary files.
+    RandomSuffix,
+    /// Write the temporary file to the given directory, which is created if needed. The
+    /// directory must be on the same filesystem as the preferences file, or the rename fails.
+    Directory(std::path::PathBuf),
+}
+
+impl PreferencesTempStrategy {
+    /// The path of the temporary file for writing `file_name` in `dir`.
+    pub(crate) fn temp_path(&self, dir: &std::path::Path, file_name: &str) -> std::path::PathBuf {
+        match self {
+            Self::Suffix => dir.join(format!("{}.new", file_name)),
+            Self::RandomSuffix => {
+                // Each `RandomState` is seeded differently, which is random enough to tell
+                // temporary files apart.
+                use std::hash::{BuildHasher, Hasher};
+                let mut hasher = std::hash::RandomState::new().build_hasher();
+                hasher.write_u32(std::process::id());
+                dir.join(format!("{}.{:x}.new", file_name, hasher.finish()))
+            }
+            Self::Directory(temp_dir) => temp_dir.join(format!("{}.new", file_name)),
         }
     }
 }
@@ -48,23 +437,144 @@ impl Default for PreferencesPlugin {
 #[derive(Resource)]
 pub struct PreferencesDir(pub std::path::PathBuf);
 
+/// Resource holding a hook which picks the preferences directory when the plugin is built,
+/// e.g. to special-case Steam Deck or console sandboxes where the configuration lives outside
+/// the standard directory. It must be inserted before the plugin is added, and is only used if
+/// no [`PreferencesDir`] has been inserted either; otherwise the plugin uses the operating
+/// system's preferences directory.
+#[derive(Resource)]
+pub struct PreferencesDirResolver(pub Box<dyn Fn() -> std::path::PathBuf + Send + Sync>);
+
 #[derive(Resource, Default)]
 pub struct PreferencesChanged(bool);
 
+impl PreferencesChanged {
+    /// Returns true if the preferences have been marked as changed, and not saved since.
+    pub fn is_changed(&self) -> bool {
+        self.0
+    }
+}
+
+/// Resource holding the time left before changed preferences are saved, which counts down with
+/// the [`Time`] resource. Since it doesn't read the system clock, tests can advance `Time` by
+/// hand to trigger the save.
 #[derive(Resource, Default)]
-pub struct PreferencesDebounceTimer(f32);
+pub struct PreferencesDebounceTimer {
+    remaining: f32,
+    /// A change was marked with [`mark_preferences_changed_critical`], so the next autosave
+    /// doesn't wait.
+    critical: bool,
+    /// The seconds since the first change which hasn't been saved yet.
+    unsaved: f32,
+}
+
+impl PreferencesDebounceTimer {
+    /// The seconds left before the changed preferences are saved.
+    pub fn remaining(&self) -> f32 {
+        self.remaining
+    }
+
+    /// The seconds since the first change which hasn't been saved yet.
+    pub fn unsaved(&self) -> f32 {
+        self.unsaved
+    }
+
+    /// Forget the pending changes once the preferences have been saved some other way.
+    pub(crate) fn clear_pending(&mut self) {
+        self.critical = false;
+        self.unsaved = 0.0;
+    }
+}
+
+/// Resource which limits how long changed preferences can go unsaved, in seconds. Each change
+/// restarts the debounce delay, so a value which keeps changing, such as a slider which is being
+/// dragged, would otherwise never be saved; once the first unsaved change is this old, the
+/// autosave saves the preferences even though they are still changing. Defaults to 10 seconds.
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct PreferencesMaxSaveDelay(pub f32);
+
+impl Default for PreferencesMaxSaveDelay {
+    fn default() -> Self {
+        Self(10.0)
+    }
+}
+
+/// Resource which makes the autosave wait until the preferences haven't been changed for the
+/// given number of frames, instead of using the [`PreferencesDebounceTimer`]. Counting frames
+/// rather than time means that a paused app, or one stalled by a long frame, doesn't save in
+/// the middle of an interaction. The [`PreferencesMaxSaveDelay`] still applies.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PreferencesIdleFrames {
+    frames: u32,
+    remaining: u32,
+}
+
+impl PreferencesIdleFrames {
+    pub fn new(frames: u32) -> Self {
+        Self {
+            frames,
+            remaining: 0,
+        }
+    }
+
+    /// The frames left without changes before the changed preferences are saved.
+    pub fn remaining(&self) -> u32 {
+        self.remaining
+    }
+}
+
+/// Resource recording which preference resources have been marked as changed with
+/// [`mark_changed`] since the preferences were last saved.
+#[derive(Resource, Debug, Default, Clone)]
+pub struct PreferencesDirty(HashSet<std::any::TypeId>);
+
+impl PreferencesDirty {
+    /// Returns true if the resource `T` has been marked as changed.
+    pub fn contains<T: 'static>(&self) -> bool {
+        self.0.contains(&std::any::TypeId::of::<T>())
+    }
+
+    /// The type ids of the resources which have been marked as changed.
+    pub fn iter(&self) -> impl Iterator<Item = std::any::TypeId> + '_ {
+        self.0.iter().copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.0.clear();
+    }
+}
 
 impl Plugin for PreferencesPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<PreferencesChanged>()
             .init_resource::<PreferencesDebounceTimer>()
+            .insert_resource(self.format.clone())
+            .init_resource::<PreferencesRegistrations>()
+            .init_resource::<cache::PreferencesResourceCache>()
+            .add_event::<PreferencesError>()
+            .insert_resource(PreferencesSubApps(self.sub_apps.clone()))
             .add_systems(Update, save_preferences);
-        if let Some(base_dirs) = BaseDirs::new() {
-            let prefs_path = base_dirs.preference_dir().join(&self.app_name);
-            app.insert_resource(PreferencesDir(prefs_path.clone()));
-            info!("Preferences path: {:?}", prefs_path);
-        } else {
-            warn!("Could not find user configuration directories");
+        let mut registrations = app.world_mut().resource_mut::<PreferencesRegistrations>();
+        for registration in &self.registrations {
+            registrations.register(registration.clone());
+        }
+        if !app.world().contains_resource::<PreferencesDir>() {
+            let prefs_path = match app.world().get_resource::<PreferencesDirResolver>() {
+                Some(resolver) => Some((resolver.0)()),
+                None => {
+                    BaseDirs::new().map(|base_dirs| base_dirs.preference_dir().join(&self.app_name))
+                }
+            };
+            if let Some(prefs_path) = prefs_path {
+                app.insert_resource(PreferencesDir(prefs_path.clone()));
+                info!("Preferences path: {:?}", prefs_path);
+            } else {
+                warn!("Could not find user configuration directories");
+            }
         }
     }
 
@@ -81,13 +591,27 @@ impl Plugin for PreferencesPlugin {
 fn save_preferences(
     mut changed: ResMut<PreferencesChanged>,
     mut timer: ResMut<PreferencesDebounceTimer>,
+    idle: Option<ResMut<PreferencesIdleFrames>>,
+    max_delay: Option<Res<PreferencesMaxSaveDelay>>,
     time: Res<Time>,
     mut cmd: Commands,
 ) {
     if changed.0 {
-        timer.0 = (timer.0 - time.delta_secs()).max(0.0);
-        if timer.0 <= 0.0 {
+        let max_delay = max_delay.as_deref().copied().unwrap_or_default();
+        timer.unsaved += time.delta_secs();
+        let elapsed = match idle {
+            Some(mut idle) => {
+                idle.remaining = idle.remaining.saturating_sub(1);
+                idle.remaining == 0
+            }
+            None => {
+                timer.remaining = (timer.remaining - time.delta_secs()).max(0.0);
+                timer.remaining <= 0.0
+            }
+        };
+        if elapsed || timer.critical || timer.unsaved >= max_delay.0 {
             changed.0 = false;
+            timer.clear_pending();
             cmd.queue(SavePreferences::Always);
         }
     }
@@ -103,6 +627,614 @@ impl Command for SetPreferencesChanged {
         let mut timer = world
             .get_resource_mut::<PreferencesDebounceTimer>()
             .unwrap();
-        timer.0 = 1.0;
+        timer.remaining = 1.0;
+        if let Some(mut idle) = world.get_resource_mut::<PreferencesIdleFrames>() {
+            idle.remaining = idle.frames;
+        }
+    }
+}
+
+/// Mark the preferences as changed, as [`SetPreferencesChanged`] does, for a change which must
+/// not be lost, such as a purchase or an unlock. The autosave then saves the preferences the
+/// next time it runs, without waiting for the debounce delay or the [`PreferencesIdleFrames`].
+pub fn mark_preferences_changed_critical(world: &mut World) {
+    SetPreferencesChanged.apply(world);
+    world
+        .get_resource_mut::<PreferencesDebounceTimer>()
+        .unwrap()
+        .critical = true;
+}
+
+/// Record the preference resource `T` as changed in the [`PreferencesDirty`] set, and mark the
+/// preferences as changed, as [`SetPreferencesChanged`] does.
+pub fn mark_changed<T: Resource>(world: &mut World) {
+    world
+        .get_resource_or_insert_with(PreferencesDirty::default)
+        .0
+        .insert(std::any::TypeId::of::<T>());
+    SetPreferencesChanged.apply(world);
+}
+
+/// Modify a preference resource and mark it as changed, as [`mark_changed`] does, so that the
+/// change can't be saved without also being flagged, e.g.
+/// `update_preference::<AudioSettings>(world, |audio| audio.volume = 0.5)`.
+pub fn update_preference<T: Resource>(world: &mut World, update: impl FnOnce(&mut T)) {
+    update(&mut world.resource_mut::<T>());
+    mark_changed::<T>(world);
+}
+
+/// Command which loads the preferences again from the preferences directory.
+#[derive(Default)]
+pub struct LoadPreferences;
+
+impl Command for LoadPreferences {
+    fn apply(self, world: &mut World) {
+        load::load_preferences(world);
+    }
+}
+
+/// Command which loads only the named groups again from the preferences file, e.g. after the
+/// file was edited outside the app, leaving the other preferences as they are. Groups are named
+/// as in [`PreferencesGroup`], and nested groups are loaded along with their parent.
+pub struct LoadPreferencesGroups(pub Vec<String>);
+
+impl Command for LoadPreferencesGroups {
+    fn apply(self, world: &mut World) {
+        load::load_preference_groups(world, &self.0);
+    }
+}
+
+/// Change the [`PreferencesDir`], e.g. to a location chosen by the user, and queue a
+/// [`LoadPreferences`] to load the preferences from there. Pending changes are dropped, so that
+/// they aren't saved over the preferences in the new directory.
+pub fn set_preferences_dir(world: &mut World, path: impl Into<std::path::PathBuf>) {
+    world.insert_resource(PreferencesDir(path.into()));
+    if let Some(mut changed) = world.get_resource_mut::<PreferencesChanged>() {
+        changed.0 = false;
+    }
+    if let Some(mut dirty) = world.get_resource_mut::<PreferencesDirty>() {
+        dirty.clear();
+    }
+    world.commands().queue(LoadPreferences);
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        any::TypeId,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use super::*;
+
+    /// Create an empty scratch directory for a test.
+    pub(crate) fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("bevy_basic_prefs_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Create a world with the resources needed to save and load preferences in `dir`.
+    pub(crate) fn test_world(dir: &std::path::Path) -> World {
+        let mut world = World::new();
+        world.init_resource::<AppTypeRegistry>();
+        world.init_resource::<PreferencesChanged>();
+        world.init_resource::<cache::PreferencesResourceCache>();
+        world.insert_resource(PreferencesDir(dir.to_path_buf()));
+        world
+    }
+
+    /// Count the preferences files written in `world`.
+    fn count_saves(world: &mut World) -> Arc<AtomicUsize> {
+        let saves = Arc::new(AtomicUsize::new(0));
+        let counter = saves.clone();
+        world.insert_resource(save::PreferencesPreWrite(Box::new(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        })));
+        saves
+    }
+
+    /// A schedule which saves the preferences when they have changed, as the plugin does.
+    fn save_schedule() -> Schedule {
+        let mut schedule = Schedule::default();
+        schedule.add_systems(save_preferences);
+        schedule
+    }
+
+    #[derive(Resource, Reflect)]
+    struct Volume(f32);
+
+    #[derive(Resource, Reflect)]
+    struct PlayerName(String);
+
+    #[derive(Resource, Reflect)]
+    struct WindowSize {
+        width: i32,
+        height: i32,
+    }
+
+    #[test]
+    fn test_bulk_registration() {
+        let mut app = App::new();
+        app.add_plugins(PreferencesPlugin::new("bulk_registration").with_types([
+            (TypeId::of::<Volume>(), Some("audio"), Some("volume")),
+            (TypeId::of::<PlayerName>(), None, Some("player_name")),
+            (TypeId::of::<WindowSize>(), Some("window"), None),
+        ]))
+        .register_type::<Volume>()
+        .register_type::<PlayerName>()
+        .register_type::<WindowSize>()
+        .insert_resource(Volume(0.5))
+        .insert_resource(PlayerName("Ferris".to_string()))
+        .insert_resource(WindowSize {
+            width: 800,
+            height: 600,
+        });
+
+        let table = save::build_preferences_table(app.world());
+        assert_eq!(table["audio"]["volume"].as_float(), Some(0.5));
+        assert_eq!(table["player_name"].as_str(), Some("Ferris"));
+        assert_eq!(table["window"]["width"].as_integer(), Some(800));
+        assert_eq!(table["window"]["height"].as_integer(), Some(600));
+    }
+
+    #[test]
+    fn test_checksum_mismatch_uses_defaults() {
+        let dir = test_dir("checksum_mismatch");
+        let mut world = test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<Volume>();
+        world.insert_resource(PreferencesRegistrations::default());
+        world.resource_mut::<PreferencesRegistrations>().register((
+            TypeId::of::<Volume>(),
+            Some("audio"),
+            Some("volume"),
+        ));
+        world.insert_resource(PreferencesIntegrity::Crc32);
+        world.insert_resource(Volume(0.5));
+        SavePreferences::Always.apply(&mut world);
+        assert!(dir.join("prefs.toml.crc32").exists());
+
+        // An intact file loads normally.
+        world.insert_resource(Volume(1.0));
+        load::load_preferences(&mut world);
+        assert_eq!(world.resource::<Volume>().0, 0.5);
+
+        // A corrupted file is rejected.
+        std::fs::write(dir.join("prefs.toml"), "[audio]\nvolume = 0.25\n").unwrap();
+        world.insert_resource(Volume(1.0));
+        load::load_preferences(&mut world);
+        assert_eq!(world.resource::<Volume>().0, 1.0);
+    }
+
+    #[test]
+    fn test_checksum_added_to_unchanged_file() {
+        let dir = test_dir("checksum_unchanged");
+        let mut world = test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<Volume>();
+        world.insert_resource(PreferencesRegistrations::default());
+        world.resource_mut::<PreferencesRegistrations>().register((
+            TypeId::of::<Volume>(),
+            Some("audio"),
+            Some("volume"),
+        ));
+        world.insert_resource(Volume(0.5));
+        SavePreferences::Always.apply(&mut world);
+        assert!(!dir.join("prefs.toml.crc32").exists());
+
+        // Enabling integrity checking writes the checksum, although the file is unchanged.
+        world.insert_resource(PreferencesIntegrity::Crc32);
+        SavePreferences::Always.apply(&mut world);
+        assert!(dir.join("prefs.toml.crc32").exists());
+        world.insert_resource(Volume(1.0));
+        load::load_preferences(&mut world);
+        assert_eq!(world.resource::<Volume>().0, 0.5);
+
+        // A stale checksum is replaced too.
+        std::fs::write(dir.join("prefs.toml.crc32"), "00000000\n").unwrap();
+        SavePreferences::Always.apply(&mut world);
+        world.insert_resource(Volume(1.0));
+        load::load_preferences(&mut world);
+        assert_eq!(world.resource::<Volume>().0, 0.5);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[derive(Resource, Reflect, Default)]
+    #[reflect(@PreferencesGroup("graphics"))]
+    struct GraphicsSettings {
+        brightness: f32,
+    }
+
+    #[test]
+    fn test_init_preference() {
+        let mut app = App::new();
+        app.add_plugins(PreferencesPlugin::new("init_preference"))
+            .init_preference::<GraphicsSettings>();
+
+        assert_eq!(app.world().resource::<GraphicsSettings>().brightness, 0.0);
+        let type_id = TypeId::of::<GraphicsSettings>();
+        assert!(app
+            .world()
+            .resource::<AppTypeRegistry>()
+            .read()
+            .contains(type_id));
+        assert!(app
+            .world()
+            .resource::<PreferencesRegistrations>()
+            .get(type_id)
+            .is_some());
+
+        let table = save::build_preferences_table(app.world());
+        assert_eq!(table["graphics"]["brightness"].as_float(), Some(0.0));
+    }
+
+    #[test]
+    fn test_update_preference() {
+        let mut world = test_world(&test_dir("update_preference"));
+        world.init_resource::<PreferencesDebounceTimer>();
+        world.insert_resource(GraphicsSettings { brightness: 0.0 });
+        update_preference::<GraphicsSettings>(&mut world, |graphics| graphics.brightness = 0.5);
+        assert_eq!(world.resource::<GraphicsSettings>().brightness, 0.5);
+        assert!(world.resource::<PreferencesChanged>().0);
+    }
+
+    #[test]
+    fn test_mark_changed() {
+        let dir = test_dir("mark_changed");
+        let mut world = test_world(&dir);
+        world.init_resource::<PreferencesDebounceTimer>();
+        world.insert_resource(GraphicsSettings { brightness: 0.5 });
+        world.insert_resource(Volume(0.5));
+        mark_changed::<GraphicsSettings>(&mut world);
+
+        let dirty = world.resource::<PreferencesDirty>();
+        assert!(dirty.contains::<GraphicsSettings>());
+        assert!(!dirty.contains::<Volume>());
+        assert_eq!(dirty.iter().count(), 1);
+        assert!(world.resource::<PreferencesChanged>().0);
+
+        SavePreferences::IfChanged.apply(&mut world);
+        assert!(world.resource::<PreferencesDirty>().is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_debounce_with_manual_clock() {
+        let dir = test_dir("manual_clock");
+        let mut world = test_world(&dir);
+        world.init_resource::<PreferencesDebounceTimer>();
+        // Without the `TimePlugin`, time only passes when the test advances it.
+        world.init_resource::<Time>();
+        let saves = count_saves(&mut world);
+        let mut schedule = save_schedule();
+
+        SetPreferencesChanged.apply(&mut world);
+        for remaining in [0.6, 0.2] {
+            world
+                .resource_mut::<Time>()
+                .advance_by(std::time::Duration::from_millis(400));
+            schedule.run(&mut world);
+            let timer = world.resource::<PreferencesDebounceTimer>().remaining();
+            assert!((timer - remaining).abs() < 1e-6);
+            assert_eq!(saves.load(Ordering::SeqCst), 0);
+        }
+        for _ in 0..3 {
+            world
+                .resource_mut::<Time>()
+                .advance_by(std::time::Duration::from_millis(400));
+            schedule.run(&mut world);
+        }
+        assert_eq!(saves.load(Ordering::SeqCst), 1);
+        assert!(!world.resource::<PreferencesChanged>().is_changed());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_dir_resolver() {
+        let dir = test_dir("dir_resolver");
+        let resolved = dir.join("steamdeck");
+        let mut app = App::new();
+        app.insert_resource(PreferencesDirResolver(Box::new(move || resolved.clone())))
+            .add_plugins(PreferencesPlugin::new("dir_resolver"));
+        assert_eq!(
+            app.world().resource::<PreferencesDir>().0,
+            dir.join("steamdeck")
+        );
+
+        // An explicit directory takes precedence.
+        let mut app = App::new();
+        app.insert_resource(PreferencesDir(dir.join("explicit")))
+            .insert_resource(PreferencesDirResolver(Box::new(|| unreachable!())))
+            .add_plugins(PreferencesPlugin::new("dir_resolver"));
+        assert_eq!(
+            app.world().resource::<PreferencesDir>().0,
+            dir.join("explicit")
+        );
+    }
+
+    #[test]
+    fn test_max_save_delay() {
+        let dir = test_dir("max_save_delay");
+        let mut world = test_world(&dir);
+        world.init_resource::<PreferencesDebounceTimer>();
+        world.init_resource::<Time>();
+        world.insert_resource(PreferencesMaxSaveDelay(2.0));
+        let saves = count_saves(&mut world);
+        let mut schedule = save_schedule();
+
+        // A value which changes every frame keeps restarting the debounce delay, but is still
+        // saved once the first change is two seconds old.
+        let mut frame = || {
+            SetPreferencesChanged.apply(&mut world);
+            world
+                .resource_mut::<Time>()
+                .advance_by(std::time::Duration::from_millis(500));
+            schedule.run(&mut world);
+            saves.load(Ordering::SeqCst)
+        };
+        for _ in 0..3 {
+            assert_eq!(frame(), 0);
+        }
+        assert_eq!(frame(), 1);
+
+        // The window starts again from the next change.
+        for _ in 0..3 {
+            assert_eq!(frame(), 1);
+        }
+        assert_eq!(frame(), 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_critical_change() {
+        let dir = test_dir("critical_change");
+        let mut world = test_world(&dir);
+        world.init_resource::<PreferencesDebounceTimer>();
+        world.init_resource::<Time>();
+        let saves = count_saves(&mut world);
+        let mut schedule = save_schedule();
+
+        // A normal change waits for the debounce delay.
+        SetPreferencesChanged.apply(&mut world);
+        schedule.run(&mut world);
+        assert_eq!(saves.load(Ordering::SeqCst), 0);
+
+        // A critical change is saved right away, along with the pending normal one.
+        mark_preferences_changed_critical(&mut world);
+        schedule.run(&mut world);
+        assert_eq!(saves.load(Ordering::SeqCst), 1);
+        assert!(!world.resource::<PreferencesChanged>().is_changed());
+
+        // Later normal changes are debounced again.
+        SetPreferencesChanged.apply(&mut world);
+        schedule.run(&mut world);
+        assert_eq!(saves.load(Ordering::SeqCst), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_idle_frames() {
+        let dir = test_dir("idle_frames");
+        let mut world = test_world(&dir);
+        world.init_resource::<PreferencesDebounceTimer>();
+        world.init_resource::<Time>();
+        world.insert_resource(PreferencesIdleFrames::new(3));
+        let saves = count_saves(&mut world);
+        let mut schedule = save_schedule();
+
+        // A change resets the count, and the debounce delay passing doesn't matter.
+        SetPreferencesChanged.apply(&mut world);
+        schedule.run(&mut world);
+        SetPreferencesChanged.apply(&mut world);
+        for remaining in [2, 1] {
+            world
+                .resource_mut::<Time>()
+                .advance_by(std::time::Duration::from_secs(1));
+            schedule.run(&mut world);
+            assert_eq!(
+                world.resource::<PreferencesIdleFrames>().remaining(),
+                remaining
+            );
+            assert_eq!(saves.load(Ordering::SeqCst), 0);
+        }
+        schedule.run(&mut world);
+        assert_eq!(saves.load(Ordering::SeqCst), 1);
+        assert!(!world.resource::<PreferencesChanged>().is_changed());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_load_preferences_groups() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"))]
+        struct AudioSettings {
+            volume: f32,
+        }
+
+        let dir = test_dir("load_groups");
+        let mut world = test_world(&dir);
+        {
+            let mut registry = world.resource::<AppTypeRegistry>().write();
+            registry.register::<AudioSettings>();
+            registry.register::<GraphicsSettings>();
+        }
+        world.insert_resource(AudioSettings { volume: 0.5 });
+        world.insert_resource(GraphicsSettings { brightness: 0.5 });
+        SavePreferences::Always.apply(&mut world);
+
+        // Both groups are edited outside the app, but only audio is loaded again.
+        std::fs::write(
+            dir.join("prefs.toml"),
+            "[audio]\nvolume = 0.25\n\n[graphics]\nbrightness = 1.0\n",
+        )
+        .unwrap();
+        LoadPreferencesGroups(vec!["audio".to_string()]).apply(&mut world);
+        assert_eq!(world.resource::<AudioSettings>().volume, 0.25);
+        assert_eq!(world.resource::<GraphicsSettings>().brightness, 0.5);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_set_preferences_dir() {
+        let old_dir = test_dir("set_dir_old");
+        let new_dir = test_dir("set_dir_new");
+        let mut world = test_world(&old_dir);
+        world.init_resource::<PreferencesDebounceTimer>();
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<GraphicsSettings>();
+        world.insert_resource(GraphicsSettings { brightness: 0.5 });
+        std::fs::create_dir_all(&new_dir).unwrap();
+        std::fs::write(
+            new_dir.join("prefs.toml"),
+            "[graphics]\nbrightness = 0.25\n",
+        )
+        .unwrap();
+        mark_changed::<GraphicsSettings>(&mut world);
+
+        set_preferences_dir(&mut world, &new_dir);
+        world.flush();
+        assert_eq!(world.resource::<PreferencesDir>().0, new_dir);
+        assert_eq!(world.resource::<GraphicsSettings>().brightness, 0.25);
+        assert!(!world.resource::<PreferencesChanged>().0);
+        SavePreferences::IfChanged.apply(&mut world);
+        assert!(!old_dir.exists());
+        let _ = std::fs::remove_dir_all(&new_dir);
+    }
+
+    #[derive(States, Reflect, Default, Debug, Clone, PartialEq, Eq, Hash)]
+    #[reflect(Default)]
+    enum Difficulty {
+        #[default]
+        Easy,
+        Hard,
+    }
+
+    #[test]
+    fn test_registered_state() {
+        let dir = test_dir("registered_state");
+        let mut world = test_world(&dir);
+        {
+            let registry = world.resource::<AppTypeRegistry>().clone();
+            let mut registry = registry.write();
+            registry.register::<State<Difficulty>>();
+            registry.register::<NextState<Difficulty>>();
+        }
+        let mut registrations = PreferencesRegistrations::default();
+        registrations.register(
+            PreferenceRegistration::of::<State<Difficulty>>()
+                .with_group("game")
+                .with_key("difficulty"),
+        );
+        world.insert_resource(registrations);
+        world.insert_resource(State::new(Difficulty::Hard));
+        world.init_resource::<NextState<Difficulty>>();
+
+        let table = save::build_preferences_table(&world);
+        assert_eq!(table["game"]["difficulty"].as_str(), Some("Hard"));
+
+        load::apply_preferences(&mut world, &table, None);
+        assert!(matches!(
+            world.resource::<NextState<Difficulty>>(),
+            NextState::Pending(Difficulty::Hard)
+        ));
+    }
+
+    #[test]
+    fn test_persist_state_disabled() {
+        let dir = test_dir("persist_state_disabled");
+        let mut world = test_world(&dir);
+        {
+            let registry = world.resource::<AppTypeRegistry>().clone();
+            let mut registry = registry.write();
+            registry.register::<State<Difficulty>>();
+            registry.register::<NextState<Difficulty>>();
+        }
+        let mut registrations = PreferencesRegistrations::default();
+        registrations.register(
+            PreferenceRegistration::of::<State<Difficulty>>()
+                .with_group("game")
+                .with_key("difficulty"),
+        );
+        world.insert_resource(registrations);
+        world.insert_resource(State::new(Difficulty::Hard));
+        world.init_resource::<NextState<Difficulty>>();
+        world.insert_resource(PreferencesPersistState(false));
+
+        let table = save::build_preferences_table(&world);
+        assert!(table.get("game").is_none());
+
+        let table: toml::Table = "[game]\ndifficulty = \"Hard\"\n".parse().unwrap();
+        load::apply_preferences(&mut world, &table, None);
+        assert!(matches!(
+            world.resource::<NextState<Difficulty>>(),
+            NextState::Unchanged
+        ));
+    }
+
+    #[derive(Resource, Reflect)]
+    struct Player1Controls(String);
+
+    #[derive(Resource, Reflect)]
+    struct Player2Controls(String);
+
+    #[test]
+    fn test_profiles() {
+        let dir = test_dir("profiles");
+        let mut world = test_world(&dir);
+        {
+            let registry = world.resource::<AppTypeRegistry>().clone();
+            let mut registry = registry.write();
+            registry.register::<Volume>();
+            registry.register::<Player1Controls>();
+            registry.register::<Player2Controls>();
+        }
+        let mut registrations = PreferencesRegistrations::default();
+        registrations.register((TypeId::of::<Volume>(), Some("audio"), Some("volume")));
+        for (type_id, profile) in [
+            (TypeId::of::<Player1Controls>(), "player1"),
+            (TypeId::of::<Player2Controls>(), "player2"),
+        ] {
+            registrations.register(
+                PreferenceRegistration::from_type_id(type_id)
+                    .with_group("controls")
+                    .with_key("scheme")
+                    .with_profile(profile),
+            );
+        }
+        world.insert_resource(registrations);
+        world.insert_resource(Volume(0.5));
+        world.insert_resource(Player1Controls("keyboard".to_string()));
+        world.insert_resource(Player2Controls("gamepad".to_string()));
+
+        // Saving a single profile only writes that profile's file.
+        SavePreferences::Profile("player1".to_string()).apply(&mut world);
+        let player1 = std::fs::read_to_string(dir.join("prefs.player1.toml")).unwrap();
+        assert_eq!(player1, "[controls]\nscheme = \"keyboard\"\n");
+        assert!(!dir.join("prefs.player2.toml").exists());
+        assert!(!dir.join("prefs.toml").exists());
+
+        // A full save writes every profile, and keeps them out of the main file.
+        SavePreferences::Always.apply(&mut world);
+        let main = std::fs::read_to_string(dir.join("prefs.toml")).unwrap();
+        assert_eq!(main, "[audio]\nvolume = 0.5\n");
+        let player2 = std::fs::read_to_string(dir.join("prefs.player2.toml")).unwrap();
+        assert_eq!(player2, "[controls]\nscheme = \"gamepad\"\n");
+
+        world.insert_resource(Player1Controls(String::new()));
+        world.insert_resource(Player2Controls(String::new()));
+        load::load_preferences(&mut world);
+        assert_eq!(world.resource::<Player1Controls>().0, "keyboard");
+        assert_eq!(world.resource::<Player2Controls>().0, "gamepad");
     }
 }
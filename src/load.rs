@@ -1,108 +1,517 @@
-use std::fs;
-use thiserror::Error;
+use std::{
+    any::TypeId,
+    fs,
+    path::{Path, PathBuf},
+};
 
 use bevy::{
     prelude::*,
     reflect::{
         DynamicEnum, DynamicTuple, DynamicVariant, Enum, EnumInfo, ReflectFromPtr, ReflectMut,
-        TypeInfo, VariantInfo,
+        ReflectRef, TypeInfo, TypeRegistration, TypeRegistry, VariantInfo,
     },
+    utils::HashSet,
 };
 
-use crate::{PreferencesDir, PreferencesGroup, PreferencesKey};
+use crate::{
+    backend::read_from_backends,
+    convert::{
+        decode_enum, decode_opaque, decode_option, find_variant, is_option, is_skipped, load_array,
+        load_list, load_tuple, unit_variant_at, DecodeContext,
+    },
+    encryption::decrypt_groups,
+    error::{report_error, PreferencesError},
+    integrity::{self, PreferencesIntegrity},
+    keys::{group_path, normalize_key, rename_keys, PreferencesKeyStyle},
+    references::link_references,
+    registration::{
+        in_profile, is_state, is_transparent_wrapper, preference_names, state_preference_names,
+        PreferencesRegistrations,
+    },
+    save::capture_baseline,
+    secrets::{load_secrets, PreferencesSecrets},
+    strict::unknown_keys,
+    validate::{take_snapshot, validate},
+    FormatError, PreferencesDir, PreferencesFileFormat, PreferencesFormat, PreferencesFragmentsDir,
+    PreferencesGroup, PreferencesKey, PreferencesLoadMode, PreferencesLoadStrict,
+    PreferencesNamespace, PreferencesPersistState, PreferencesVariantMatching, TomlFormat,
+};
 use bevy::ecs::world::World;
 
+type TableMigrationFn = dyn Fn(toml::Table) -> toml::Table + Send + Sync;
+
+/// Resource holding migrations for preferences documents written by older versions of the app.
+/// A table-level migration receives the whole document after it is read, and returns the
+/// document whose values are applied, so that groups and keys can be moved around freely after
+/// the group layout changes. Migrations run in the order they were added.
+#[derive(Resource, Default)]
+pub struct PreferencesMigrations {
+    table: Vec<Box<TableMigrationFn>>,
+}
+
+impl PreferencesMigrations {
+    /// Add a migration which transforms the whole document.
+    pub fn with_table_migration(
+        mut self,
+        migration: impl Fn(toml::Table) -> toml::Table + Send + Sync + 'static,
+    ) -> Self {
+        self.table.push(Box::new(migration));
+        self
+    }
+
+    fn migrate(&self, table: &toml::Table) -> toml::Table {
+        self.table
+            .iter()
+            .fold(table.clone(), |table, migration| migration(table))
+    }
+}
+
 /// Load all resources registered with the `PreferencesGroup` and `PreferencesKey` attributes
-/// from the preferences file.
+/// from the preferences file, and from the file of each registered profile. Profiles which are
+/// loaded lazily are skipped. Once they have all been loaded, the
+/// [`PreferencesPostLoadValidator`](crate::PreferencesPostLoadValidator) is run, if any.
 pub fn load_preferences(world: &mut World) {
-    let prefs_dir = world.get_resource::<PreferencesDir>().unwrap();
-    let prefs_file = prefs_dir.0.join("prefs.toml");
+    let snapshot = take_snapshot(world);
+    load_profile(world, None);
+    let profiles = world
+        .get_resource::<PreferencesRegistrations>()
+        .map(|registrations| {
+            registrations
+                .profiles()
+                .into_iter()
+                .filter(|profile| !registrations.is_lazy(profile))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    for profile in profiles {
+        load_profile(world, Some(profile));
+    }
+    validate(world, snapshot);
+}
 
-    let table = if prefs_file.exists() && prefs_file.is_file() {
-        let prefs_str = match fs::read_to_string(&prefs_file) {
-            Ok(prefs_str) => prefs_str,
-            Err(e) => {
-                error!("Error reading preferences file: {}", e);
-                return;
-            }
-        };
-        let table_value = match toml::from_str::<toml::Value>(&prefs_str) {
-            Ok(table_value) => table_value,
+/// Resource recording which lazily loaded profiles have already been read.
+#[derive(Resource, Default)]
+pub(crate) struct LazyPreferencesLoaded(HashSet<&'static str>);
+
+/// Returns true if the profile is loaded lazily and hasn't been read yet, in which case its
+/// file must not be overwritten with the current (default) values.
+pub(crate) fn is_lazy_unloaded(world: &World, profile: &str) -> bool {
+    world
+        .get_resource::<PreferencesRegistrations>()
+        .is_some_and(|registrations| registrations.is_lazy(profile))
+        && !world
+            .get_resource::<LazyPreferencesLoaded>()
+            .is_some_and(|loaded| loaded.0.contains(profile))
+}
+
+/// Load the preference resource `T` from its file, if it was registered with
+/// [`PreferenceRegistration::with_lazy_group`](crate::PreferenceRegistration::with_lazy_group)
+/// and hasn't been loaded yet. This is an exclusive system, so it can be run before the systems
+/// which first use the resource, or called directly. The
+/// [`PreferencesPostLoadValidator`](crate::PreferencesPostLoadValidator) is run afterwards, if
+/// any.
+pub fn load_lazy_preference<T: Resource>(world: &mut World) {
+    let Some(profile) = world
+        .get_resource::<PreferencesRegistrations>()
+        .and_then(|registrations| registrations.get(TypeId::of::<T>()))
+        .filter(|registration| registration.lazy)
+        .and_then(|registration| registration.profile)
+    else {
+        return;
+    };
+    let mut loaded = world.get_resource_or_insert_with(LazyPreferencesLoaded::default);
+    if loaded.0.insert(profile) {
+        let snapshot = take_snapshot(world);
+        load_profile(world, Some(profile));
+        validate(world, snapshot);
+    }
+}
+
+/// Load the main preferences file or the file of the given profile, falling back to the
+/// backends if the file is missing.
+fn load_profile(world: &mut World, profile: Option<&str>) {
+    if let Some(table) = read_profile(world, profile) {
+        apply_preferences(world, &table, profile);
+    }
+    capture_baseline(world, profile);
+}
+
+/// Load only the preferences stored in the given groups, or in groups nested within them, from
+/// the main preferences file. The other preferences are left as they are.
+pub(crate) fn load_preference_groups(world: &mut World, groups: &[String]) {
+    let snapshot = take_snapshot(world);
+    if let Some(table) = read_profile(world, None) {
+        apply_preferences_in_groups(world, &table, None, Some(groups));
+    }
+    validate(world, snapshot);
+}
+
+/// Read the document of the main preferences file, merged with its fragments, or of the given
+/// profile, falling back to the backends if the file is missing.
+fn read_profile(world: &mut World, profile: Option<&str>) -> Option<toml::Table> {
+    let table = match read_preferences_file(world, profile) {
+        Ok(Some(table)) => Some(table),
+        Ok(None) => read_from_backends(world, profile),
+        Err(e) => {
+            report_error(world, e);
+            None
+        }
+    };
+    match profile {
+        Some(_) => table,
+        None => merge_fragments(world, table),
+    }
+}
+
+/// Merge the fragments in the [`PreferencesFragmentsDir`] over the main document, in
+/// alphabetical order of their file names.
+fn merge_fragments(world: &World, table: Option<toml::Table>) -> Option<toml::Table> {
+    let Some(fragments_dir) = world
+        .get_resource::<PreferencesFragmentsDir>()
+        .and_then(|fragments| fragments.0.as_ref())
+    else {
+        return table;
+    };
+    let fragments_dir = world
+        .get_resource::<PreferencesDir>()
+        .unwrap()
+        .0
+        .join(fragments_dir);
+    let Ok(entries) = fs::read_dir(&fragments_dir) else {
+        return table;
+    };
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "toml"))
+        .collect();
+    paths.sort();
+    let mut table = table;
+    for path in paths {
+        let fragment = match fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| {
+                TomlFormat::default()
+                    .deserialize(&bytes)
+                    .map_err(|e| e.to_string())
+            }) {
+            Ok(fragment) => fragment,
             Err(e) => {
-                error!("Error parsing preferences file: {}", e);
-                return;
+                warn!("Error reading preferences fragment {:?}: {}", path, e);
+                continue;
             }
         };
+        merge_override(table.get_or_insert_with(toml::Table::new), fragment);
+    }
+    table
+}
 
-        match table_value {
-            toml::Value::Table(table) => table,
-            _ => {
-                error!("Preferences file must be a table");
-                return;
+/// Merge `from` into `into`, replacing values which are present in both except for tables,
+/// which are merged recursively.
+fn merge_override(into: &mut toml::Table, from: toml::Table) {
+    for (key, value) in from {
+        match (into.get_mut(&key), value) {
+            (Some(toml::Value::Table(into)), toml::Value::Table(from)) => {
+                merge_override(into, from)
+            }
+            (_, value) => {
+                into.insert(key, value);
             }
         }
-    } else {
-        return;
+    }
+}
+
+/// Load preferences from an in-memory document encoded in the current [`PreferencesFileFormat`]
+/// rather than from the preferences file, for example defaults embedded with `include_bytes!`.
+/// No filesystem access is performed. The
+/// [`PreferencesPostLoadValidator`](crate::PreferencesPostLoadValidator) is run afterwards, if
+/// any.
+pub fn load_preferences_from_bytes(world: &mut World, bytes: &[u8]) -> Result<(), FormatError> {
+    let format = world
+        .get_resource::<PreferencesFileFormat>()
+        .cloned()
+        .unwrap_or_default();
+    let mut table = format.0.deserialize(bytes)?;
+    decrypt_groups(world, &mut table);
+    let snapshot = take_snapshot(world);
+    apply_preferences(world, &table, None);
+    validate(world, snapshot);
+    Ok(())
+}
+
+/// Read and parse the main preferences file, or the file for the given profile. Returns
+/// `None` if the file doesn't exist or can't be read, and an error for problems which the user
+/// can fix.
+pub(crate) fn read_preferences_file(
+    world: &World,
+    profile: Option<&str>,
+) -> Result<Option<toml::Table>, PreferencesError> {
+    let prefs_dir = world.get_resource::<PreferencesDir>().unwrap();
+    let format = world
+        .get_resource::<PreferencesFileFormat>()
+        .cloned()
+        .unwrap_or_default();
+    let prefs_file = prefs_dir.0.join(match profile {
+        Some(profile) => format.profile_file_name(profile),
+        None => format.file_name(),
+    });
+
+    if prefs_file.is_dir() {
+        return Err(PreferencesError::PathIsDirectory(prefs_file));
+    }
+    if !prefs_file.is_file() {
+        return Ok(None);
+    }
+    if let Some(table) = parse_file(world, &format, &prefs_file) {
+        return Ok(Some(table));
+    }
+
+    // Recover from the most recent backup which can be read, e.g. `prefs.toml.bak`, then
+    // `prefs.toml.bak.1`.
+    for backup in backup_paths(&prefs_file).take_while(|path| path.is_file()) {
+        if let Some(table) = parse_file(world, &format, &backup) {
+            warn!("Preferences: Loaded from backup {:?}", backup);
+            return Ok(Some(table));
+        }
+    }
+    Ok(None)
+}
+
+/// Read, verify and parse a preferences file, logging the reason if it can't be loaded.
+fn parse_file(
+    world: &World,
+    format: &PreferencesFileFormat,
+    prefs_file: &Path,
+) -> Option<toml::Table> {
+    let prefs_bytes = match fs::read(prefs_file) {
+        Ok(prefs_bytes) => prefs_bytes,
+        Err(e) => {
+            error!("Error reading preferences file {:?}: {}", prefs_file, e);
+            return None;
+        }
     };
+    let integrity = world
+        .get_resource::<PreferencesIntegrity>()
+        .copied()
+        .unwrap_or_default();
+    if integrity == PreferencesIntegrity::Crc32
+        && !integrity::verify_checksum(prefs_file, &prefs_bytes)
+    {
+        warn!(
+            "Preferences file does not match its checksum: {:?}",
+            prefs_file
+        );
+        return None;
+    }
+    match format.0.deserialize(&prefs_bytes) {
+        Ok(mut table) => {
+            decrypt_groups(world, &mut table);
+            Some(table)
+        }
+        Err(e) => {
+            error!("Error parsing preferences file {:?}: {}", prefs_file, e);
+            None
+        }
+    }
+}
+
+/// The paths of the backups of a preferences file, newest first: `prefs.toml.bak`,
+/// `prefs.toml.bak.1`, `prefs.toml.bak.2` and so on.
+pub(crate) fn backup_paths(prefs_file: &Path) -> impl Iterator<Item = PathBuf> + '_ {
+    (0..).map(move |index| {
+        let mut path = prefs_file.as_os_str().to_owned();
+        match index {
+            0 => path.push(".bak"),
+            _ => path.push(format!(".bak.{}", index)),
+        }
+        PathBuf::from(path)
+    })
+}
+
+/// Apply the values in a parsed preferences document to all resources registered with the
+/// `PreferencesGroup` and `PreferencesKey` attributes which belong to the given profile.
+pub(crate) fn apply_preferences(world: &mut World, table: &toml::Table, profile: Option<&str>) {
+    apply_preferences_in_groups(world, table, profile, None);
+}
 
+/// Apply the values in a parsed preferences document, as [`apply_preferences`] does, but if
+/// `groups` is given, only to the resources stored in those groups.
+fn apply_preferences_in_groups(
+    world: &mut World,
+    table: &toml::Table,
+    profile: Option<&str>,
+    groups: Option<&[String]>,
+) {
+    let migrated;
+    let table = match world.get_resource::<PreferencesMigrations>() {
+        Some(migrations) => {
+            migrated = migrations.migrate(table);
+            &migrated
+        }
+        None => table,
+    };
+    let load_mode = world
+        .get_resource::<PreferencesLoadMode>()
+        .copied()
+        .unwrap_or_default();
+    let secrets = world.get_resource::<PreferencesSecrets>().cloned();
+    let matching = world
+        .get_resource::<PreferencesVariantMatching>()
+        .copied()
+        .unwrap_or_default();
+    let style = world
+        .get_resource::<PreferencesKeyStyle>()
+        .copied()
+        .unwrap_or_default();
+    let namespace = world
+        .get_resource::<PreferencesNamespace>()
+        .map(|namespace| namespace.0.clone());
+    let empty = toml::Table::new();
+    let table = match &namespace {
+        Some(namespace) => match table.get(namespace) {
+            Some(toml::Value::Table(table)) => table,
+            _ => &empty,
+        },
+        None => table,
+    };
+    let renamed;
+    let table = if style == PreferencesKeyStyle::AsIs {
+        table
+    } else {
+        renamed = rename_keys(table, &|key| style.invert(key).into_owned());
+        &renamed
+    };
     let registry = world.get_resource::<AppTypeRegistry>().unwrap().clone();
+    let registrations = world.get_resource::<PreferencesRegistrations>().cloned();
+    insert_default_resources(world, &registry, registrations.as_ref(), profile);
+    let registry = registry.read();
+    let cx = DecodeContext {
+        registry: &registry,
+        matching,
+    };
+    let strict = world
+        .get_resource::<PreferencesLoadStrict>()
+        .is_some_and(|strict| strict.0);
+    let persist_state = PreferencesPersistState::enabled(world);
+    // A scoped load doesn't report the unknown keys again.
+    let unknown = match groups {
+        Some(_) => Vec::new(),
+        None => unknown_keys(world, table, profile),
+    };
+    for key in unknown {
+        if strict {
+            report_error(world, PreferencesError::UnknownKey(key));
+        } else {
+            debug!("Preferences: Unknown key in preferences file: {}", key);
+        }
+    }
     let resources = world
         .iter_resources()
         .map(|(res, _)| (res.type_id(), res.id()))
         .collect::<Vec<_>>();
     for (res_type_id, res_id) in resources {
         if let Some(tid) = res_type_id {
-            if let Some(treg) = registry.read().get(tid) {
+            if !in_profile(registrations.as_ref(), tid, profile) {
+                continue;
+            }
+            if let Some(treg) = registry.get(tid) {
+                if groups.is_some_and(|groups| {
+                    !in_groups(cx.registry, registrations.as_ref(), treg, groups)
+                }) {
+                    continue;
+                }
                 let type_name = treg.type_info().type_path();
+                let (group_attr, key_attr) =
+                    preference_names(registrations.as_ref(), treg.type_info());
                 match treg.type_info() {
-                    TypeInfo::Struct(stty) => {
-                        let group_attr = stty.custom_attributes().get::<PreferencesGroup>();
-                        let key_attr = stty.custom_attributes().get::<PreferencesKey>();
-                        if group_attr.is_some() || key_attr.is_some() {
-                            let mut ptr = world.get_resource_mut_by_id(res_id).unwrap();
-                            let reflect_from_ptr = treg.data::<ReflectFromPtr>().unwrap();
-                            let ReflectMut::Struct(strct) =
-                                unsafe { reflect_from_ptr.as_reflect_mut(ptr.as_mut()) }
-                                    .reflect_mut()
-                            else {
-                                panic!("Expected Struct");
-                            };
-                            maybe_load_struct(&registry, strct, group_attr, key_attr, &table);
+                    TypeInfo::Struct(stty) if group_attr.is_some() || key_attr.is_some() => {
+                        let Some(mut ptr) = world.get_resource_mut_by_id(res_id) else {
+                            debug!("Preferences: Resource {} has no value, skipping", type_name);
+                            continue;
+                        };
+                        let reflect_from_ptr = treg.data::<ReflectFromPtr>().unwrap();
+                        let reflect = unsafe { reflect_from_ptr.as_reflect_mut(ptr.as_mut()) };
+                        if load_mode == PreferencesLoadMode::ResetOnMissing {
+                            reset_to_default(treg, reflect);
+                        }
+                        let defaults = treg.data::<ReflectDefault>().map(ReflectDefault::default);
+                        let ReflectMut::Struct(strct) = reflect.reflect_mut() else {
+                            panic!("Expected Struct");
+                        };
+                        let defaults =
+                            defaults
+                                .as_deref()
+                                .and_then(|defaults| match defaults.reflect_ref() {
+                                    ReflectRef::Struct(defaults) => Some(defaults),
+                                    _ => None,
+                                });
+                        maybe_load_struct(cx, strct, defaults, group_attr, key_attr, table);
+                        if let Some(secrets) = &secrets {
+                            load_secrets(
+                                secrets.0.as_ref(),
+                                namespace.as_deref(),
+                                stty,
+                                group_attr,
+                                reflect,
+                            );
                         }
                     }
 
                     TypeInfo::TupleStruct(tsty) => {
-                        let group_attr = tsty.custom_attributes().get::<PreferencesGroup>();
-                        let key_attr = tsty.custom_attributes().get::<PreferencesKey>();
-                        let mut ptr = world.get_resource_mut_by_id(res_id).unwrap();
+                        let transparent = is_transparent_wrapper(tsty) && !is_state(tsty);
+                        let is_preference =
+                            !transparent && (group_attr.is_some() || key_attr.is_some());
+                        let Some(mut ptr) = world.get_resource_mut_by_id(res_id) else {
+                            debug!("Preferences: Resource {} has no value, skipping", type_name);
+                            continue;
+                        };
                         let reflect_from_ptr = treg.data::<ReflectFromPtr>().unwrap();
-                        let ReflectMut::TupleStruct(tuple_struct) =
-                            unsafe { reflect_from_ptr.as_reflect_mut(ptr.as_mut()) }.reflect_mut()
-                        else {
+                        let reflect = unsafe { reflect_from_ptr.as_reflect_mut(ptr.as_mut()) };
+                        if is_preference && load_mode == PreferencesLoadMode::ResetOnMissing {
+                            reset_to_default(treg, reflect);
+                        }
+                        let defaults = treg.data::<ReflectDefault>().map(ReflectDefault::default);
+                        let ReflectMut::TupleStruct(tuple_struct) = reflect.reflect_mut() else {
                             panic!("Expected TupleStruct");
                         };
-                        if group_attr.is_some() || key_attr.is_some() {
+                        if is_preference {
+                            let defaults = defaults.as_deref().and_then(|defaults| match defaults
+                                .reflect_ref()
+                            {
+                                ReflectRef::TupleStruct(defaults) => Some(defaults),
+                                _ => None,
+                            });
                             maybe_load_tuple_struct(
-                                &registry,
+                                cx,
                                 tuple_struct,
+                                defaults,
                                 group_attr,
                                 key_attr,
-                                &table,
+                                table,
                             );
-                        } else if tsty
-                            .type_path()
-                            .starts_with("bevy_state::state::resources::NextState<")
+                        } else if transparent
+                            || persist_state
+                                && tsty
+                                    .type_path()
+                                    .starts_with("bevy_state::state::resources::NextState<")
                         {
                             let state_reflect = tuple_struct.field_mut(0).unwrap();
                             let state_info = state_reflect.get_represented_type_info().unwrap();
+                            let (group_attr, key_attr) =
+                                state_preference_names(registrations.as_ref(), tid, state_info);
                             let field_reflect_mut = state_reflect.reflect_mut();
                             match (state_info, field_reflect_mut) {
-                                (TypeInfo::Struct(_), ReflectMut::Struct(_)) => todo!(),
-                                (TypeInfo::TupleStruct(_), ReflectMut::TupleStruct(_)) => {
-                                    todo!()
+                                (TypeInfo::Struct(_), ReflectMut::Struct(strct)) => {
+                                    maybe_load_struct(cx, strct, None, group_attr, key_attr, table);
+                                }
+                                (TypeInfo::TupleStruct(_), ReflectMut::TupleStruct(inner)) => {
+                                    maybe_load_tuple_struct(
+                                        cx, inner, None, group_attr, key_attr, table,
+                                    );
                                 }
                                 (TypeInfo::Enum(enum_ty), ReflectMut::Enum(enum_mut)) => {
-                                    maybe_load_enum(enum_ty, enum_mut, &table);
+                                    maybe_load_enum(
+                                        enum_ty, enum_mut, group_attr, key_attr, table, matching,
+                                    );
                                 }
                                 _ => {}
                             }
@@ -110,14 +519,12 @@ pub fn load_preferences(world: &mut World) {
                     }
 
                     TypeInfo::Enum(ety) => {
-                        if let Some(group) = ety.custom_attributes().get::<PreferencesGroup>() {
-                            let _group = table.get(group.0).unwrap().as_table().unwrap();
-                            warn!("Preferences: Enums not supported yet: {}", type_name);
-                        } else if let Some(_key) = ety.custom_attributes().get::<PreferencesKey>() {
+                        if group_attr.is_some() || key_attr.is_some() {
                             warn!("Preferences: Enums not supported yet: {}", type_name);
-                        } else if ety
-                            .type_path()
-                            .starts_with("bevy_state::state::resources::NextState<")
+                        } else if persist_state
+                            && ety
+                                .type_path()
+                                .starts_with("bevy_state::state::resources::NextState<")
                         {
                             let Some(VariantInfo::Tuple(pending_ty)) = ety.variant("Pending")
                             else {
@@ -125,7 +532,7 @@ pub fn load_preferences(world: &mut World) {
                             };
                             let state_ty = pending_ty.field_at(0).unwrap();
                             let state_type_id = state_ty.type_id();
-                            let rr = registry.read();
+                            let rr = cx.registry;
                             let Some(state_type_reg) = rr.get(state_type_id) else {
                                 warn!(
                                     "Expected state type registration for {}",
@@ -134,22 +541,19 @@ pub fn load_preferences(world: &mut World) {
                                 continue;
                             };
                             let state_info = state_type_reg.type_info();
-                            let group_attr = match state_info {
-                                TypeInfo::Struct(_) => todo!(),
-                                TypeInfo::TupleStruct(_) => todo!(),
-                                TypeInfo::Enum(enum_ty) => {
-                                    enum_ty.custom_attributes().get::<PreferencesGroup>()
-                                }
-                                _ => None,
-                            };
-                            let key_attr = match state_info {
-                                TypeInfo::Struct(_) => todo!(),
-                                TypeInfo::TupleStruct(_) => todo!(),
-                                TypeInfo::Enum(enum_ty) => {
-                                    enum_ty.custom_attributes().get::<PreferencesKey>()
-                                }
-                                _ => None,
-                            };
+                            // Names registered for `State<S>` apply to `NextState<S>` too.
+                            let state_resource = rr
+                                .get_with_type_path(&ety.type_path().replacen(
+                                    "NextState<",
+                                    "State<",
+                                    1,
+                                ))
+                                .map_or(state_type_id, TypeRegistration::type_id);
+                            let (group_attr, key_attr) = state_preference_names(
+                                registrations.as_ref(),
+                                state_resource,
+                                state_info,
+                            );
                             if group_attr.is_none() && key_attr.is_none() {
                                 continue;
                             }
@@ -164,13 +568,21 @@ pub fn load_preferences(world: &mut World) {
                                 (TypeInfo::Struct(_), ReflectMut::Struct(_)) => false,
                                 (TypeInfo::TupleStruct(_), ReflectMut::TupleStruct(_)) => false,
                                 (TypeInfo::Enum(enum_ty), ReflectMut::Enum(enum_mut)) => {
-                                    maybe_load_enum(enum_ty, enum_mut, &table)
+                                    maybe_load_enum(
+                                        enum_ty, enum_mut, group_attr, key_attr, table, matching,
+                                    )
                                 }
                                 _ => false,
                             };
 
                             if change {
-                                let mut ptr = world.get_resource_mut_by_id(res_id).unwrap();
+                                let Some(mut ptr) = world.get_resource_mut_by_id(res_id) else {
+                                    debug!(
+                                        "Preferences: Resource {} has no value, skipping",
+                                        type_name
+                                    );
+                                    continue;
+                                };
                                 let reflect_from_ptr = treg.data::<ReflectFromPtr>().unwrap();
                                 let ReflectMut::Enum(enum_mut) =
                                     unsafe { reflect_from_ptr.as_reflect_mut(ptr.as_mut()) }
@@ -192,23 +604,120 @@ pub fn load_preferences(world: &mut World) {
                     _ => {}
                 }
             }
-            // println!("Saving preferences for {:?}", res.name());
         }
     }
+    link_references(world, table, registrations.as_ref());
 }
 
-fn maybe_load_struct(
+/// Insert the default value of each preference resource in the given profile which isn't
+/// present in the world yet, so that the file contents can be applied to it. Only types which
+/// register both `ReflectResource` and `ReflectDefault`, e.g. `#[reflect(Resource, Default)]`,
+/// can be inserted.
+pub(crate) fn insert_default_resources(
+    world: &mut World,
     registry: &AppTypeRegistry,
+    registrations: Option<&PreferencesRegistrations>,
+    profile: Option<&str>,
+) {
+    let registry = registry.read();
+    for treg in registry.iter() {
+        let (group_attr, key_attr) = preference_names(registrations, treg.type_info());
+        if (group_attr.is_none() && key_attr.is_none())
+            || !in_profile(registrations, treg.type_id(), profile)
+        {
+            continue;
+        }
+        let (Some(reflect_resource), Some(reflect_default)) = (
+            treg.data::<ReflectResource>(),
+            treg.data::<ReflectDefault>(),
+        ) else {
+            continue;
+        };
+        if reflect_resource.reflect(world).is_none() {
+            let value = reflect_default.default();
+            reflect_resource.insert(world, value.as_partial_reflect(), &registry);
+        }
+    }
+}
+
+/// Reset a preference resource to its `Default` value, so that keys which are missing from the
+/// preferences file don't retain their current runtime values.
+fn reset_to_default(treg: &TypeRegistration, value: &mut dyn Reflect) {
+    if let Some(reflect_default) = treg.data::<ReflectDefault>() {
+        // Skipped fields keep their current values.
+        let mut default = reflect_default.default();
+        if let (ReflectRef::Struct(current), ReflectMut::Struct(default)) =
+            (value.reflect_ref(), default.reflect_mut())
+        {
+            for i in 0..current.field_len() {
+                if is_skipped(current, i) {
+                    default
+                        .field_at_mut(i)
+                        .unwrap()
+                        .apply(current.field_at(i).unwrap());
+                }
+            }
+        }
+        value.apply(default.as_partial_reflect());
+    } else {
+        warn!(
+            "Preferences: Cannot reset {} without ReflectDefault",
+            treg.type_info().type_path()
+        );
+    }
+}
+
+/// Find the table for a group, descending through the enclosing tables of a nested group.
+/// Returns true if the resource is stored in one of the groups, or in a group nested within
+/// one of them. For a state, or another transparent wrapper, this is the group of its value.
+fn in_groups(
+    registry: &TypeRegistry,
+    registrations: Option<&PreferencesRegistrations>,
+    treg: &TypeRegistration,
+    groups: &[String],
+) -> bool {
+    let group = match treg.type_info() {
+        TypeInfo::TupleStruct(tsty)
+            if is_transparent_wrapper(tsty)
+                || tsty
+                    .type_path()
+                    .starts_with("bevy_state::state::resources::NextState<") =>
+        {
+            let Some(inner_info) = tsty
+                .field_at(0)
+                .and_then(|field| registry.get_type_info(field.type_id()))
+            else {
+                return false;
+            };
+            state_preference_names(registrations, treg.type_id(), inner_info).0
+        }
+        info => preference_names(registrations, info).0,
+    };
+    group.is_some_and(|group| {
+        groups.iter().any(|name| {
+            group.0 == name
+                || group
+                    .0
+                    .strip_prefix(name.as_str())
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+    })
+}
+
+fn group_table<'a>(table: &'a toml::Table, group: &PreferencesGroup) -> Option<&'a toml::Table> {
+    group_path(group.0).try_fold(table, |table, key| table.get(key.as_ref())?.as_table())
+}
+
+fn maybe_load_struct(
+    cx: DecodeContext,
     strct: &mut dyn Struct,
+    defaults: Option<&dyn Struct>,
     group_attr: Option<&PreferencesGroup>,
     key_attr: Option<&PreferencesKey>,
     table: &toml::Table,
 ) {
     if let Some(group) = group_attr {
-        let Some(group_value) = table.get(group.0) else {
-            return;
-        };
-        let Some(group) = group_value.as_table() else {
+        let Some(group) = group_table(table, group) else {
             return;
         };
 
@@ -216,140 +725,287 @@ fn maybe_load_struct(
             todo!();
         } else {
             // TODO: Need to derive key name from tuple struct name
-            load_struct(registry, strct, group);
-            // todo!();
+            load_struct(cx, strct, defaults, group);
         }
     } else if let Some(_key) = key_attr {
         todo!();
-        // load_struct(registry, strct, key.0, table);
     }
 }
 
-fn load_struct(registry: &AppTypeRegistry, strct: &mut dyn Struct, table: &toml::Table) {
+/// Load the fields of a struct from a table. `defaults` is the same struct in the `Default` value
+/// of its resource, if there is one, which supplies the value of a field which can't be parsed.
+fn load_struct(
+    cx: DecodeContext,
+    strct: &mut dyn Struct,
+    defaults: Option<&dyn Struct>,
+    table: &toml::Table,
+) {
     for i in 0..strct.field_len() {
-        let key = strct.name_at(i).unwrap().to_owned();
-        let field_mut = strct.field_at_mut(i).unwrap();
-        match field_mut.get_represented_type_info().unwrap() {
-            TypeInfo::Struct(_) => todo!(),
-            TypeInfo::TupleStruct(_) => todo!(),
-            TypeInfo::Tuple(_) => todo!(),
-            TypeInfo::List(_) => todo!(),
-            TypeInfo::Array(_) => todo!(),
-            TypeInfo::Map(_) => todo!(),
-            TypeInfo::Set(_) => todo!(),
+        if is_skipped(strct, i) {
+            continue;
+        }
+        let key = normalize_key(strct.name_at(i).unwrap()).into_owned();
+        load_field(
+            cx,
+            strct.field_at_mut(i).unwrap(),
+            defaults.and_then(|defaults| defaults.field_at(i)),
+            table.get(&key),
+        );
+    }
+}
 
-            TypeInfo::Enum(en) => {
-                if en.type_path().starts_with("core::option::Option") {
-                    if table.contains_key(&key) {
-                        let VariantInfo::Tuple(variant_info) = en.variant("Some").unwrap() else {
-                            panic!("Expected Tuple variant for Some");
-                        };
-                        let some_field = variant_info.field_at(0).unwrap();
-                        let rr = registry.read();
-                        let field_type = rr.get(some_field.type_id()).unwrap();
-                        let mut tuple = DynamicTuple::default();
-                        tuple.set_represented_type(Some(field_type.type_info()));
-                        drop(rr);
-                        decode_value(tuple.as_partial_reflect_mut(), table.get(&key).unwrap());
-                        let value = DynamicEnum::new("Some", DynamicVariant::Tuple(tuple));
-                        field_mut.apply(value.as_partial_reflect());
-                    } else {
-                        // Key not found, set to None
-                        field_mut.apply(
-                            DynamicEnum::new("None", DynamicVariant::Unit).as_partial_reflect(),
-                        );
-                    };
-                } else {
-                    warn!("Preferences: Unsupported enum type: {:?}", en);
+/// Load the value stored for a field, where `value` is `None` if its key is missing, and
+/// `default` is the value of the field in the `Default` of its resource.
+fn load_field(
+    cx: DecodeContext,
+    field_mut: &mut dyn PartialReflect,
+    default: Option<&dyn PartialReflect>,
+    value: Option<&toml::Value>,
+) {
+    let default_struct = default.and_then(|default| match default.reflect_ref() {
+        ReflectRef::Struct(default) => Some(default),
+        _ => None,
+    });
+    match field_mut.get_represented_type_info().unwrap() {
+        TypeInfo::Struct(_) => match (value, field_mut.reflect_mut()) {
+            // Fields missing from the nested table keep their current values.
+            (Some(toml::Value::Table(field_table)), ReflectMut::Struct(nested)) => {
+                load_struct(cx, nested, default_struct, field_table);
+            }
+            // A struct stored as an array, such as one listed in the `PreferencesArrayTypes`, is
+            // loaded by position.
+            (Some(toml::Value::Array(items)), ReflectMut::Struct(nested)) => {
+                if items.len() != nested.field_len() {
+                    warn!(
+                        "Preferences: Expected {} elements for {}, found {}",
+                        nested.field_len(),
+                        nested.reflect_type_path(),
+                        items.len()
+                    );
+                    return;
+                }
+                for (i, item) in items.iter().enumerate() {
+                    load_field(
+                        cx,
+                        nested.field_at_mut(i).unwrap(),
+                        default_struct.and_then(|default| default.field_at(i)),
+                        Some(item),
+                    );
+                }
+            }
+            _ => {}
+        },
+        TypeInfo::TupleStruct(_) => todo!(),
+        TypeInfo::Tuple(_) => {
+            if let Some(value) = value {
+                load_tuple(field_mut, value);
+            }
+        }
+        TypeInfo::List(_) => {
+            if let Some(value) = value {
+                load_list(cx, field_mut, value);
+            }
+        }
+        TypeInfo::Array(_) => {
+            if let Some(value) = value {
+                load_array(field_mut, value);
+            }
+        }
+        TypeInfo::Map(_) => {
+            if let Some(value) = value {
+                load_map(cx, field_mut, value);
+            }
+        }
+        TypeInfo::Set(_) => todo!(),
+
+        TypeInfo::Enum(_) => {
+            let ty = field_mut.get_represented_type_info().unwrap();
+            if is_option(ty) {
+                // A missing key sets the field to `None`.
+                if let Some(value) = decode_option(cx, ty, value) {
+                    field_mut.apply(value.as_partial_reflect());
+                }
+            } else if let Some(value) = value {
+                if let Some(value) = decode_enum(cx, ty, value) {
+                    field_mut.apply(value.as_partial_reflect());
                 }
             }
+        }
 
-            TypeInfo::Opaque(_) => {
-                if let Some(value) = table.get(&key) {
-                    if let Ok(value) =
-                        decode_value_boxed(field_mut.get_represented_type_info().unwrap(), value)
-                    {
-                        field_mut.apply(value.as_partial_reflect())
-                    }
+        TypeInfo::Opaque(_) => {
+            if let Some(value) = value {
+                let ty = field_mut.get_represented_type_info().unwrap();
+                match decode_opaque(cx.registry, ty, value) {
+                    Some(value) => field_mut.apply(value.as_partial_reflect()),
+                    None => default_on_parse_failure(cx.registry, field_mut, default),
                 }
             }
         }
     }
 }
 
+/// Reset a field whose value in the file couldn't be decoded to its default value, rather than
+/// keeping a value which may be stale. This is `default`, the value of the field in the
+/// `Default` of its resource, if given; otherwise the `Default` of the field's own type. A field
+/// with neither keeps its current value.
+fn default_on_parse_failure(
+    registry: &TypeRegistry,
+    field: &mut dyn PartialReflect,
+    default: Option<&dyn PartialReflect>,
+) {
+    let type_path = field.reflect_type_path();
+    if let Some(default) = default {
+        warn!("Preferences: Using the default value for {}", type_path);
+        field.apply(default);
+        return;
+    }
+    match field
+        .get_represented_type_info()
+        .and_then(|ty| registry.get_type_data::<ReflectDefault>(ty.type_id()))
+    {
+        Some(default) => {
+            warn!("Preferences: Using the default value for {}", type_path);
+            field.apply(default.default().as_partial_reflect());
+        }
+        None => warn!(
+            "Preferences: Keeping the current value for {}, it has no ReflectDefault",
+            type_path
+        ),
+    }
+}
+
+/// Load a TOML table into a map with string keys, replacing its entries. Each value is loaded
+/// over the current value for its key, or over a default value for a new key, so a struct
+/// value is loaded field by field. Entries whose value type has no `ReflectDefault` can only
+/// replace existing ones.
+fn load_map(cx: DecodeContext, field: &mut dyn PartialReflect, value: &toml::Value) {
+    let Some(TypeInfo::Map(map_ty)) = field.get_represented_type_info() else {
+        return;
+    };
+    let Some(table) = value.as_table() else {
+        warn!("Preferences: Expected a table for {}", map_ty.type_path());
+        return;
+    };
+    if !map_ty.key_ty().is::<String>() {
+        warn!(
+            "Preferences: Unsupported key type {} for {}",
+            map_ty.key_ty().path(),
+            map_ty.type_path()
+        );
+        return;
+    }
+    let default = cx
+        .registry
+        .get_type_data::<ReflectDefault>(map_ty.value_ty().id())
+        .cloned();
+    let ReflectMut::Map(map) = field.reflect_mut() else {
+        return;
+    };
+    let mut entries = Vec::with_capacity(table.len());
+    for (key, item) in table {
+        let mut entry = match (map.get(key), &default) {
+            (Some(current), _) => current.clone_value(),
+            (None, Some(default)) => default.default().into_partial_reflect(),
+            (None, None) => {
+                warn!(
+                    "Preferences: Can't add key {} to {}, its value type has no ReflectDefault",
+                    key,
+                    map_ty.type_path()
+                );
+                continue;
+            }
+        };
+        load_field(cx, entry.as_mut(), None, Some(item));
+        entries.push((key.clone(), entry));
+    }
+    map.drain();
+    for (key, entry) in entries {
+        map.insert_boxed(Box::new(key), entry);
+    }
+}
+
 fn maybe_load_tuple_struct(
-    registry: &AppTypeRegistry,
+    cx: DecodeContext,
     tuple_struct: &mut dyn TupleStruct,
+    defaults: Option<&dyn TupleStruct>,
     group_attr: Option<&PreferencesGroup>,
     key_attr: Option<&PreferencesKey>,
     table: &toml::Table,
 ) {
     if let Some(group) = group_attr {
-        let Some(group_value) = table.get(group.0) else {
-            return;
-        };
-        let Some(group) = group_value.as_table() else {
+        let Some(group) = group_table(table, group) else {
             return;
         };
 
         if let Some(key) = key_attr {
-            load_tuple_struct(registry, tuple_struct, key.0, group);
+            load_tuple_struct(cx, tuple_struct, defaults, key.0, group);
         } else {
             // TODO: Need to derive key name from tuple struct name
             todo!();
         }
     } else if let Some(key) = key_attr {
-        load_tuple_struct(registry, tuple_struct, key.0, table);
+        load_tuple_struct(cx, tuple_struct, defaults, key.0, table);
     }
 }
 
 fn load_tuple_struct(
-    registry: &AppTypeRegistry,
+    cx: DecodeContext,
     tuple_struct: &mut dyn TupleStruct,
-    key: &'static str,
+    defaults: Option<&dyn TupleStruct>,
+    key: &str,
     table: &toml::Table,
 ) {
+    let key = normalize_key(key);
+    let key = key.as_ref();
     if tuple_struct.field_len() == 1 {
         let field_mut = tuple_struct.field_mut(0).unwrap();
         match field_mut.get_represented_type_info().unwrap() {
             TypeInfo::Struct(_) => todo!(),
             TypeInfo::TupleStruct(_) => todo!(),
-            TypeInfo::Tuple(_) => todo!(),
-            TypeInfo::List(_) => todo!(),
-            TypeInfo::Array(_) => todo!(),
-            TypeInfo::Map(_) => todo!(),
+            TypeInfo::Tuple(_) => {
+                if let Some(value) = table.get(key) {
+                    load_tuple(field_mut, value);
+                }
+            }
+            TypeInfo::List(_) => {
+                if let Some(value) = table.get(key) {
+                    load_list(cx, field_mut, value);
+                }
+            }
+            TypeInfo::Array(_) => {
+                if let Some(value) = table.get(key) {
+                    load_array(field_mut, value);
+                }
+            }
+            TypeInfo::Map(_) => {
+                if let Some(value) = table.get(key) {
+                    load_map(cx, field_mut, value);
+                }
+            }
             TypeInfo::Set(_) => todo!(),
-            TypeInfo::Enum(en) => {
-                if en.type_path().starts_with("core::option::Option") {
-                    if table.contains_key(key) {
-                        let VariantInfo::Tuple(variant_info) = en.variant("Some").unwrap() else {
-                            panic!("Expected Tuple variant for Some");
-                        };
-                        let some_field = variant_info.field_at(0).unwrap();
-                        let rr = registry.read();
-                        let field_type = rr.get(some_field.type_id()).unwrap();
-                        let mut tuple = DynamicTuple::default();
-                        tuple.set_represented_type(Some(field_type.type_info()));
-                        drop(rr);
-                        decode_value(tuple.as_partial_reflect_mut(), table.get(key).unwrap());
-                        let value = DynamicEnum::new("Some", DynamicVariant::Tuple(tuple));
+            TypeInfo::Enum(_) => {
+                let ty = field_mut.get_represented_type_info().unwrap();
+                if is_option(ty) {
+                    // A missing key sets the field to `None`.
+                    if let Some(value) = decode_option(cx, ty, table.get(key)) {
                         field_mut.apply(value.as_partial_reflect());
-                    } else {
-                        // Key not found, set to None
-                        field_mut.apply(
-                            DynamicEnum::new("None", DynamicVariant::Unit).as_partial_reflect(),
-                        );
-                    };
-                } else {
-                    warn!("Preferences: Unsupported enum type: {:?}", en);
+                    }
+                } else if let Some(value) = table.get(key) {
+                    if let Some(value) = decode_enum(cx, ty, value) {
+                        field_mut.apply(value.as_partial_reflect());
+                    }
                 }
             }
             TypeInfo::Opaque(_) => {
                 if let Some(value) = table.get(key) {
-                    if let Ok(value) =
-                        decode_value_boxed(field_mut.get_represented_type_info().unwrap(), value)
-                    {
-                        field_mut.apply(value.as_partial_reflect())
+                    let ty = field_mut.get_represented_type_info().unwrap();
+                    match decode_opaque(cx.registry, ty, value) {
+                        Some(value) => field_mut.apply(value.as_partial_reflect()),
+                        None => default_on_parse_failure(
+                            cx.registry,
+                            field_mut,
+                            defaults.and_then(|defaults| defaults.field(0)),
+                        ),
                     }
                 }
             }
@@ -357,36 +1013,44 @@ fn load_tuple_struct(
     }
 }
 
-fn maybe_load_enum(enum_ty: &EnumInfo, enum_mut: &mut dyn Enum, table: &toml::Table) -> bool {
-    let group_attr = enum_ty.custom_attributes().get::<PreferencesGroup>();
-    let key_attr = enum_ty.custom_attributes().get::<PreferencesKey>();
+fn maybe_load_enum(
+    enum_ty: &EnumInfo,
+    enum_mut: &mut dyn Enum,
+    group_attr: Option<&PreferencesGroup>,
+    key_attr: Option<&PreferencesKey>,
+    table: &toml::Table,
+    matching: PreferencesVariantMatching,
+) -> bool {
     if let Some(group) = group_attr {
-        let Some(group_value) = table.get(group.0) else {
-            return false;
-        };
-        let Some(group) = group_value.as_table() else {
+        let Some(group) = group_table(table, group) else {
             return false;
         };
 
         if let Some(key) = key_attr {
-            load_enum(enum_ty, enum_mut, key.0, group);
+            load_enum(enum_ty, enum_mut, key.0, group, matching);
             true
         } else {
             // TODO: Need to derive key name from tuple struct name
             todo!();
         }
     } else if let Some(key) = key_attr {
-        load_enum(enum_ty, enum_mut, key.0, table);
+        load_enum(enum_ty, enum_mut, key.0, table, matching);
         true
     } else {
         false
     }
 }
 
-fn load_enum(enum_ty: &EnumInfo, enum_mut: &mut dyn Enum, key: &'static str, table: &toml::Table) {
-    match table.get(key) {
+fn load_enum(
+    enum_ty: &EnumInfo,
+    enum_mut: &mut dyn Enum,
+    key: &str,
+    table: &toml::Table,
+    matching: PreferencesVariantMatching,
+) {
+    match table.get(normalize_key(key).as_ref()) {
         Some(toml::Value::String(s)) => {
-            let Some(variant) = enum_ty.variant(s) else {
+            let Some(variant) = find_variant(enum_ty, s, matching) else {
                 warn!("Preferences: Unknown variant: {}", s);
                 return;
             };
@@ -395,6 +1059,16 @@ fn load_enum(enum_ty: &EnumInfo, enum_mut: &mut dyn Enum, key: &'static str, tab
                 enum_mut.apply(dynamic_enum.as_partial_reflect());
             }
         }
+        Some(toml::Value::Integer(index)) => {
+            let Some(variant) = unit_variant_at(enum_ty, *index) else {
+                warn!("Preferences: Unknown variant index: {}", index);
+                return;
+            };
+            if variant.name() != enum_mut.variant_name() {
+                let dynamic_enum = DynamicEnum::new(variant.name(), DynamicVariant::Unit);
+                enum_mut.apply(dynamic_enum.as_partial_reflect());
+            }
+        }
         None => {}
         _ => {
             warn!("Preferences: unsupported type: {}", key);
@@ -402,125 +1076,19 @@ fn load_enum(enum_ty: &EnumInfo, enum_mut: &mut dyn Enum, key: &'static str, tab
     };
 }
 
-fn decode_value(field: &mut dyn PartialReflect, value: &toml::Value) {
-    match field.get_represented_type_info().unwrap() {
-        TypeInfo::Struct(_) => todo!("Implement struct deserialization"),
-        TypeInfo::TupleStruct(_) => todo!("Implement tuplestruct deserialization"),
-        TypeInfo::Tuple(_) => todo!("Implement tuple deserialization"),
-        TypeInfo::List(_) => todo!("Implement list deserialization"),
-        TypeInfo::Array(_) => todo!("Implement array deserialization"),
-        TypeInfo::Map(_) => todo!("Implement map deserialization"),
-        TypeInfo::Set(_) => todo!("Implement set deserialization"),
-        TypeInfo::Enum(_) => todo!("Implement enum deserialization"),
-
-        TypeInfo::Opaque(val_ty) => match value {
-            toml::Value::Float(float_val) => {
-                if let Some(float_field) = field.try_downcast_mut::<f32>() {
-                    float_field.apply((*float_val as f32).as_partial_reflect());
-                } else if let Some(float_field) = field.try_downcast_mut::<f64>() {
-                    float_field.apply((*float_val).as_partial_reflect());
-                } else {
-                    warn!("Preferences: Unsupported type: {:?}", val_ty);
-                }
-            }
-
-            toml::Value::String(str_val) => {
-                if let Some(str_field) = field.try_downcast_mut::<String>() {
-                    str_field.apply(str_val.as_partial_reflect());
-                } else {
-                    warn!("Preferences: Unsupported type: {:?}", val_ty);
-                }
-            }
-
-            toml::Value::Boolean(bool_val) => {
-                if let Some(str_field) = field.try_downcast_mut::<bool>() {
-                    str_field.apply(bool_val.as_partial_reflect());
-                } else {
-                    warn!("Preferences: Unsupported type: {:?}", val_ty);
-                }
-            }
-
-            _ => {
-                warn!("Preferences: unsupported type: {}", val_ty.type_path());
-            }
-        },
-    };
-}
-
-#[non_exhaustive]
-#[derive(Debug, Error)]
-pub enum DecodeTomlError {
-    #[error("Unsupported type: {0}")]
-    UnsupportedConversion(&'static str),
-    #[error("Unsupported source type: {0}")]
-    UnsupportedSource(&'static str),
-}
-
-fn decode_value_boxed(
-    ty: &TypeInfo,
-    value: &toml::Value,
-) -> Result<Box<dyn PartialReflect>, DecodeTomlError> {
-    match value {
-        toml::Value::Float(float_val) => {
-            if ty.is::<f32>() {
-                Ok((*float_val as f32).clone_value())
-            } else if ty.is::<f64>() {
-                Ok((*float_val).clone_value())
-            } else {
-                warn!("Preferences: Unsupported conversion: {:?}", ty);
-                Err(DecodeTomlError::UnsupportedConversion(ty.type_path()))
-            }
-        }
-
-        toml::Value::Integer(int_val) => {
-            if ty.is::<f32>() {
-                Ok((*int_val as f32).clone_value())
-            } else if ty.is::<f64>() {
-                Ok((*int_val).clone_value())
-            } else if ty.is::<i8>() {
-                Ok(((*int_val).clamp(i8::MIN as i64, i8::MAX as i64) as i8).clone_value())
-            } else if ty.is::<i16>() {
-                Ok(((*int_val).clamp(i16::MIN as i64, i16::MAX as i64) as i16).clone_value())
-            } else if ty.is::<i32>() {
-                Ok(((*int_val).clamp(i32::MIN as i64, i32::MAX as i64) as i32).clone_value())
-            } else if ty.is::<i64>() {
-                Ok((*int_val).clone_value())
-            } else if ty.is::<u8>() {
-                Ok(((*int_val).clamp(u8::MIN as i64, u8::MAX as i64) as u8).clone_value())
-            } else if ty.is::<u16>() {
-                Ok(((*int_val).clamp(u16::MIN as i64, u16::MAX as i64) as u16).clone_value())
-            } else if ty.is::<u32>() {
-                Ok(((*int_val).clamp(u32::MIN as i64, u32::MAX as i64) as u32).clone_value())
-            } else if ty.is::<u64>() {
-                Ok(((*int_val).max(0) as u64).clone_value())
-            } else if ty.is::<usize>() {
-                Ok((*int_val as usize).clone_value())
-            } else {
-                warn!("Preferences: Unsupported conversion: {:?}", ty);
-                Err(DecodeTomlError::UnsupportedConversion(ty.type_path()))
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
 
-        toml::Value::String(str_val) => {
-            if ty.is::<String>() {
-                Ok(str_val.clone_value())
-            } else {
-                warn!("Preferences: Unsupported conversion: {:?}", ty);
-                Err(DecodeTomlError::UnsupportedConversion(ty.type_path()))
-            }
-        }
+    use super::*;
 
-        _ => {
-            warn!("Preferences: unsupported source type: {}", ty.type_path());
-            Err(DecodeTomlError::UnsupportedSource(ty.type_path()))
-            // Box::new(() as Reflect)
+    /// Decode a value into an opaque field in the same way as loading does.
+    fn decode_value(field: &mut dyn PartialReflect, value: &toml::Value) {
+        let ty = field.get_represented_type_info().unwrap();
+        if let Some(value) = decode_opaque(&TypeRegistry::default(), ty, value) {
+            field.apply(value.as_partial_reflect());
         }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
     #[test]
     fn test_decode_value_float() {
@@ -552,6 +1120,78 @@ mod tests {
         assert!(field);
     }
 
+    #[test]
+    fn test_decode_value_bool_from_string() {
+        let mut field = false;
+        decode_value(&mut field, &toml::Value::String("true".into()));
+        assert!(field);
+
+        decode_value(&mut field, &toml::Value::String("false".into()));
+        assert!(!field);
+
+        // Other strings are rejected, leaving the field unchanged.
+        decode_value(&mut field, &toml::Value::String("yes".into()));
+        assert!(!field);
+        field = true;
+        decode_value(&mut field, &toml::Value::String("no".into()));
+        assert!(field);
+    }
+
+    #[test]
+    fn test_load_legacy_bool_string() {
+        #[derive(Reflect)]
+        struct Flags {
+            flag: bool,
+            other: bool,
+        }
+
+        let table: toml::Table = toml::from_str("flag = \"true\"\nother = true\n").unwrap();
+        let mut flags = Flags {
+            flag: false,
+            other: false,
+        };
+        load_struct(
+            DecodeContext::new(&TypeRegistry::default()),
+            &mut flags,
+            None,
+            &table,
+        );
+        assert!(flags.flag);
+        assert!(flags.other);
+    }
+
+    #[derive(Reflect)]
+    struct Keys {
+        slots: [i32; 3],
+    }
+
+    fn load_keys(source: &str) -> [i32; 3] {
+        let table: toml::Table = toml::from_str(source).unwrap();
+        let mut keys = Keys { slots: [7, 8, 9] };
+        load_struct(
+            DecodeContext::new(&TypeRegistry::default()),
+            &mut keys,
+            None,
+            &table,
+        );
+        keys.slots
+    }
+
+    #[test]
+    fn test_load_array_exact_length() {
+        assert_eq!(load_keys("slots = [1, 2, 3]"), [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_load_array_too_few() {
+        assert_eq!(load_keys("slots = [1, 2]"), [7, 8, 9]);
+    }
+
+    #[test]
+    fn test_load_array_too_many() {
+        assert_eq!(load_keys("slots = [1, 2, 3, 4]"), [7, 8, 9]);
+    }
+
     #[test]
     fn test_decode_value_unsupported_type() {
         let mut field = 0.0f32;
@@ -561,4 +1201,1062 @@ mod tests {
 
         assert_eq!(field, 0.0);
     }
+
+    #[derive(Resource, Reflect, Default)]
+    #[reflect(Default, @PreferencesGroup("audio"))]
+    struct AudioSettings {
+        volume: f32,
+        device: String,
+    }
+
+    fn audio_world(mode: PreferencesLoadMode) -> World {
+        let mut world = World::new();
+        world.init_resource::<AppTypeRegistry>();
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<AudioSettings>();
+        world.insert_resource(mode);
+        world.insert_resource(AudioSettings {
+            volume: 0.2,
+            device: "headphones".to_string(),
+        });
+        world
+    }
+
+    #[test]
+    fn test_load_inserts_default_resource() {
+        #[derive(Resource, Reflect, Default)]
+        #[reflect(Resource, Default, @PreferencesGroup("video"))]
+        struct VideoSettings {
+            brightness: f32,
+            display: String,
+        }
+
+        let mut world = World::new();
+        world.init_resource::<AppTypeRegistry>();
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<VideoSettings>();
+        load_preferences_from_bytes(&mut world, b"[video]\nbrightness = 0.75\n").unwrap();
+        let video = world.resource::<VideoSettings>();
+        assert_eq!(video.brightness, 0.75);
+        assert_eq!(video.display, "");
+    }
+
+    #[test]
+    fn test_key_style_round_trip() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"))]
+        struct MixerSettings {
+            master_volume: f32,
+            output_device: String,
+        }
+
+        let dir = crate::tests::test_dir("key_style");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<MixerSettings>();
+        world.insert_resource(PreferencesKeyStyle::Kebab);
+        world.insert_resource(MixerSettings {
+            master_volume: 0.5,
+            output_device: "speakers".to_string(),
+        });
+        crate::SavePreferences::Always.apply(&mut world);
+        assert_eq!(
+            fs::read_to_string(dir.join("prefs.toml")).unwrap(),
+            "[audio]\nmaster-volume = 0.5\noutput-device = \"speakers\"\n"
+        );
+
+        world.insert_resource(MixerSettings {
+            master_volume: 1.0,
+            output_device: String::new(),
+        });
+        load_preferences(&mut world);
+        let mixer = world.resource::<MixerSettings>();
+        assert_eq!(mixer.master_volume, 0.5);
+        assert_eq!(mixer.output_device, "speakers");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_load_enum_case_insensitive() {
+        #[derive(Reflect, PartialEq, Debug, Default)]
+        enum Theme {
+            #[default]
+            Light,
+            Dark,
+        }
+
+        #[derive(Resource, Reflect, Default)]
+        #[reflect(Default, @PreferencesGroup("gfx"))]
+        struct GraphicsSettings {
+            theme: Theme,
+        }
+
+        fn load_theme(matching: PreferencesVariantMatching) -> Theme {
+            let mut world = World::new();
+            world.init_resource::<AppTypeRegistry>();
+            {
+                let registry = world.resource::<AppTypeRegistry>();
+                let mut registry = registry.write();
+                registry.register::<GraphicsSettings>();
+                registry.register::<Theme>();
+            }
+            world.insert_resource(matching);
+            world.init_resource::<GraphicsSettings>();
+            load_preferences_from_bytes(&mut world, b"[gfx]\ntheme = \"dark\"\n").unwrap();
+            world.remove_resource::<GraphicsSettings>().unwrap().theme
+        }
+
+        assert_eq!(load_theme(PreferencesVariantMatching::Exact), Theme::Light);
+        assert_eq!(
+            load_theme(PreferencesVariantMatching::CaseInsensitive),
+            Theme::Dark
+        );
+    }
+
+    #[test]
+    fn test_load_integer_into_float_and_usize() {
+        #[derive(Resource, Reflect, Default)]
+        #[reflect(Default, @PreferencesGroup("view"))]
+        struct ViewSettings {
+            scale: f64,
+            columns: usize,
+        }
+
+        let mut world = World::new();
+        world.init_resource::<AppTypeRegistry>();
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<ViewSettings>();
+        world.init_resource::<ViewSettings>();
+        load_preferences_from_bytes(&mut world, b"[view]\nscale = 2\ncolumns = -1\n").unwrap();
+        let view = world.resource::<ViewSettings>();
+        assert_eq!(view.scale, 2.0);
+        assert_eq!(view.columns, 0);
+    }
+
+    #[test]
+    fn test_load_from_bytes() {
+        const DEFAULTS: &[u8] = b"[audio]\nvolume = 0.9\ndevice = \"speakers\"\n";
+
+        let mut world = audio_world(PreferencesLoadMode::KeepOnMissing);
+        load_preferences_from_bytes(&mut world, DEFAULTS).unwrap();
+        let audio = world.resource::<AudioSettings>();
+        assert_eq!(audio.volume, 0.9);
+        assert_eq!(audio.device, "speakers");
+
+        assert!(load_preferences_from_bytes(&mut world, b"[audio").is_err());
+    }
+
+    #[test]
+    fn test_load_keeps_missing_keys() {
+        let mut world = audio_world(PreferencesLoadMode::KeepOnMissing);
+        let table: toml::Table = toml::from_str("[audio]\nvolume = 0.7\n").unwrap();
+        apply_preferences(&mut world, &table, None);
+
+        let audio = world.resource::<AudioSettings>();
+        assert_eq!(audio.volume, 0.7);
+        assert_eq!(audio.device, "headphones");
+    }
+
+    #[test]
+    fn test_load_resets_missing_keys() {
+        let mut world = audio_world(PreferencesLoadMode::ResetOnMissing);
+        let table: toml::Table = toml::from_str("[audio]\nvolume = 0.7\n").unwrap();
+        apply_preferences(&mut world, &table, None);
+
+        let audio = world.resource::<AudioSettings>();
+        assert_eq!(audio.volume, 0.7);
+        assert_eq!(audio.device, "");
+    }
+
+    #[test]
+    fn test_load_strict_unknown_keys() {
+        let mut world = audio_world(PreferencesLoadMode::KeepOnMissing);
+        world.init_resource::<Events<PreferencesError>>();
+        let bytes = b"[audio]\nvolume = 0.5\nvolumme = 0.7\n\n[vidio]\nbrightness = 1.0\n";
+        load_preferences_from_bytes(&mut world, bytes).unwrap();
+        assert!(world
+            .resource::<Events<PreferencesError>>()
+            .iter_current_update_events()
+            .next()
+            .is_none());
+
+        world.insert_resource(PreferencesLoadStrict(true));
+        load_preferences_from_bytes(&mut world, bytes).unwrap();
+        let events = world.resource::<Events<PreferencesError>>();
+        let errors: Vec<_> = events
+            .iter_current_update_events()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "Unknown preference key in preferences file: audio.volumme",
+                "Unknown preference key in preferences file: vidio",
+            ]
+        );
+        // The known values are still loaded.
+        assert_eq!(world.resource::<AudioSettings>().volume, 0.5);
+    }
+
+    #[derive(Resource, Reflect)]
+    #[reflect(@PreferencesGroup("server"))]
+    struct ServerSettings {
+        motd: Arc<str>,
+        region: Arc<String>,
+    }
+
+    #[test]
+    fn test_arc_str_round_trip() {
+        let mut world = World::new();
+        world.init_resource::<AppTypeRegistry>();
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<ServerSettings>();
+        world.insert_resource(ServerSettings {
+            motd: Arc::from("Hello"),
+            region: Arc::new("eu-west".to_string()),
+        });
+        let table = crate::save::build_preferences_table(&world);
+        assert_eq!(table["server"]["motd"].as_str(), Some("Hello"));
+        assert_eq!(table["server"]["region"].as_str(), Some("eu-west"));
+
+        world.insert_resource(ServerSettings {
+            motd: Arc::from(""),
+            region: Arc::new(String::new()),
+        });
+        apply_preferences(&mut world, &table, None);
+        let server = world.resource::<ServerSettings>();
+        assert_eq!(&*server.motd, "Hello");
+        assert_eq!(server.region.as_str(), "eu-west");
+    }
+
+    #[test]
+    fn test_load_nested_group() {
+        #[derive(Reflect)]
+        struct Quality(i32);
+
+        let table: toml::Table = toml::from_str("[game.graphics.shadows]\nquality = 2\n").unwrap();
+        let mut quality = Quality(0);
+        maybe_load_tuple_struct(
+            DecodeContext::new(&TypeRegistry::default()),
+            &mut quality,
+            None,
+            Some(&PreferencesGroup("game/graphics/shadows")),
+            Some(&PreferencesKey("quality")),
+            &table,
+        );
+        assert_eq!(quality.0, 2);
+    }
+
+    #[test]
+    fn test_list_of_enums_round_trip() {
+        use bevy::ecs::world::Command;
+
+        #[derive(Reflect, Debug, Clone, PartialEq)]
+        enum Layout {
+            Auto,
+            Fixed(f32),
+        }
+
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("ui"))]
+        struct UiSettings {
+            layouts: Vec<Layout>,
+        }
+
+        let dir = crate::tests::test_dir("list_of_enums");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<UiSettings>();
+        world.insert_resource(UiSettings {
+            layouts: vec![Layout::Auto, Layout::Fixed(0.5)],
+        });
+        crate::SavePreferences::Always.apply(&mut world);
+
+        let text = std::fs::read_to_string(dir.join("prefs.toml")).unwrap();
+        assert_eq!(text, "[ui]\nlayouts = [\"Auto\", { Fixed = 0.5 }]\n");
+
+        world.resource_mut::<UiSettings>().layouts = vec![Layout::Fixed(1.0); 3];
+        load_preferences(&mut world);
+        assert_eq!(
+            world.resource::<UiSettings>().layouts,
+            [Layout::Auto, Layout::Fixed(0.5)]
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_enum_index_round_trip() {
+        use bevy::ecs::world::Command;
+
+        #[derive(Reflect, Debug, Clone, Copy, PartialEq)]
+        #[reflect(@crate::PreferencesEnumIndex)]
+        enum Filter {
+            Nearest,
+            Linear,
+            Anisotropic,
+        }
+
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("graphics"))]
+        struct GraphicsSettings {
+            filter: Filter,
+        }
+
+        let dir = crate::tests::test_dir("enum_index");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<GraphicsSettings>();
+        world.insert_resource(GraphicsSettings {
+            filter: Filter::Anisotropic,
+        });
+        crate::SavePreferences::Always.apply(&mut world);
+
+        let text = std::fs::read_to_string(dir.join("prefs.toml")).unwrap();
+        assert_eq!(text, "[graphics]\nfilter = 2\n");
+
+        world.resource_mut::<GraphicsSettings>().filter = Filter::Nearest;
+        load_preferences(&mut world);
+        assert_eq!(
+            world.resource::<GraphicsSettings>().filter,
+            Filter::Anisotropic
+        );
+
+        // The variant name is still accepted.
+        load_preferences_from_bytes(&mut world, b"[graphics]\nfilter = \"Linear\"\n").unwrap();
+        assert_eq!(world.resource::<GraphicsSettings>().filter, Filter::Linear);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_transparent_wrapper_round_trip() {
+        use bevy::ecs::world::Command;
+
+        #[derive(Reflect)]
+        #[reflect(@PreferencesGroup("audio"))]
+        struct AudioSettings {
+            volume: f32,
+        }
+
+        #[derive(Resource, Reflect)]
+        #[reflect(@crate::PreferencesTransparent)]
+        struct Prefs<T: Reflect + TypePath>(T);
+
+        let dir = crate::tests::test_dir("transparent_wrapper");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<Prefs<AudioSettings>>();
+        world.insert_resource(Prefs(AudioSettings { volume: 0.5 }));
+        crate::SavePreferences::Always.apply(&mut world);
+
+        let text = std::fs::read_to_string(dir.join("prefs.toml")).unwrap();
+        assert_eq!(text, "[audio]\nvolume = 0.5\n");
+
+        world.resource_mut::<Prefs<AudioSettings>>().0.volume = 1.0;
+        load_preferences(&mut world);
+        assert_eq!(world.resource::<Prefs<AudioSettings>>().0.volume, 0.5);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_double_option_round_trip() {
+        use bevy::ecs::world::Command;
+
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("profile"))]
+        struct ProfileSettings {
+            nickname: Option<Option<String>>,
+        }
+
+        let dir = crate::tests::test_dir("double_option");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<ProfileSettings>();
+        for (nickname, text) in [
+            (None, "[profile]\n"),
+            (Some(None), "[profile]\nnickname = []\n"),
+            (
+                Some(Some("ace".to_string())),
+                "[profile]\nnickname = \"ace\"\n",
+            ),
+        ] {
+            world.insert_resource(ProfileSettings {
+                nickname: nickname.clone(),
+            });
+            crate::SavePreferences::Always.apply(&mut world);
+            assert_eq!(fs::read_to_string(dir.join("prefs.toml")).unwrap(), text);
+
+            world.resource_mut::<ProfileSettings>().nickname = Some(Some("other".to_string()));
+            load_preferences(&mut world);
+            assert_eq!(world.resource::<ProfileSettings>().nickname, nickname);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_default_on_parse_failure() {
+        #[derive(Resource, Reflect)]
+        #[reflect(Default, @PreferencesGroup("audio"))]
+        struct AudioSettings {
+            volume: f32,
+            muted: bool,
+            balance: f32,
+        }
+
+        impl Default for AudioSettings {
+            fn default() -> Self {
+                Self {
+                    volume: 0.8,
+                    muted: false,
+                    balance: 0.5,
+                }
+            }
+        }
+
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("video"))]
+        struct VideoSettings {
+            gamma: f32,
+        }
+
+        let dir = crate::tests::test_dir("default_on_parse_failure");
+        let mut world = crate::tests::test_world(&dir);
+        {
+            let mut registry = world.resource::<AppTypeRegistry>().write();
+            registry.register::<AudioSettings>();
+            registry.register::<VideoSettings>();
+        }
+        world.insert_resource(AudioSettings {
+            volume: 0.5,
+            muted: false,
+            balance: 0.25,
+        });
+        world.insert_resource(VideoSettings { gamma: 2.2 });
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("prefs.toml"),
+            "[audio]\nvolume = \"loud\"\nmuted = true\n\n[video]\ngamma = \"bright\"\n",
+        )
+        .unwrap();
+        load_preferences(&mut world);
+        // The value comes from the resource's `Default`, not the field type's.
+        let audio = world.resource::<AudioSettings>();
+        assert_eq!(audio.volume, 0.8);
+        assert!(audio.muted);
+        assert_eq!(audio.balance, 0.25);
+        // Without a resource `Default`, the field type's `Default` is used.
+        assert_eq!(world.resource::<VideoSettings>().gamma, 0.0);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_backup_fallback() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"))]
+        struct AudioSettings {
+            volume: f32,
+        }
+
+        let dir = crate::tests::test_dir("backup_fallback");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<AudioSettings>();
+        world.insert_resource(AudioSettings { volume: 1.0 });
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("prefs.toml"), "[audio\nvolume = 0.5\n").unwrap();
+        fs::write(dir.join("prefs.toml.bak"), "[audio]\nvolume = \n").unwrap();
+        fs::write(dir.join("prefs.toml.bak.1"), "[audio]\nvolume = 0.25\n").unwrap();
+        fs::write(dir.join("prefs.toml.bak.2"), "[audio]\nvolume = 0.75\n").unwrap();
+        load_preferences(&mut world);
+        assert_eq!(world.resource::<AudioSettings>().volume, 0.25);
+
+        // The backups after a missing one are not tried.
+        fs::remove_file(dir.join("prefs.toml.bak.1")).unwrap();
+        world.insert_resource(AudioSettings { volume: 1.0 });
+        load_preferences(&mut world);
+        assert_eq!(world.resource::<AudioSettings>().volume, 1.0);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_none_policies() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("player"))]
+        struct PlayerSettings {
+            nickname: Option<String>,
+            #[reflect(@crate::PreferencesNone::Explicit)]
+            team: Option<String>,
+            #[reflect(@crate::PreferencesNone::Explicit)]
+            scores: Option<Vec<i32>>,
+        }
+
+        let dir = crate::tests::test_dir("none_policies");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<PlayerSettings>();
+        world.insert_resource(PlayerSettings {
+            nickname: None,
+            team: None,
+            scores: None,
+        });
+        crate::SavePreferences::Always.apply(&mut world);
+        assert_eq!(
+            fs::read_to_string(dir.join("prefs.toml")).unwrap(),
+            "[player]\nteam = []\n"
+        );
+
+        world.insert_resource(PlayerSettings {
+            nickname: Some("ace".to_string()),
+            team: Some("red".to_string()),
+            scores: Some(vec![3]),
+        });
+        load_preferences(&mut world);
+        let player = world.resource::<PlayerSettings>();
+        assert_eq!(player.nickname, None);
+        assert_eq!(player.team, None);
+        assert_eq!(player.scores, None);
+
+        // An empty list is still a value of its own.
+        for scores in [Vec::new(), vec![3, 5]] {
+            world.insert_resource(PlayerSettings {
+                nickname: None,
+                team: None,
+                scores: Some(scores.clone()),
+            });
+            crate::SavePreferences::Always.apply(&mut world);
+            world.resource_mut::<PlayerSettings>().scores = None;
+            load_preferences(&mut world);
+            assert_eq!(world.resource::<PlayerSettings>().scores, Some(scores));
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_empty_list_round_trip() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("chat"))]
+        struct ChatSettings {
+            channels: Vec<String>,
+        }
+
+        impl Default for ChatSettings {
+            fn default() -> Self {
+                Self {
+                    channels: vec!["general".to_string(), "help".to_string()],
+                }
+            }
+        }
+
+        let dir = crate::tests::test_dir("empty_list");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<ChatSettings>();
+        world.insert_resource(ChatSettings {
+            channels: Vec::new(),
+        });
+        crate::SavePreferences::Always.apply(&mut world);
+        assert_eq!(
+            fs::read_to_string(dir.join("prefs.toml")).unwrap(),
+            "[chat]\nchannels = []\n"
+        );
+
+        // The empty list replaces the non-empty default, while a missing key keeps it.
+        world.insert_resource(ChatSettings::default());
+        load_preferences(&mut world);
+        assert!(world.resource::<ChatSettings>().channels.is_empty());
+
+        fs::write(dir.join("prefs.toml"), "[chat]\n").unwrap();
+        world.insert_resource(ChatSettings::default());
+        load_preferences(&mut world);
+        assert_eq!(world.resource::<ChatSettings>().channels.len(), 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_array_types() {
+        /// A vector type from another math crate.
+        #[derive(Reflect, Debug, PartialEq)]
+        struct Point2 {
+            x: f32,
+            y: f32,
+        }
+
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("window"))]
+        struct WindowSettings {
+            position: Point2,
+        }
+
+        let dir = crate::tests::test_dir("array_types");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<WindowSettings>();
+        world.insert_resource(crate::PreferencesArrayTypes::default().with_type::<Point2>());
+        world.insert_resource(WindowSettings {
+            position: Point2 { x: 10.0, y: 20.5 },
+        });
+        crate::SavePreferences::Always.apply(&mut world);
+        assert_eq!(
+            fs::read_to_string(dir.join("prefs.toml")).unwrap(),
+            "[window]\nposition = [10.0, 20.5]\n"
+        );
+
+        world.insert_resource(WindowSettings {
+            position: Point2 { x: 0.0, y: 0.0 },
+        });
+        load_preferences(&mut world);
+        assert_eq!(
+            world.resource::<WindowSettings>().position,
+            Point2 { x: 10.0, y: 20.5 }
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_list_of_data_enums_round_trip() {
+        #[derive(Reflect, Debug, PartialEq)]
+        enum Effect {
+            Fade { duration: f32, to: Option<f32> },
+            Echo(f32, u32),
+        }
+
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"))]
+        struct AudioSettings {
+            effects: Vec<Effect>,
+        }
+
+        let dir = crate::tests::test_dir("list_of_data_enums");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<AudioSettings>();
+        world.insert_resource(AudioSettings {
+            effects: vec![
+                Effect::Fade {
+                    duration: 1.5,
+                    to: None,
+                },
+                Effect::Echo(0.25, 3),
+            ],
+        });
+        crate::SavePreferences::Always.apply(&mut world);
+        assert_eq!(
+            fs::read_to_string(dir.join("prefs.toml")).unwrap(),
+            "[[audio.effects]]\n\n[audio.effects.Fade]\nduration = 1.5\n\n\
+             [[audio.effects]]\nEcho = [0.25, 3]\n"
+        );
+
+        world.insert_resource(AudioSettings {
+            effects: vec![Effect::Echo(1.0, 1)],
+        });
+        load_preferences(&mut world);
+        assert_eq!(
+            world.resource::<AudioSettings>().effects,
+            [
+                Effect::Fade {
+                    duration: 1.5,
+                    to: None,
+                },
+                Effect::Echo(0.25, 3),
+            ]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_map_of_structs_round_trip() {
+        #[derive(Reflect, Default, Debug, PartialEq)]
+        #[reflect(Default)]
+        struct AudioChannel {
+            volume: f32,
+            muted: bool,
+        }
+
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"))]
+        struct AudioSettings {
+            channels: bevy::utils::HashMap<String, AudioChannel>,
+        }
+
+        let dir = crate::tests::test_dir("map_of_structs");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<AudioSettings>();
+        let mut channels = bevy::utils::HashMap::default();
+        channels.insert(
+            "music".to_string(),
+            AudioChannel {
+                volume: 0.5,
+                muted: false,
+            },
+        );
+        channels.insert(
+            "voice".to_string(),
+            AudioChannel {
+                volume: 1.0,
+                muted: true,
+            },
+        );
+        world.insert_resource(AudioSettings { channels });
+        crate::SavePreferences::Always.apply(&mut world);
+        assert_eq!(
+            fs::read_to_string(dir.join("prefs.toml")).unwrap(),
+            "[audio.channels.music]\nmuted = false\nvolume = 0.5\n\n\
+             [audio.channels.voice]\nmuted = true\nvolume = 1.0\n"
+        );
+
+        let mut channels = bevy::utils::HashMap::default();
+        channels.insert("effects".to_string(), AudioChannel::default());
+        world.insert_resource(AudioSettings { channels });
+        load_preferences(&mut world);
+        let channels = &world.resource::<AudioSettings>().channels;
+        assert_eq!(channels.len(), 2);
+        assert_eq!(
+            channels["music"],
+            AudioChannel {
+                volume: 0.5,
+                muted: false
+            }
+        );
+        assert_eq!(
+            channels["voice"],
+            AudioChannel {
+                volume: 1.0,
+                muted: true
+            }
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_table_migration() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("ui"))]
+        struct UiSettings {
+            volume: f32,
+        }
+
+        let mut world = World::new();
+        world.init_resource::<AppTypeRegistry>();
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<UiSettings>();
+        world.insert_resource(UiSettings { volume: 1.0 });
+        // The UI volume used to be stored in the audio group.
+        world.insert_resource(PreferencesMigrations::default().with_table_migration(
+            |mut table| {
+                let volume = table
+                    .get_mut("audio")
+                    .and_then(|audio| audio.as_table_mut())
+                    .and_then(|audio| audio.remove("ui_volume"));
+                if let Some(volume) = volume {
+                    let mut ui = toml::Table::new();
+                    ui.insert("volume".to_string(), volume);
+                    table.insert("ui".to_string(), toml::Value::Table(ui));
+                }
+                table
+            },
+        ));
+        load_preferences_from_bytes(&mut world, b"[audio]\nui_volume = 0.25\n").unwrap();
+        assert_eq!(world.resource::<UiSettings>().volume, 0.25);
+    }
+
+    #[test]
+    fn test_load_fragments() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("window"))]
+        struct WindowSettings {
+            width: u32,
+            height: u32,
+            fullscreen: bool,
+        }
+
+        let dir = crate::tests::test_dir("load_fragments");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<WindowSettings>();
+        world.insert_resource(WindowSettings {
+            width: 0,
+            height: 0,
+            fullscreen: false,
+        });
+        world.insert_resource(PreferencesFragmentsDir(Some("prefs.d".into())));
+        fs::create_dir_all(dir.join("prefs.d")).unwrap();
+        fs::write(
+            dir.join("prefs.toml"),
+            "[window]\nwidth = 800\nheight = 600\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("prefs.d").join("10-display.toml"),
+            "[window]\nwidth = 1024\nfullscreen = true\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("prefs.d").join("20-local.toml"),
+            "[window]\nwidth = 1920\n",
+        )
+        .unwrap();
+        fs::write(dir.join("prefs.d").join("notes.txt"), "width = 1\n").unwrap();
+        load_preferences(&mut world);
+
+        let window = world.resource::<WindowSettings>();
+        assert_eq!(window.width, 1920);
+        assert_eq!(window.height, 600);
+        assert!(window.fullscreen);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_load_with_byte_order_mark() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"))]
+        struct AudioSettings {
+            volume: f32,
+        }
+
+        let dir = crate::tests::test_dir("byte_order_mark");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<AudioSettings>();
+        world.insert_resource(AudioSettings { volume: 1.0 });
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("prefs.toml"),
+            "\u{feff}\n\n[audio]\nvolume = 0.25\n",
+        )
+        .unwrap();
+        load_preferences(&mut world);
+        assert_eq!(world.resource::<AudioSettings>().volume, 0.25);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_skipped_field() {
+        use bevy::{ecs::world::Command, utils::HashMap};
+
+        use crate::PreferencesSkip;
+
+        #[derive(Resource, Reflect, Default)]
+        #[reflect(Default, @PreferencesGroup("browser"))]
+        struct BrowserSettings {
+            home_page: String,
+            #[reflect(@PreferencesSkip)]
+            page_cache: HashMap<String, String>,
+        }
+
+        let dir = crate::tests::test_dir("skipped_field");
+        let mut world = crate::tests::test_world(&dir);
+        world.insert_resource(PreferencesLoadMode::ResetOnMissing);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<BrowserSettings>();
+        let mut settings = BrowserSettings {
+            home_page: "about:blank".to_string(),
+            ..default()
+        };
+        settings
+            .page_cache
+            .insert("about:blank".to_string(), String::new());
+        world.insert_resource(settings);
+        crate::SavePreferences::Always.apply(&mut world);
+        assert_eq!(
+            fs::read_to_string(dir.join("prefs.toml")).unwrap(),
+            "[browser]\nhome_page = \"about:blank\"\n"
+        );
+
+        fs::write(
+            dir.join("prefs.toml"),
+            "[browser]\nhome_page = \"https://bevyengine.org\"\npage_cache = {}\n",
+        )
+        .unwrap();
+        load_preferences(&mut world);
+        let settings = world.resource::<BrowserSettings>();
+        assert_eq!(settings.home_page, "https://bevyengine.org");
+        assert_eq!(settings.page_cache.len(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_opaque_serde_fallback() {
+        use bevy::ecs::world::Command;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Reflect, Serialize, Deserialize, Clone, Debug, PartialEq)]
+        #[reflect(opaque, Serialize, Deserialize)]
+        #[serde(transparent)]
+        struct ServerId(String);
+
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("network"))]
+        struct NetworkSettings {
+            server: ServerId,
+            port: u16,
+        }
+
+        let dir = crate::tests::test_dir("opaque_fallback");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<NetworkSettings>();
+        world.insert_resource(crate::PreferencesOpaqueFallback::Serde);
+        world.insert_resource(NetworkSettings {
+            server: ServerId("eu-1".to_string()),
+            port: 4000,
+        });
+        crate::SavePreferences::Always.apply(&mut world);
+
+        let text = std::fs::read_to_string(dir.join("prefs.toml")).unwrap();
+        assert_eq!(text, "[network]\nport = 4000\nserver = \"eu-1\"\n");
+
+        world.insert_resource(NetworkSettings {
+            server: ServerId(String::new()),
+            port: 0,
+        });
+        load_preferences(&mut world);
+        let network = world.resource::<NetworkSettings>();
+        assert_eq!(network.server, ServerId("eu-1".to_string()));
+        assert_eq!(network.port, 4000);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_load_partial_nested_struct() {
+        #[derive(Reflect)]
+        struct WindowSize {
+            width: u32,
+            height: u32,
+        }
+
+        #[derive(Reflect)]
+        struct DisplaySettings {
+            scale: f32,
+            window: WindowSize,
+        }
+
+        let table: toml::Table =
+            toml::from_str("[display]\nscale = 2.0\n\n[display.window]\nwidth = 800\n").unwrap();
+        let mut display = DisplaySettings {
+            scale: 1.0,
+            window: WindowSize {
+                width: 640,
+                height: 480,
+            },
+        };
+        maybe_load_struct(
+            DecodeContext::new(&TypeRegistry::default()),
+            &mut display,
+            None,
+            Some(&PreferencesGroup("display")),
+            None,
+            &table,
+        );
+        assert_eq!(display.scale, 2.0);
+        assert_eq!(display.window.width, 800);
+        assert_eq!(display.window.height, 480);
+    }
+
+    #[test]
+    fn test_lazy_group() {
+        use bevy::ecs::world::Command;
+
+        #[derive(Resource, Reflect)]
+        struct GraphicsSettings {
+            quality: String,
+        }
+
+        let dir = crate::tests::test_dir("lazy_group");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<GraphicsSettings>();
+        let mut registrations = PreferencesRegistrations::default();
+        registrations.register(
+            crate::PreferenceRegistration::of::<GraphicsSettings>().with_lazy_group("graphics"),
+        );
+        world.insert_resource(registrations);
+        world.insert_resource(GraphicsSettings {
+            quality: "low".to_string(),
+        });
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("prefs.graphics.toml"),
+            "[graphics]\nquality = \"high\"\n",
+        )
+        .unwrap();
+
+        // The group's file isn't read at startup, nor overwritten by a save.
+        load_preferences(&mut world);
+        assert_eq!(world.resource::<GraphicsSettings>().quality, "low");
+        crate::SavePreferences::Always.apply(&mut world);
+        assert!(fs::read_to_string(dir.join("prefs.graphics.toml"))
+            .unwrap()
+            .contains("high"));
+
+        load_lazy_preference::<GraphicsSettings>(&mut world);
+        assert_eq!(world.resource::<GraphicsSettings>().quality, "high");
+
+        // Only the first request reads the file.
+        world.resource_mut::<GraphicsSettings>().quality = "medium".to_string();
+        load_lazy_preference::<GraphicsSettings>(&mut world);
+        assert_eq!(world.resource::<GraphicsSettings>().quality, "medium");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(feature = "unicode-normalization")]
+    #[test]
+    fn test_load_normalizes_group_and_key() {
+        #[derive(Reflect)]
+        struct Volume(f32);
+
+        let table: toml::Table =
+            toml::from_str("[\"caf\u{e9}\"]\n\"volum\u{e9}\" = 0.5\n").unwrap();
+        let mut volume = Volume(1.0);
+        maybe_load_tuple_struct(
+            DecodeContext::new(&TypeRegistry::default()),
+            &mut volume,
+            None,
+            Some(&PreferencesGroup("cafe\u{301}")),
+            Some(&PreferencesKey("volume\u{301}")),
+            &table,
+        );
+        assert_eq!(volume.0, 0.5);
+    }
 }
@@ -0,0 +1,189 @@
+use std::any::TypeId;
+
+use bevy::{
+    ecs::component::ComponentId,
+    prelude::*,
+    reflect::{ReflectFromPtr, ReflectMut, Struct, TypeInfo},
+};
+
+use crate::{
+    keys::{group_path, normalize_key},
+    registration::{preference_names, PreferencesRegistrations},
+    PreferencesRef,
+};
+
+/// Returns true if the field of the struct at the given index is marked with
+/// [`PreferencesRef`].
+pub(crate) fn is_reference(strct: &dyn Struct, index: usize) -> bool {
+    strct
+        .get_represented_struct_info()
+        .and_then(|info| info.field_at(index))
+        .is_some_and(|field| field.custom_attributes().contains::<PreferencesRef>())
+}
+
+/// The group name which a reference to the given value is stored as, from the group declared
+/// by the value's type.
+pub(crate) fn referenced_group(value: &dyn PartialReflect) -> Option<&'static str> {
+    let info = value.get_represented_type_info()?;
+    let group = preference_names(None, info).0.map(|group| group.0);
+    if group.is_none() {
+        warn!(
+            "Preferences: {} can't be stored as a reference, it has no PreferencesGroup",
+            info.type_path()
+        );
+    }
+    group
+}
+
+/// A field which is to be set to the value of the resource it refers to.
+struct Link {
+    resource: ComponentId,
+    resource_type: TypeId,
+    field: usize,
+    target: ComponentId,
+    target_type: TypeId,
+}
+
+/// Set each field marked with [`PreferencesRef`] to the current value of the resource whose
+/// group name is stored for the field in the document. Fields which are missing from the
+/// document keep their values.
+pub(crate) fn link_references(
+    world: &mut World,
+    table: &toml::Table,
+    registrations: Option<&PreferencesRegistrations>,
+) {
+    let registry = world.resource::<AppTypeRegistry>().clone();
+    let registry = registry.read();
+    let mut links = Vec::new();
+    for (res, _) in world.iter_resources() {
+        let Some(treg) = res.type_id().and_then(|tid| registry.get(tid)) else {
+            continue;
+        };
+        let TypeInfo::Struct(stty) = treg.type_info() else {
+            continue;
+        };
+        let (Some(group), None) = preference_names(registrations, treg.type_info()) else {
+            continue;
+        };
+        let group_table =
+            group_path(group.0).try_fold(table, |table, key| table.get(key.as_ref())?.as_table());
+        for (index, field) in stty.iter().enumerate() {
+            if !field.custom_attributes().contains::<PreferencesRef>() {
+                continue;
+            }
+            let Some(key) = group_table
+                .and_then(|group| group.get(normalize_key(field.name()).as_ref()))
+                .and_then(|value| value.as_str())
+            else {
+                continue;
+            };
+            let target = registry
+                .get(field.type_id())
+                .filter(|target| {
+                    preference_names(registrations, target.type_info())
+                        .0
+                        .is_some_and(|target_group| target_group.0 == key)
+                })
+                .and_then(|_| world.components().get_resource_id(field.type_id()))
+                .filter(|&target| world.get_resource_by_id(target).is_some());
+            match target {
+                Some(target) => links.push(Link {
+                    resource: res.id(),
+                    resource_type: treg.type_id(),
+                    field: index,
+                    target,
+                    target_type: field.type_id(),
+                }),
+                None => warn!(
+                    "Preferences: {}.{} refers to {}, which isn't a {} resource",
+                    stty.type_path(),
+                    field.name(),
+                    key,
+                    field.type_path()
+                ),
+            }
+        }
+    }
+
+    for link in links {
+        let (Some(target_from_ptr), Some(resource_from_ptr)) = (
+            registry.get_type_data::<ReflectFromPtr>(link.target_type),
+            registry.get_type_data::<ReflectFromPtr>(link.resource_type),
+        ) else {
+            continue;
+        };
+        let Some(ptr) = world.get_resource_by_id(link.target) else {
+            continue;
+        };
+        let value = unsafe { target_from_ptr.as_reflect(ptr) }.clone_value();
+        let Some(mut ptr) = world.get_resource_mut_by_id(link.resource) else {
+            continue;
+        };
+        if let ReflectMut::Struct(strct) =
+            unsafe { resource_from_ptr.as_reflect_mut(ptr.as_mut()) }.reflect_mut()
+        {
+            strct
+                .field_at_mut(link.field)
+                .unwrap()
+                .apply(value.as_ref());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::world::Command;
+
+    use super::*;
+    use crate::{PreferencesGroup, SavePreferences};
+
+    #[derive(Resource, Reflect, Clone, PartialEq, Debug)]
+    #[reflect(@PreferencesGroup("theme"))]
+    struct Theme {
+        accent: String,
+    }
+
+    #[derive(Resource, Reflect)]
+    #[reflect(@PreferencesGroup("editor"))]
+    struct EditorSettings {
+        font_size: f32,
+        #[reflect(@PreferencesRef)]
+        theme: Theme,
+    }
+
+    #[test]
+    fn test_reference_by_key() {
+        let dir = crate::tests::test_dir("reference_by_key");
+        let mut world = crate::tests::test_world(&dir);
+        {
+            let mut registry = world.resource::<AppTypeRegistry>().write();
+            registry.register::<Theme>();
+            registry.register::<EditorSettings>();
+        }
+        let theme = Theme {
+            accent: "blue".to_string(),
+        };
+        world.insert_resource(theme.clone());
+        world.insert_resource(EditorSettings {
+            font_size: 12.0,
+            theme,
+        });
+        SavePreferences::Always.apply(&mut world);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("prefs.toml")).unwrap(),
+            "[editor]\nfont_size = 12.0\ntheme = \"theme\"\n\n[theme]\naccent = \"blue\"\n"
+        );
+
+        std::fs::write(
+            dir.join("prefs.toml"),
+            "[editor]\nfont_size = 14.0\ntheme = \"theme\"\n\n[theme]\naccent = \"red\"\n",
+        )
+        .unwrap();
+        crate::load::load_preferences(&mut world);
+        let editor = world.resource::<EditorSettings>();
+        assert_eq!(editor.font_size, 14.0);
+        assert_eq!(editor.theme, *world.resource::<Theme>());
+        assert_eq!(editor.theme.accent, "red");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
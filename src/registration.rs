@@ -0,0 +1,246 @@
+use std::any::TypeId;
+
+use bevy::{
+    prelude::*,
+    reflect::{attributes::CustomAttributes, GetTypeRegistration, TupleStructInfo, TypeInfo},
+    utils::HashMap,
+};
+
+use crate::{PreferencesGroup, PreferencesKey, PreferencesTransparent};
+
+/// Declares a type as a preference at runtime, as an alternative to annotating the type with
+/// `PreferencesGroup` and `PreferencesKey` reflect attributes. A registration which names a
+/// group or key takes precedence over any attributes on the type.
+#[derive(Debug, Clone)]
+pub struct PreferenceRegistration {
+    pub type_id: TypeId,
+    pub group: Option<PreferencesGroup>,
+    pub key: Option<PreferencesKey>,
+    /// If set, the type is saved to the preferences file for this profile rather than the
+    /// main preferences file.
+    pub profile: Option<&'static str>,
+    /// If set, the type is only saved when this predicate returns true, e.g. so that the
+    /// settings of a disabled subsystem are left out of the preferences file.
+    pub include_if: Option<fn(&World) -> bool>,
+    /// If true, the type's profile file is not read at startup, but only when the resource is
+    /// first requested with [`load_lazy_preference`](crate::load_lazy_preference).
+    pub lazy: bool,
+}
+
+impl PreferenceRegistration {
+    /// Create a registration for type `T` with no group or key.
+    pub fn of<T: 'static>() -> Self {
+        Self::from_type_id(TypeId::of::<T>())
+    }
+
+    pub fn from_type_id(type_id: TypeId) -> Self {
+        Self {
+            type_id,
+            group: None,
+            key: None,
+            profile: None,
+            include_if: None,
+            lazy: false,
+        }
+    }
+
+    /// Place the type's contents in the named table.
+    pub fn with_group(mut self, group: &'static str) -> Self {
+        self.group = Some(PreferencesGroup(group));
+        self
+    }
+
+    /// Store the type under the given key.
+    pub fn with_key(mut self, key: &'static str) -> Self {
+        self.key = Some(PreferencesKey(key));
+        self
+    }
+
+    /// Store the type in a separate preferences file for the named profile, for example each
+    /// player's preferences in a split-screen game.
+    pub fn with_profile(mut self, profile: &'static str) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    /// Store the type in the named group of its own file, e.g. `prefs.graphics.toml`, which is
+    /// only read the first time the resource is requested with
+    /// [`load_lazy_preference`](crate::load_lazy_preference) rather than at startup.
+    pub fn with_lazy_group(mut self, group: &'static str) -> Self {
+        self.group = Some(PreferencesGroup(group));
+        self.profile = Some(group);
+        self.lazy = true;
+        self
+    }
+
+    /// Only save the type when the predicate returns true.
+    pub fn with_include_if(mut self, predicate: fn(&World) -> bool) -> Self {
+        self.include_if = Some(predicate);
+        self
+    }
+}
+
+impl From<(TypeId, Option<&'static str>, Option<&'static str>)> for PreferenceRegistration {
+    fn from((type_id, group, key): (TypeId, Option<&'static str>, Option<&'static str>)) -> Self {
+        Self {
+            type_id,
+            group: group.map(PreferencesGroup),
+            key: key.map(PreferencesKey),
+            profile: None,
+            include_if: None,
+            lazy: false,
+        }
+    }
+}
+
+/// Resource containing all of the preferences which were registered at runtime.
+#[derive(Resource, Debug, Default, Clone)]
+pub struct PreferencesRegistrations(HashMap<TypeId, PreferenceRegistration>);
+
+impl PreferencesRegistrations {
+    /// Add a registration, replacing any previous registration for the same type.
+    pub fn register(&mut self, registration: impl Into<PreferenceRegistration>) {
+        let registration = registration.into();
+        self.0.insert(registration.type_id, registration);
+    }
+
+    pub fn get(&self, type_id: TypeId) -> Option<&PreferenceRegistration> {
+        self.0.get(&type_id)
+    }
+
+    /// The names of all profiles which have at least one registered type, in sorted order.
+    pub fn profiles(&self) -> Vec<&'static str> {
+        let mut profiles = self
+            .0
+            .values()
+            .filter_map(|registration| registration.profile)
+            .collect::<Vec<_>>();
+        profiles.sort_unstable();
+        profiles.dedup();
+        profiles
+    }
+
+    /// Remove the `include_if` predicates, so that every type is saved.
+    pub(crate) fn include_all(&mut self) {
+        for registration in self.0.values_mut() {
+            registration.include_if = None;
+        }
+    }
+
+    /// Returns true if the profile holds types which are loaded lazily.
+    pub fn is_lazy(&self, profile: &str) -> bool {
+        self.0
+            .values()
+            .any(|registration| registration.lazy && registration.profile == Some(profile))
+    }
+}
+
+/// Extension methods for setting up preferences on an [`App`].
+pub trait PreferencesAppExt {
+    /// Initialize a preference resource to its default value, register it with the type
+    /// registry, and register it as a preference. The type's group and key come from its
+    /// reflect attributes.
+    fn init_preference<T: Resource + Default + GetTypeRegistration>(&mut self) -> &mut Self;
+}
+
+impl PreferencesAppExt for App {
+    fn init_preference<T: Resource + Default + GetTypeRegistration>(&mut self) -> &mut Self {
+        self.init_resource::<T>()
+            .register_type::<T>()
+            .init_resource::<PreferencesRegistrations>();
+        self.world_mut()
+            .resource_mut::<PreferencesRegistrations>()
+            .register(PreferenceRegistration::of::<T>());
+        self
+    }
+}
+
+/// Returns true if the type belongs to the given profile. Types without a registered profile
+/// belong to the main preferences file (`None`).
+pub(crate) fn in_profile(
+    registrations: Option<&PreferencesRegistrations>,
+    type_id: TypeId,
+    profile: Option<&str>,
+) -> bool {
+    registrations
+        .and_then(|r| r.get(type_id))
+        .and_then(|registration| registration.profile)
+        == profile
+}
+
+/// Returns false if the type has an `include_if` predicate which excludes it from being saved.
+pub(crate) fn is_included(
+    registrations: Option<&PreferencesRegistrations>,
+    type_id: TypeId,
+    world: &World,
+) -> bool {
+    registrations
+        .and_then(|r| r.get(type_id))
+        .and_then(|registration| registration.include_if)
+        .is_none_or(|predicate| predicate(world))
+}
+
+/// Returns true if the type is a `State<S>` resource.
+pub(crate) fn is_state(tsty: &TupleStructInfo) -> bool {
+    tsty.type_path()
+        .starts_with("bevy_state::state::resources::State<")
+}
+
+/// Returns true if the tuple struct is a wrapper whose single field is saved in its place: a
+/// `State<S>`, or a type with the [`PreferencesTransparent`] annotation. The names of the field
+/// are looked up with [`state_preference_names`]. States are loaded through their
+/// `NextState<S>` resource rather than the wrapper.
+pub(crate) fn is_transparent_wrapper(tsty: &TupleStructInfo) -> bool {
+    tsty.field_len() == 1
+        && (is_state(tsty)
+            || tsty
+                .custom_attributes()
+                .contains::<PreferencesTransparent>())
+}
+
+/// The custom attributes of a struct, tuple struct or enum type.
+pub(crate) fn type_attributes(info: &TypeInfo) -> Option<&CustomAttributes> {
+    match info {
+        TypeInfo::Struct(info) => Some(info.custom_attributes()),
+        TypeInfo::TupleStruct(info) => Some(info.custom_attributes()),
+        TypeInfo::Enum(info) => Some(info.custom_attributes()),
+        _ => None,
+    }
+}
+
+/// Look up the group and key under which a type is stored, from its runtime registration if
+/// it names either one, or otherwise from its reflect attributes.
+pub(crate) fn preference_names<'a>(
+    registrations: Option<&'a PreferencesRegistrations>,
+    info: &'a TypeInfo,
+) -> (Option<&'a PreferencesGroup>, Option<&'a PreferencesKey>) {
+    if let Some(registration) = registrations.and_then(|r| r.get(info.type_id())) {
+        if registration.group.is_some() || registration.key.is_some() {
+            return (registration.group.as_ref(), registration.key.as_ref());
+        }
+    }
+    match type_attributes(info) {
+        Some(attrs) => (
+            attrs.get::<PreferencesGroup>(),
+            attrs.get::<PreferencesKey>(),
+        ),
+        None => (None, None),
+    }
+}
+
+/// Look up the group and key of a state type, given the type id of its `State<S>` resource, or
+/// of the field of a transparent wrapper given the wrapper's type id. Since `State<S>` can't be
+/// annotated, a runtime registration of the wrapper takes precedence; otherwise the names come
+/// from the field's type itself.
+pub(crate) fn state_preference_names<'a>(
+    registrations: Option<&'a PreferencesRegistrations>,
+    state_resource: TypeId,
+    state_info: &'a TypeInfo,
+) -> (Option<&'a PreferencesGroup>, Option<&'a PreferencesKey>) {
+    if let Some(registration) = registrations.and_then(|r| r.get(state_resource)) {
+        if registration.group.is_some() || registration.key.is_some() {
+            return (registration.group.as_ref(), registration.key.as_ref());
+        }
+    }
+    preference_names(registrations, state_info)
+}
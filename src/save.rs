@@ -1,12 +1,41 @@
-use std::fs;
+use std::{
+    any::Any,
+    fs, io,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+};
 
 use bevy::{
-    ecs::world::Command,
+    app::InternedAppLabel,
+    ecs::{component::ComponentInfo, world::Command},
     prelude::*,
-    reflect::{Enum, EnumInfo, ReflectFromPtr, ReflectRef, TypeInfo, VariantType},
+    reflect::{attributes::CustomAttributes, Enum, ReflectRef, StructInfo, TypeInfo, VariantType},
+    utils::HashMap,
 };
 
-use crate::{PreferencesChanged, PreferencesDir, PreferencesGroup, PreferencesKey};
+use crate::{
+    backend::PreferencesBackends,
+    cache::{preference_resources, PreferenceResource},
+    convert::{encode_unit_variant, save_struct, store_prop, EncodeContext},
+    encryption::encrypt_groups,
+    error::{report_error, PreferencesError},
+    integrity::{self, PreferencesIntegrity},
+    keys::{group_path, normalize_key, rename_keys, PreferencesKeyStyle},
+    load::{backup_paths, insert_default_resources, is_lazy_unloaded, read_preferences_file},
+    registration::{
+        in_profile, is_included, is_state, is_transparent_wrapper, state_preference_names,
+        PreferencesRegistrations,
+    },
+    secrets::{secret_fields, PreferencesSecrets},
+    timestamps::{update_timestamps, PreferencesTimestamps},
+    PreferencesArrayTypes, PreferencesBackups, PreferencesCatchPanics, PreferencesChanged,
+    PreferencesComment, PreferencesDebounceTimer, PreferencesDir, PreferencesDirty, PreferencesDoc,
+    PreferencesDurationFormat, PreferencesEmptyTables, PreferencesFileFormat, PreferencesFormat,
+    PreferencesGroup, PreferencesKey, PreferencesLayout, PreferencesMaxSize, PreferencesNamespace,
+    PreferencesOpaqueFallback, PreferencesOrder, PreferencesOrdering, PreferencesPersistState,
+    PreferencesSaveMode, PreferencesTableForm, PreferencesTableStyle, PreferencesTempStrategy,
+    PreferencesVerifyWrites, TomlFormat,
+};
 
 #[derive(Default, PartialEq)]
 pub enum SavePreferences {
@@ -15,374 +44,2332 @@ pub enum SavePreferences {
     IfChanged,
     /// Save preferences unconditionally.
     Always,
+    /// Save only the preferences which were registered with the given profile, unconditionally.
+    Profile(String),
 }
 
 impl Command for SavePreferences {
     fn apply(self, world: &mut World) {
         let mut changed = world.get_resource_mut::<PreferencesChanged>().unwrap();
-        if changed.0 || self == SavePreferences::Always {
+        if let SavePreferences::Profile(profile) = self {
+            if is_lazy_unloaded(world, &profile) {
+                return;
+            }
+            let document = build_profile_document(world, Some(&profile));
+            for e in write_preferences_files(world, vec![(Some(&profile), document)]) {
+                report_error(world, e);
+            }
+        } else if changed.0 || self == SavePreferences::Always {
             changed.0 = false;
-            let prefs_dir = world.get_resource::<PreferencesDir>().unwrap();
-            let registry = world.get_resource::<AppTypeRegistry>().unwrap();
-            // let asset_server = world.get_resource::<AssetServer>();
-            let registry_read = registry.read();
-            let prefs_file_new = prefs_dir.0.join("prefs.toml.new");
-            let prefs_file = prefs_dir.0.join("prefs.toml");
-            let mut table = toml::Table::new();
-            for (res, _) in world.iter_resources() {
-                if let Some(tid) = res.type_id() {
-                    if let Some(treg) = registry_read.get(tid) {
-                        match treg.type_info() {
-                            bevy::reflect::TypeInfo::Struct(stty) => {
-                                let group_attr = stty.custom_attributes().get::<PreferencesGroup>();
-                                let key_attr = stty.custom_attributes().get::<PreferencesKey>();
-                                if group_attr.is_some() || key_attr.is_some() {
-                                    let ptr = world.get_resource_by_id(res.id()).unwrap();
-                                    let reflect_from_ptr = treg.data::<ReflectFromPtr>().unwrap();
-                                    let ReflectRef::Struct(st) =
-                                        unsafe { reflect_from_ptr.as_reflect(ptr) }.reflect_ref()
-                                    else {
-                                        panic!("Expected Struct");
-                                    };
-                                    maybe_save_struct(st, group_attr, key_attr, &mut table);
-                                }
-                            }
-                            bevy::reflect::TypeInfo::TupleStruct(tsty) => {
-                                let group_attr = tsty.custom_attributes().get::<PreferencesGroup>();
-                                let key_attr = tsty.custom_attributes().get::<PreferencesKey>();
-                                let ptr = world.get_resource_by_id(res.id()).unwrap();
-                                let reflect_from_ptr = treg.data::<ReflectFromPtr>().unwrap();
-                                let ReflectRef::TupleStruct(tuple_struct) =
-                                    unsafe { reflect_from_ptr.as_reflect(ptr) }.reflect_ref()
-                                else {
-                                    panic!("Expected TupleStruct");
-                                };
-                                if group_attr.is_some() || key_attr.is_some() {
-                                    maybe_save_tuple_struct(
-                                        tuple_struct,
-                                        group_attr,
-                                        key_attr,
-                                        &mut table,
-                                    );
-                                } else if tsty
-                                    .type_path()
-                                    .starts_with("bevy_state::state::resources::State<")
-                                {
-                                    let state_reflect = tuple_struct.field(0).unwrap();
-                                    let state_info =
-                                        state_reflect.get_represented_type_info().unwrap();
-                                    let field_reflect_ref = state_reflect.reflect_ref();
-                                    match (state_info, field_reflect_ref) {
-                                        (TypeInfo::Struct(_), ReflectRef::Struct(_)) => todo!(),
-                                        (TypeInfo::TupleStruct(_), ReflectRef::TupleStruct(_)) => {
-                                            todo!()
-                                        }
-                                        (TypeInfo::Enum(enum_ty), ReflectRef::Enum(enum_ref)) => {
-                                            maybe_save_enum(enum_ty, enum_ref, &mut table);
-                                        }
-                                        _ => {}
-                                    }
-                                }
-                            }
-                            bevy::reflect::TypeInfo::Enum(ety) => {
-                                if let Some(_group) =
-                                    ety.custom_attributes().get::<PreferencesGroup>()
-                                {
-                                    warn!("Preferences: Enums not supported yet: {}", res.name());
-                                } else if let Some(_key) =
-                                    ety.custom_attributes().get::<PreferencesKey>()
-                                {
-                                    warn!("Preferences: Enums not supported yet: {}", res.name());
-                                }
-                                // warn!("Preferences: Enums not supported yet: {}", res.name());
-                            }
-
-                            // Other types cannot be preferences since they don't have attributes.
-                            _ => {}
-                        }
-                    }
-                    // println!("Saving preferences for {:?}", res.name());
-                }
+            if let Some(mut dirty) = world.get_resource_mut::<PreferencesDirty>() {
+                dirty.clear();
             }
-
-            // Recursively create the preferences directory if it doesn't exist.
-            let mut dir_builder = std::fs::DirBuilder::new();
-            dir_builder.recursive(true);
-            if let Err(e) = dir_builder.create(prefs_dir.0.clone()) {
-                warn!("Could not create preferences directory: {:?}", e);
-                return;
+            if let Some(mut timer) = world.get_resource_mut::<PreferencesDebounceTimer>() {
+                timer.clear_pending();
             }
-
-            // Write to temporary file.
-            if let Err(e) = fs::write(&prefs_file_new, table.to_string()) {
-                warn!("Could not write preferences file: {:?}", e);
-                return;
+            let mut documents = vec![(None, build_profile_document(world, None))];
+            let profiles = world
+                .get_resource::<PreferencesRegistrations>()
+                .map(PreferencesRegistrations::profiles)
+                .unwrap_or_default();
+            for profile in profiles {
+                if is_lazy_unloaded(world, profile) {
+                    continue;
+                }
+                documents.push((Some(profile), build_profile_document(world, Some(profile))));
             }
-
-            // Replace old prefs file with new one.
-            if let Err(e) = fs::rename(&prefs_file_new, prefs_file) {
-                warn!("Could not save preferences file: {:?}", e);
+            for e in write_preferences_files(world, documents) {
+                report_error(world, e);
             }
-
-            // info!("Saved: {:?}", prefs_file);
-            // println!("Preferences:\n{}\n", table);
         }
     }
 }
 
-fn maybe_save_struct(
-    strct: &dyn Struct,
-    group_attr: Option<&PreferencesGroup>,
-    key_attr: Option<&PreferencesKey>,
-    table: &mut toml::Table,
-) {
-    if let Some(group) = group_attr {
-        let group = table
-            .entry(group.0.to_string())
-            .or_insert(toml::Value::Table(toml::Table::new()))
-            .as_table_mut()
-            .unwrap();
-        if let Some(_key) = key_attr {
-            todo!();
-        } else {
-            // TODO: Need to derive key name from tuple struct name
-            save_struct(strct, group);
+/// Resource listing the sub-apps whose preference resources are included by
+/// [`save_app_preferences`].
+#[derive(Resource, Debug, Default, Clone)]
+pub struct PreferencesSubApps(pub Vec<InternedAppLabel>);
+
+/// Save the preferences of the main world together with those of each sub-app listed in
+/// [`PreferencesSubApps`], into a single preferences file. Commands such as
+/// [`SavePreferences`] only see the main world, so sub-app preferences are only saved by this
+/// function. Each sub-app world needs its own `AppTypeRegistry` containing its preference
+/// types. If a value is present in more than one world, the main world takes precedence.
+pub fn save_app_preferences(app: &mut App) {
+    let world = app.world();
+    let mut document = build_profile_document(world, None);
+    let sub_apps = world
+        .get_resource::<PreferencesSubApps>()
+        .cloned()
+        .unwrap_or_default();
+    for label in sub_apps.0 {
+        let Some(sub_app) = app.get_sub_app(label) else {
+            warn!("Preferences: No sub-app {:?}", label);
+            continue;
+        };
+        if !sub_app.world().contains_resource::<AppTypeRegistry>() {
+            continue;
         }
-    } else if let Some(_key) = key_attr {
-        // save_struct(strct, key.0, table);
-        todo!();
+        let sub_document = build_profile_document(sub_app.world(), None);
+        merge_tables(&mut document.table, sub_document.table);
+        document.comments.extend(sub_document.comments);
+        document.layout.extend(sub_document.layout);
+        document.secrets.extend(sub_document.secrets);
+        document.namespaces.extend(sub_document.namespaces);
+        document.panicked.extend(sub_document.panicked);
+    }
+    for e in write_preferences_files(world, vec![(None, document)]) {
+        report_error(app.world_mut(), e);
     }
 }
 
-fn save_struct(strct: &dyn Struct, table: &mut toml::Table) {
-    for i in 0..strct.field_len() {
-        let field_reflect = strct.field_at(i).unwrap();
-        match field_reflect.reflect_ref() {
-            ReflectRef::Struct(_) => todo!(),
-            ReflectRef::TupleStruct(_) => todo!(),
-            ReflectRef::Tuple(_) => todo!(),
-            ReflectRef::List(_) => todo!(),
-            ReflectRef::Array(_) => todo!(),
-            ReflectRef::Map(_) => todo!(),
-            ReflectRef::Set(_) => todo!(),
-            ReflectRef::Enum(_) | ReflectRef::Opaque(_) => {
-                store_prop(field_reflect, strct.name_at(i).unwrap(), table);
+/// Add the entries of `from` which are missing from `into`, merging nested tables.
+fn merge_tables(into: &mut toml::Table, from: toml::Table) {
+    for (key, value) in from {
+        match (into.get_mut(&key), value) {
+            (Some(toml::Value::Table(into)), toml::Value::Table(from)) => merge_tables(into, from),
+            (Some(_), _) => {}
+            (None, value) => {
+                into.insert(key, value);
             }
         }
     }
 }
 
-fn maybe_save_tuple_struct(
-    tuple_struct: &dyn TupleStruct,
-    group_attr: Option<&PreferencesGroup>,
-    key_attr: Option<&PreferencesKey>,
-    table: &mut toml::Table,
-) {
-    if let Some(group) = group_attr {
-        let group = table
-            .entry(group.0.to_string())
-            .or_insert(toml::Value::Table(toml::Table::new()))
-            .as_table_mut()
-            .unwrap();
-        if let Some(key) = key_attr {
-            save_tuple_struct(tuple_struct, key.0, group);
-        } else {
-            // TODO: Need to derive key name from tuple struct name
-            todo!();
+/// Add the entries of `from` to `into`, merging nested tables. Unlike [`merge_tables`], the
+/// entries of `from` replace those of `into`, as if they had been saved into it.
+fn replace_tables(into: &mut toml::Table, from: toml::Table) {
+    for (key, value) in from {
+        match (into.get_mut(&key), value) {
+            (Some(toml::Value::Table(into)), toml::Value::Table(from)) => {
+                replace_tables(into, from)
+            }
+            (_, value) => {
+                into.insert(key, value);
+            }
         }
-    } else if let Some(key) = key_attr {
-        save_tuple_struct(tuple_struct, key.0, table);
     }
 }
 
-fn save_tuple_struct(tuple_struct: &dyn TupleStruct, key: &'static str, table: &mut toml::Table) {
-    if tuple_struct.field_len() == 1 {
-        let field_reflect = tuple_struct.field(0).unwrap();
-        match field_reflect.reflect_ref() {
-            ReflectRef::Struct(_) => todo!(),
-            ReflectRef::TupleStruct(_) => todo!(),
-            ReflectRef::Tuple(_) => todo!(),
-            ReflectRef::List(_) => todo!(),
-            ReflectRef::Array(_) => todo!(),
-            ReflectRef::Map(_) => todo!(),
-            ReflectRef::Set(_) => todo!(),
-            ReflectRef::Enum(_) | ReflectRef::Opaque(_) => {
-                store_prop(field_reflect, key, table);
-            }
+/// Build a preferences document holding the default value of every preference type which
+/// registers `ReflectDefault` and `ReflectResource`, with every key present, e.g. to author a
+/// reference config to ship with a game. The world's own preference resources are not read.
+/// Like [`dump_preferences`], resources registered with a profile are not included.
+pub fn generate_default_document(world: &World) -> toml::Table {
+    let registry = world.resource::<AppTypeRegistry>().clone();
+    let mut defaults = World::new();
+    defaults.insert_resource(registry.clone());
+    if let Some(mut registrations) = world.get_resource::<PreferencesRegistrations>().cloned() {
+        registrations.include_all();
+        defaults.insert_resource(registrations);
+    }
+    if let Some(style) = world.get_resource::<PreferencesKeyStyle>() {
+        defaults.insert_resource(*style);
+    }
+    if let Some(namespace) = world.get_resource::<PreferencesNamespace>() {
+        defaults.insert_resource(namespace.clone());
+    }
+    let registrations = defaults.get_resource::<PreferencesRegistrations>().cloned();
+    insert_default_resources(&mut defaults, &registry, registrations.as_ref(), None);
+    build_profile_document(&defaults, None).table
+}
+
+/// Command which logs the current preferences at info level as TOML, e.g. so that a user can
+/// paste them into a bug report. Nothing is written to disk.
+pub struct DumpPreferences;
+
+impl Command for DumpPreferences {
+    fn apply(self, world: &mut World) {
+        info!("Preferences:\n{}", dump_preferences(world));
+    }
+}
+
+/// Format the current preferences as TOML, regardless of the configured file format.
+/// Resources registered with a profile are not included.
+pub fn dump_preferences(world: &World) -> String {
+    let document = build_profile_document(world, None);
+    match TomlFormat::default().serialize_with_layout(
+        &document.table,
+        &document.comments,
+        &document.layout,
+    ) {
+        Ok(bytes) => String::from_utf8(bytes).unwrap(),
+        Err(e) => format!("Could not encode preferences: {}", e),
+    }
+}
+
+/// Compute a hash of the current preference values, e.g. to check whether the settings have
+/// changed since they were last synced. The hash depends only on the values, which are hashed
+/// in key order, so it is the same across runs and platforms for the same preferences.
+/// Resources registered with a profile are not included.
+pub fn preferences_hash(world: &World) -> u64 {
+    let table = build_preferences_table(world);
+    match toml::to_string(&table) {
+        Ok(text) => integrity::fnv1a64(text.as_bytes()),
+        Err(e) => {
+            warn!("Could not encode preferences: {}", e);
+            0
         }
     }
 }
 
-fn maybe_save_enum(enum_ty: &EnumInfo, enum_ref: &dyn Enum, table: &mut toml::Table) {
-    let group_attr = enum_ty.custom_attributes().get::<PreferencesGroup>();
-    let key_attr = enum_ty.custom_attributes().get::<PreferencesKey>();
-    if let Some(group) = group_attr {
-        let group = table
-            .entry(group.0.to_string())
-            .or_insert(toml::Value::Table(toml::Table::new()))
-            .as_table_mut()
-            .unwrap();
-        if let Some(key) = key_attr {
-            save_enum(enum_ref, key.0, group);
-        } else {
-            // TODO: Need to derive key name from tuple struct name
-            todo!();
+/// Format the current preferences as environment variable assignments, one per line, e.g.
+/// `MYAPP_AUDIO_VOLUME=0.5` for the `volume` key of the `audio` group with the prefix `MYAPP`.
+/// The variable names are the key paths in upper case, joined with underscores. Strings are
+/// written as they are, and other values as TOML, so a list is written as `'[1, 2]'`. Values
+/// with characters which the shell would interpret, such as spaces or newlines, are single-quoted
+/// so that the output can be sourced by a POSIX shell. Resources registered with a profile are
+/// not included.
+pub fn export_as_env(world: &World, prefix: &str) -> String {
+    let document = build_profile_document(world, None);
+    let mut path = Vec::new();
+    if !prefix.is_empty() {
+        path.push(env_name(prefix));
+    }
+    let mut lines = String::new();
+    push_env_lines(&document.table, &mut path, &mut lines);
+    lines
+}
+
+fn push_env_lines(table: &toml::Table, path: &mut Vec<String>, lines: &mut String) {
+    for (key, value) in table {
+        path.push(env_name(key));
+        match value {
+            toml::Value::Table(inner) => push_env_lines(inner, path, lines),
+            toml::Value::String(text) => {
+                lines.push_str(&format!("{}={}\n", path.join("_"), shell_quote(text)));
+            }
+            _ => {
+                let text = value.to_string();
+                lines.push_str(&format!("{}={}\n", path.join("_"), shell_quote(&text)));
+            }
         }
-    } else if let Some(key) = key_attr {
-        save_enum(enum_ref, key.0, table);
+        path.pop();
     }
 }
 
-fn save_enum(enum_ref: &dyn Enum, key: &'static str, table: &mut toml::Table) {
-    if enum_ref.variant_type() != VariantType::Unit {
-        todo!("Figure out how to encode non-unit enums in TOML");
+/// Quote a value for a POSIX shell, unless it only has characters which the shell takes
+/// literally. Inside single quotes, a single quote is written as `'\''`.
+fn shell_quote(text: &str) -> String {
+    let literal = |c: char| c.is_ascii_alphanumeric() || "-_./:@+,%".contains(c);
+    if !text.is_empty() && text.chars().all(literal) {
+        text.to_string()
+    } else {
+        format!("'{}'", text.replace('\'', "'\\''"))
     }
-    let v = toml::Value::String(enum_ref.variant_name().to_string());
-    table.insert(key.to_string(), v);
-}
-
-/// Encode a reflected property and store it in the table with the given key.
-fn store_prop(value: &dyn PartialReflect, key: &str, table: &mut toml::Table) {
-    match value.reflect_ref() {
-        ReflectRef::Struct(st) => {
-            let mut field_table = toml::Table::new();
-            save_struct(st, &mut field_table);
-            table.insert(key.to_string(), toml::Value::Table(field_table));
-        }
-
-        ReflectRef::TupleStruct(_) => todo!(),
-        ReflectRef::Tuple(_) => todo!(),
-        ReflectRef::List(_) => todo!(),
-        ReflectRef::Array(_) => todo!(),
-        ReflectRef::Map(_) => todo!(),
-        ReflectRef::Set(_) => todo!(),
-
-        ReflectRef::Enum(en) => {
-            let type_path = value.get_represented_type_info().unwrap().type_path();
-            if type_path.starts_with("core::option::Option") {
-                // None values just leave out the key.
-                if en.variant_name() == "Some" {
-                    let some_value = en.field_at(0).unwrap();
-                    store_prop(some_value, key, table);
-                }
+}
+
+/// Convert a key to the form used in environment variable names, e.g. `ui-scale` to `UI_SCALE`.
+fn env_name(key: &str) -> String {
+    key.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
             } else {
-                warn!("Preferences: Unsupported enum type: {:?}", type_path);
-            }
-        }
-
-        ReflectRef::Opaque(val) => {
-            if let Some(f) = value.try_downcast_ref::<f32>() {
-                let v = toml::Value::Float(*f as f64);
-                table.insert(key.to_string(), v);
-            } else if let Some(f) = value.try_downcast_ref::<f64>() {
-                let v = toml::Value::Float(*f);
-                table.insert(key.to_string(), v);
-            } else if let Some(i) = value.try_downcast_ref::<i8>() {
-                let v = toml::Value::Integer(*i as i64);
-                table.insert(key.to_string(), v);
-            } else if let Some(i) = value.try_downcast_ref::<i16>() {
-                let v = toml::Value::Integer(*i as i64);
-                table.insert(key.to_string(), v);
-            } else if let Some(i) = value.try_downcast_ref::<i32>() {
-                let v = toml::Value::Integer(*i as i64);
-                table.insert(key.to_string(), v);
-            } else if let Some(i) = value.try_downcast_ref::<i64>() {
-                let v = toml::Value::Integer(*i);
-                table.insert(key.to_string(), v);
-            } else if let Some(i) = value.try_downcast_ref::<u8>() {
-                let v = toml::Value::Integer(*i as i64);
-                table.insert(key.to_string(), v);
-            } else if let Some(i) = value.try_downcast_ref::<u16>() {
-                let v = toml::Value::Integer(*i as i64);
-                table.insert(key.to_string(), v);
-            } else if let Some(i) = value.try_downcast_ref::<u32>() {
-                let v = toml::Value::Integer(*i as i64);
-                table.insert(key.to_string(), v);
-            } else if let Some(i) = value.try_downcast_ref::<u64>() {
-                if *i <= i64::MAX as u64 {
-                    let v = toml::Value::Integer(*i as i64);
-                    table.insert(key.to_string(), v);
-                } else {
-                    warn!("Preferences: u64 value too large: {}", i);
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Resource holding a hook which can modify the preferences document right before it is
+/// written, e.g. to stamp it with a timestamp. It is called once for each file written.
+#[derive(Resource)]
+pub struct PreferencesPreWrite(pub Box<dyn Fn(&mut toml::Table) + Send + Sync>);
+
+/// Resource holding a comment which is written at the top of the preferences file, e.g.
+/// `"Generated by MyApp - do not edit while the app is running"`. Formats which can't represent
+/// comments leave it out.
+#[derive(Resource, Debug, Clone, PartialEq, Eq)]
+pub struct PreferencesHeader(pub String);
+
+/// The assembled preferences document, along with the comments to write into it.
+#[derive(Default, Clone)]
+pub(crate) struct PreferencesDocument {
+    pub(crate) table: toml::Table,
+    pub(crate) comments: Vec<PreferencesComment>,
+    /// The declared arrangement of the groups and keys in the document.
+    pub(crate) layout: PreferencesLayout,
+    /// Resources which could not be saved because they lack a type id, or were registered as
+    /// preferences but not with the type registry, along with the reason.
+    pub(crate) skipped: Vec<String>,
+    /// Names and values of the secret fields, which are not part of `table`.
+    pub(crate) secrets: Vec<(String, String)>,
+    /// The namespaces whose tables this document replaces. If empty, the document replaces the
+    /// whole file.
+    pub(crate) namespaces: Vec<String>,
+    /// Names of the resources whose serialization panicked, with the panic message, when
+    /// panics are caught.
+    pub(crate) panicked: Vec<(String, String)>,
+}
+
+/// Resource holding the preferences document of each file as it was when loaded, which saves
+/// are compared against in [`PreferencesSaveMode::ChangedSinceLoad`] mode.
+#[derive(Resource, Default)]
+pub(crate) struct PreferencesBaseline(HashMap<Option<String>, toml::Table>);
+
+/// Record the current preferences of the main file, or of the given profile, as the baseline
+/// for later saves, if the save mode needs one.
+pub(crate) fn capture_baseline(world: &mut World, profile: Option<&str>) {
+    if world.get_resource::<PreferencesSaveMode>() != Some(&PreferencesSaveMode::ChangedSinceLoad) {
+        return;
+    }
+    let table = build_profile_document(world, profile).table;
+    world
+        .get_resource_or_insert_with(PreferencesBaseline::default)
+        .0
+        .insert(profile.map(str::to_string), table);
+}
+
+/// Copy the values of `current` which differ from `baseline` into `table`.
+fn merge_changed(table: &mut toml::Table, current: &toml::Table, baseline: &toml::Table) {
+    for (key, value) in current {
+        match (value, baseline.get(key)) {
+            (toml::Value::Table(current), Some(toml::Value::Table(baseline))) => {
+                let entry = table
+                    .entry(key.clone())
+                    .or_insert(toml::Value::Table(toml::Table::new()));
+                if !entry.is_table() {
+                    *entry = toml::Value::Table(toml::Table::new());
                 }
-            } else if let Some(i) = value.try_downcast_ref::<usize>() {
-                if *i <= i64::MAX as usize {
-                    let v = toml::Value::Integer(*i as i64);
-                    table.insert(key.to_string(), v);
-                } else {
-                    warn!("Preferences: usize value too large: {}", i);
+                merge_changed(entry.as_table_mut().unwrap(), current, baseline);
+            }
+            (value, baseline) if baseline != Some(value) => {
+                table.insert(key.clone(), value.clone());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A preferences file serialized for writing, along with the table it was serialized from.
+struct PreparedFile {
+    file_name: String,
+    contents: Vec<u8>,
+    table: toml::Table,
+}
+
+/// Write the preferences documents of the main file and of the given profiles, and write each
+/// to the [`PreferencesBackends`]. The new contents of every file are written to temporary files
+/// first, and only once all of them have been written are they renamed into place, so that a
+/// failure doesn't leave some files updated and others stale. Problems which the user can fix
+/// are returned, and other failures are logged.
+fn write_preferences_files(
+    world: &World,
+    documents: Vec<(Option<&str>, PreferencesDocument)>,
+) -> Vec<PreferencesError> {
+    let mut errors = Vec::new();
+    let files = documents
+        .into_iter()
+        .filter_map(|(profile, document)| {
+            prepare_preferences_file(world, profile, document, &mut errors)
+        })
+        .collect::<Vec<_>>();
+
+    let mut staged = Vec::new();
+    let mut written = true;
+    for file in &files {
+        match stage_file(world, &file.file_name, &file.contents) {
+            Ok(Some(file)) => staged.push(file),
+            Ok(None) => {}
+            Err(e) => {
+                errors.push(e);
+                discard_staged(&staged);
+                written = false;
+                break;
+            }
+        }
+    }
+    if written {
+        if let Err(e) = commit_staged(&staged, |from, to| fs::rename(from, to)) {
+            errors.push(e);
+            written = false;
+        }
+    }
+
+    // A failure to write one destination doesn't prevent writing the others.
+    for file in &files {
+        if written && verify_writes(world) {
+            let format = world
+                .get_resource::<PreferencesFileFormat>()
+                .cloned()
+                .unwrap_or_default();
+            if let Err(e) = verify_file(world, &format, &file.file_name, &file.table) {
+                errors.push(e);
+            }
+        }
+        if let Some(backends) = world.get_resource::<PreferencesBackends>() {
+            for backend in &backends.0 {
+                match backend.write(&file.file_name, &file.contents, &file.table) {
+                    Ok(()) => debug!(
+                        "Preferences: Wrote {} to {}",
+                        file.file_name,
+                        backend.name()
+                    ),
+                    Err(error) => errors.push(PreferencesError::BackendFailed {
+                        backend: backend.name().to_string(),
+                        error,
+                    }),
                 }
-            } else if let Some(s) = value.try_downcast_ref::<String>() {
-                let v = toml::Value::String(s.clone());
-                table.insert(key.to_string(), v);
-            } else {
-                warn!("Preferences: Unsupported type: {:?}", val);
             }
         }
     }
+    errors
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use toml::Table;
+/// Serialize a preferences document for the main preferences file, or for the file of the given
+/// profile. Returns `None` if the file shouldn't be written, after adding any problem which the
+/// user can fix to `errors`.
+fn prepare_preferences_file(
+    world: &World,
+    profile: Option<&str>,
+    mut document: PreferencesDocument,
+    errors: &mut Vec<PreferencesError>,
+) -> Option<PreparedFile> {
+    // Write only the values which changed since they were loaded, over the existing file.
+    let baseline = world
+        .get_resource::<PreferencesBaseline>()
+        .and_then(|baseline| baseline.0.get(&profile.map(str::to_string)));
+    if let Some(baseline) = baseline {
+        let mut table = read_preferences_file(world, profile)
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        merge_changed(&mut table, &document.table, baseline);
+        document.table = table;
+    }
 
-    #[derive(Reflect)]
-    struct TestStruct {
-        field1: f32,
-        field2: String,
+    if let Some(pre_write) = world.get_resource::<PreferencesPreWrite>() {
+        (pre_write.0)(&mut document.table);
     }
 
-    #[test]
-    fn test_store_prop_f32() {
-        let mut table = Table::new();
-        let value: &dyn PartialReflect = &42.0f32;
-        store_prop(value, "test_f32", &mut table);
-        assert_eq!(table.get("test_f32").unwrap().as_float().unwrap(), 42.0);
+    let format = world
+        .get_resource::<PreferencesFileFormat>()
+        .cloned()
+        .unwrap_or_default();
+    let file_name = match profile {
+        Some(profile) => format.profile_file_name(profile),
+        None => format.file_name(),
+    };
+
+    let existing =
+        if !document.namespaces.is_empty() || world.contains_resource::<PreferencesTimestamps>() {
+            read_preferences_file(world, profile).ok().flatten()
+        } else {
+            None
+        };
+
+    // Keep the tables of other namespaces which share this file.
+    if !document.namespaces.is_empty() {
+        if let Some(existing) = &existing {
+            for (key, value) in existing {
+                if !document.namespaces.contains(key) {
+                    document.table.entry(key.clone()).or_insert(value.clone());
+                }
+            }
+        }
     }
 
-    #[test]
-    fn test_store_prop_string() {
-        let mut table = Table::new();
-        let value: &dyn PartialReflect = &"test_string".to_string();
-        store_prop(value, "test_string", &mut table);
-        assert_eq!(
-            table.get("test_string").unwrap().as_str().unwrap(),
-            "test_string"
+    update_timestamps(world, &mut document.table, existing.as_ref());
+
+    encrypt_groups(world, &mut document.table);
+
+    match world.get_resource::<PreferencesSecrets>() {
+        Some(store) => {
+            for (name, secret) in &document.secrets {
+                store.0.set(name, secret);
+            }
+        }
+        None if !document.secrets.is_empty() => {
+            warn!("Preferences: No secret store, secrets were not saved");
+        }
+        None => {}
+    }
+
+    errors.extend(
+        document.panicked.drain(..).map(|(resource, message)| {
+            PreferencesError::SerializationPanicked { resource, message }
+        }),
+    );
+
+    if !document.skipped.is_empty() {
+        debug!(
+            "Preferences: skipped resources: {}",
+            document.skipped.join(", ")
         );
     }
 
-    #[test]
-    fn test_store_prop_struct() {
-        let mut table = Table::new();
-        let test_struct = TestStruct {
-            field1: 3.1,
-            field2: "hello".to_string(),
+    let mut contents =
+        match format
+            .0
+            .serialize_with_layout(&document.table, &document.comments, &document.layout)
+        {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!("Could not encode preferences: {}", e);
+                return None;
+            }
         };
-        let value: &dyn PartialReflect = &test_struct;
-        store_prop(value, "test_struct", &mut table);
-        assert!(table.get("test_struct").is_some());
-        let struct_table = table.get("test_struct").unwrap().as_table().unwrap();
-        // assert_eq!(struct_table.get("field1").unwrap().as_float().unwrap(), 3.1);
-        assert_eq!(
-            struct_table.get("field2").unwrap().as_str().unwrap(),
-            "hello"
-        );
+    if let Some(header) = world.get_resource::<PreferencesHeader>() {
+        contents = format.0.add_header(contents, &header.0);
     }
 
-    #[test]
-    fn test_store_prop_option_some() {
-        let mut table = Table::new();
-        let value: &dyn PartialReflect = &Some(42i32);
-        store_prop(value, "test_option", &mut table);
-        assert_eq!(table.get("test_option").unwrap().as_integer().unwrap(), 42);
+    if let Some(max_size) = world.get_resource::<PreferencesMaxSize>() {
+        if let Err(e) = max_size.check(contents.len()) {
+            if max_size.strict {
+                errors.push(e);
+                return None;
+            }
+            warn!("{}", e);
+        }
     }
 
-    #[test]
-    fn test_store_prop_option_none() {
-        let mut table = Table::new();
-        let value: &dyn PartialReflect = &Option::<i32>::None;
-        store_prop(value, "test_option", &mut table);
-        assert!(table.get("test_option").is_none());
+    Some(PreparedFile {
+        file_name,
+        contents,
+        table: document.table,
+    })
+}
+
+fn verify_writes(world: &World) -> bool {
+    world
+        .get_resource::<PreferencesVerifyWrites>()
+        .is_some_and(|verify| verify.0)
+}
+
+/// Read the preferences file with the given name back, and check that it parses to `table`.
+fn verify_file(
+    world: &World,
+    format: &PreferencesFileFormat,
+    file_name: &str,
+    table: &toml::Table,
+) -> Result<(), PreferencesError> {
+    let prefs_file = world.resource::<PreferencesDir>().0.join(file_name);
+    match fs::read(&prefs_file).map(|bytes| format.0.deserialize(&bytes)) {
+        Ok(Ok(written)) if written == *table => {
+            debug!("Preferences: Verified {}", file_name);
+            Ok(())
+        }
+        _ => Err(PreferencesError::VerificationFailed(prefs_file)),
+    }
+}
+
+/// Find the file, if any, which is in the way of creating a directory at the given path: the
+/// path itself or one of its parents.
+fn file_in_path(dir: &std::path::Path) -> Option<std::path::PathBuf> {
+    dir.ancestors()
+        .find(|path| path.is_file())
+        .map(std::path::Path::to_path_buf)
+}
+
+/// A preferences file whose new contents have been written to its temporary file, but which
+/// hasn't been renamed into place yet.
+struct StagedFile {
+    path: PathBuf,
+    temp: PathBuf,
+    contents: Vec<u8>,
+    /// The previous contents of the file, if there was one.
+    existing: Option<Vec<u8>>,
+    /// Whether the checksum of the file accepts both the previous and the new contents.
+    checksum: bool,
+}
+
+/// Write the serialized preferences for the file with the given name in the preferences
+/// directory to its temporary file, ready to be renamed into place by [`commit_staged`].
+/// Returns `None` if the file is unchanged and doesn't need writing.
+fn stage_file(
+    world: &World,
+    file_name: &str,
+    contents: &[u8],
+) -> Result<Option<StagedFile>, PreferencesError> {
+    let prefs_dir = world.get_resource::<PreferencesDir>().unwrap();
+    let prefs_file = prefs_dir.0.join(file_name);
+    let temp_strategy = world
+        .get_resource::<PreferencesTempStrategy>()
+        .cloned()
+        .unwrap_or_default();
+    let prefs_file_new = temp_strategy.temp_path(&prefs_dir.0, file_name);
+    if prefs_file.is_dir() {
+        return Err(PreferencesError::PathIsDirectory(prefs_file));
+    }
+
+    let integrity = world
+        .get_resource::<PreferencesIntegrity>()
+        .copied()
+        .unwrap_or_default();
+
+    // Leave an identical file untouched, so that its modification time doesn't change. Its
+    // checksum may still be missing or stale, e.g. if integrity checking was only just enabled.
+    let existing = fs::read(&prefs_file).ok();
+    if existing.as_deref() == Some(contents) {
+        debug!("Preferences: {} is unchanged, not writing it", file_name);
+        if integrity == PreferencesIntegrity::Crc32
+            && !integrity::checksum_is_current(&prefs_file, contents)
+        {
+            if let Err(e) = integrity::write_checksum(&prefs_file, contents) {
+                warn!("Could not write preferences checksum: {:?}", e);
+            }
+        }
+        return Ok(None);
+    }
+
+    // Recursively create the preferences directory if it doesn't exist.
+    let mut dir_builder = std::fs::DirBuilder::new();
+    dir_builder.recursive(true);
+    if let Err(e) = dir_builder.create(prefs_dir.0.clone()) {
+        if let Some(file) = file_in_path(&prefs_dir.0) {
+            return Err(PreferencesError::PathComponentIsFile {
+                dir: prefs_dir.0.clone(),
+                file,
+            });
+        }
+        return Err(PreferencesError::WriteFailed {
+            path: prefs_dir.0.clone(),
+            error: e,
+        });
+    }
+    if let PreferencesTempStrategy::Directory(temp_dir) = &temp_strategy {
+        if let Err(e) = dir_builder.create(temp_dir) {
+            if let Some(file) = file_in_path(temp_dir) {
+                return Err(PreferencesError::PathComponentIsFile {
+                    dir: temp_dir.clone(),
+                    file,
+                });
+            }
+            return Err(PreferencesError::WriteFailed {
+                path: temp_dir.clone(),
+                error: e,
+            });
+        }
+    }
+
+    let backups = world
+        .get_resource::<PreferencesBackups>()
+        .copied()
+        .unwrap_or_default();
+    if backups.0 > 0 && existing.is_some() {
+        if let Err(e) = rotate_backups(&prefs_file, backups.0) {
+            warn!("Could not back up preferences file: {:?}", e);
+        }
+    }
+
+    // Write to temporary file.
+    if let Err(e) = fs::write(&prefs_file_new, contents) {
+        let _ = fs::remove_file(&prefs_file_new);
+        return Err(PreferencesError::WriteFailed {
+            path: prefs_file_new,
+            error: e,
+        });
+    }
+
+    // Until the new file is in place, the checksum accepts both the old and the new contents, so
+    // that the file matches it whenever the save is interrupted.
+    if integrity == PreferencesIntegrity::Crc32 {
+        let checksums = match &existing {
+            Some(existing) => integrity::write_checksums(&prefs_file, &[contents, existing]),
+            None => integrity::write_checksum(&prefs_file, contents),
+        };
+        if let Err(e) = checksums {
+            let _ = fs::remove_file(&prefs_file_new);
+            return Err(PreferencesError::WriteFailed {
+                path: integrity::checksum_path(&prefs_file),
+                error: e,
+            });
+        }
+    }
+
+    Ok(Some(StagedFile {
+        path: prefs_file,
+        temp: prefs_file_new,
+        contents: contents.to_vec(),
+        existing,
+        checksum: integrity == PreferencesIntegrity::Crc32,
+    }))
+}
+
+/// Rename the staged files into place, in order. If one can't be renamed, the temporary files
+/// of it and of the files after it are removed, so the files which were already renamed keep
+/// their new contents and the others keep their previous contents.
+fn commit_staged(
+    staged: &[StagedFile],
+    rename: impl Fn(&Path, &Path) -> io::Result<()>,
+) -> Result<(), PreferencesError> {
+    for (index, file) in staged.iter().enumerate() {
+        if let Err(e) = rename(&file.temp, &file.path) {
+            discard_staged(&staged[index..]);
+            return Err(PreferencesError::WriteFailed {
+                path: file.path.clone(),
+                error: e,
+            });
+        }
+        if file.checksum && file.existing.is_some() {
+            if let Err(e) = integrity::write_checksum(&file.path, &file.contents) {
+                warn!("Could not write preferences checksum: {:?}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Remove the temporary files of staged files which won't be renamed into place, and restore
+/// their checksums to accept only the previous contents.
+fn discard_staged(staged: &[StagedFile]) {
+    for file in staged {
+        let _ = fs::remove_file(&file.temp);
+        if file.checksum {
+            let restored = match &file.existing {
+                Some(existing) => integrity::write_checksum(&file.path, existing),
+                None => fs::remove_file(integrity::checksum_path(&file.path)),
+            };
+            if let Err(e) = restored {
+                warn!("Could not restore preferences checksum: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Copy the preferences file to its newest backup, `prefs.toml.bak`, after moving the existing
+/// backups along by one and dropping the oldest, so that at most `generations` are kept.
+fn rotate_backups(prefs_file: &Path, generations: usize) -> io::Result<()> {
+    let backups = backup_paths(prefs_file)
+        .take(generations)
+        .collect::<Vec<_>>();
+    for index in (1..backups.len()).rev() {
+        if backups[index - 1].is_file() {
+            move_backup(&backups[index - 1], &backups[index])?;
+        }
+    }
+
+    // Copy rather than rename, so that there is always a preferences file.
+    let mut backup_new = backups[0].clone().into_os_string();
+    backup_new.push(".new");
+    fs::copy(prefs_file, &backup_new)?;
+    fs::rename(&backup_new, &backups[0])?;
+    let checksum = integrity::checksum_path(prefs_file);
+    let backup_checksum = integrity::checksum_path(&backups[0]);
+    if checksum.is_file() {
+        fs::copy(checksum, backup_checksum)?;
+    } else if backup_checksum.is_file() {
+        fs::remove_file(backup_checksum)?;
+    }
+    Ok(())
+}
+
+/// Rename a backup, together with its checksum sidecar, if it has one.
+fn move_backup(from: &Path, to: &Path) -> io::Result<()> {
+    fs::rename(from, to)?;
+    let checksum = integrity::checksum_path(from);
+    let to_checksum = integrity::checksum_path(to);
+    if checksum.is_file() {
+        fs::rename(checksum, to_checksum)
+    } else if to_checksum.is_file() {
+        fs::remove_file(to_checksum)
+    } else {
+        Ok(())
+    }
+}
+
+/// Assemble the preferences document from all preference resources in the world, without
+/// writing anything to disk. Resources registered with a profile are not included.
+pub(crate) fn build_preferences_table(world: &World) -> toml::Table {
+    build_profile_document(world, None).table
+}
+
+/// Assemble the preferences document for the resources registered with the given profile,
+/// or for the resources without a profile if `profile` is `None`.
+pub(crate) fn build_profile_document(world: &World, profile: Option<&str>) -> PreferencesDocument {
+    let registry = world.get_resource::<AppTypeRegistry>().unwrap();
+    let registrations = world.get_resource::<PreferencesRegistrations>();
+    // let asset_server = world.get_resource::<AssetServer>();
+    let registry_read = registry.read();
+    let cx = EncodeContext {
+        fallback: match world.get_resource::<PreferencesOpaqueFallback>() {
+            Some(PreferencesOpaqueFallback::Serde) => Some(&*registry_read),
+            _ => None,
+        },
+        array_types: world.get_resource::<PreferencesArrayTypes>(),
+        durations: world
+            .get_resource::<PreferencesDurationFormat>()
+            .copied()
+            .unwrap_or_default(),
+    };
+    let catch_panics = world
+        .get_resource::<PreferencesCatchPanics>()
+        .is_some_and(|catch| catch.0);
+    let mut document = PreferencesDocument::default();
+    let resources = preference_resources(world, &registry_read);
+    for entry in &resources.resources {
+        let Some(res) = world.components().get_info(entry.id) else {
+            continue;
+        };
+        if world.get_resource_by_id(entry.id).is_none()
+            || !in_profile(registrations, entry.type_id, profile)
+            || !is_included(registrations, entry.type_id, world)
+        {
+            continue;
+        }
+        if catch_panics {
+            // Save into a document of its own, so that a panic doesn't leave part of the
+            // resource in the document.
+            let mut scratch = PreferencesDocument::default();
+            match panic::catch_unwind(AssertUnwindSafe(|| {
+                save_resource(world, res, entry, registrations, cx, &mut scratch)
+            })) {
+                Ok(()) => {
+                    replace_tables(&mut document.table, scratch.table);
+                    document.comments.extend(scratch.comments);
+                    document.layout.extend(scratch.layout);
+                    document.secrets.extend(scratch.secrets);
+                }
+                Err(payload) => document
+                    .panicked
+                    .push((res.name().to_string(), panic_message(payload.as_ref()))),
+            }
+        } else {
+            save_resource(world, res, entry, registrations, cx, &mut document);
+        }
+    }
+    for &(res_id, reason) in &resources.skipped {
+        if world.get_resource_by_id(res_id).is_none() {
+            continue;
+        }
+        if let Some(res) = world.components().get_info(res_id) {
+            document
+                .skipped
+                .push(format!("{} ({})", res.name(), reason));
+        }
+    }
+    let PreferencesDocument {
+        mut table,
+        mut comments,
+        mut layout,
+        skipped,
+        mut secrets,
+        panicked,
+        ..
+    } = document;
+    if world.get_resource::<PreferencesEmptyTables>() == Some(&PreferencesEmptyTables::Omit) {
+        remove_empty_tables(&mut table);
+    }
+    let style = world
+        .get_resource::<PreferencesKeyStyle>()
+        .copied()
+        .unwrap_or_default();
+    if style != PreferencesKeyStyle::AsIs {
+        table = rename_keys(&table, &|key| style.apply(key).into_owned());
+        for path in comments
+            .iter_mut()
+            .map(|comment| &mut comment.path)
+            .chain(layout.paths_mut())
+        {
+            for key in path {
+                *key = style.apply(key).into_owned();
+            }
+        }
+    }
+    let mut namespaces = Vec::new();
+    if let Some(namespace) = world.get_resource::<PreferencesNamespace>() {
+        table = toml::Table::from_iter([(namespace.0.clone(), toml::Value::Table(table))]);
+        for path in comments
+            .iter_mut()
+            .map(|comment| &mut comment.path)
+            .chain(layout.paths_mut())
+        {
+            path.insert(0, namespace.0.clone());
+        }
+        for (name, _) in &mut secrets {
+            *name = format!("{}.{}", namespace.0, name);
+        }
+        namespaces.push(namespace.0.clone());
+    }
+    PreferencesDocument {
+        table,
+        comments,
+        layout,
+        skipped,
+        secrets,
+        namespaces,
+        panicked,
+    }
+}
+
+/// Add a preference resource to the document.
+fn save_resource(
+    world: &World,
+    res: &ComponentInfo,
+    entry: &PreferenceResource,
+    registrations: Option<&PreferencesRegistrations>,
+    cx: EncodeContext,
+    document: &mut PreferencesDocument,
+) {
+    let tid = entry.type_id;
+    let (group_attr, key_attr) = (entry.group.as_ref(), entry.key.as_ref());
+    let reflect_from_ptr = &entry.reflect_from_ptr;
+    match entry.type_info {
+        TypeInfo::Struct(stty) if group_attr.is_some() || key_attr.is_some() => {
+            let Some(ptr) = world.get_resource_by_id(res.id()) else {
+                // The resource was listed but has no value, e.g. because it was removed.
+                debug!(
+                    "Preferences: Resource {} has no value, skipping",
+                    res.name()
+                );
+                return;
+            };
+            let ReflectRef::Struct(st) = unsafe { reflect_from_ptr.as_reflect(ptr) }.reflect_ref()
+            else {
+                panic!("Expected Struct");
+            };
+            maybe_save_struct(st, group_attr, key_attr, &mut document.table, cx);
+            take_secrets(
+                st,
+                stty,
+                group_attr,
+                &mut document.table,
+                &mut document.secrets,
+            );
+            if let Some(group) = group_attr {
+                collect_struct_comments(stty, group, &mut document.comments);
+                collect_group_layout(stty, group, &mut document.layout);
+            }
+        }
+        TypeInfo::TupleStruct(tsty) => {
+            let Some(ptr) = world.get_resource_by_id(res.id()) else {
+                // The resource was listed but has no value, e.g. because it was removed.
+                debug!(
+                    "Preferences: Resource {} has no value, skipping",
+                    res.name()
+                );
+                return;
+            };
+            let ReflectRef::TupleStruct(tuple_struct) =
+                unsafe { reflect_from_ptr.as_reflect(ptr) }.reflect_ref()
+            else {
+                panic!("Expected TupleStruct");
+            };
+            if is_state(tsty) && !PreferencesPersistState::enabled(world) {
+                return;
+            }
+            if is_transparent_wrapper(tsty) {
+                let state_reflect = tuple_struct.field(0).unwrap();
+                let state_info = state_reflect.get_represented_type_info().unwrap();
+                let (group_attr, key_attr) = state_preference_names(registrations, tid, state_info);
+                let field_reflect_ref = state_reflect.reflect_ref();
+                match (state_info, field_reflect_ref) {
+                    (TypeInfo::Struct(stty), ReflectRef::Struct(st)) => {
+                        maybe_save_struct(st, group_attr, key_attr, &mut document.table, cx);
+                        take_secrets(
+                            st,
+                            stty,
+                            group_attr,
+                            &mut document.table,
+                            &mut document.secrets,
+                        );
+                        if let Some(group) = group_attr {
+                            collect_struct_comments(stty, group, &mut document.comments);
+                            collect_group_layout(stty, group, &mut document.layout);
+                        }
+                    }
+                    (TypeInfo::TupleStruct(_), ReflectRef::TupleStruct(inner)) => {
+                        maybe_save_tuple_struct(
+                            inner,
+                            group_attr,
+                            key_attr,
+                            &mut document.table,
+                            cx,
+                        );
+                    }
+                    (TypeInfo::Enum(_), ReflectRef::Enum(enum_ref)) => {
+                        maybe_save_enum(enum_ref, group_attr, key_attr, &mut document.table);
+                    }
+                    _ => {}
+                }
+            } else if group_attr.is_some() || key_attr.is_some() {
+                maybe_save_tuple_struct(
+                    tuple_struct,
+                    group_attr,
+                    key_attr,
+                    &mut document.table,
+                    cx,
+                );
+                if let Some(doc) = tsty.custom_attributes().get::<PreferencesDoc>() {
+                    document.comments.push(PreferencesComment {
+                        path: key_path(group_attr, key_attr),
+                        text: doc.0,
+                    });
+                }
+                push_layout(
+                    &[tsty.custom_attributes()],
+                    &key_path(group_attr, key_attr),
+                    &mut document.layout,
+                );
+            }
+        }
+        TypeInfo::Enum(_) if group_attr.is_some() || key_attr.is_some() => {
+            warn!("Preferences: Enums not supported yet: {}", res.name());
+        }
+
+        // Other types cannot be preferences since they don't have attributes.
+        _ => {}
+    }
+}
+
+/// The message of a caught panic, if it has one.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Remove the tables which are empty, or which become empty once their empty tables are removed.
+fn remove_empty_tables(table: &mut toml::Table) {
+    table.retain(|_, value| match value {
+        toml::Value::Table(inner) => {
+            remove_empty_tables(inner);
+            !inner.is_empty()
+        }
+        _ => true,
+    });
+}
+
+/// Remove the secret fields of a struct from the document, and collect their values so they
+/// can be written to the secret store instead.
+fn take_secrets(
+    strct: &dyn Struct,
+    stty: &StructInfo,
+    group_attr: Option<&PreferencesGroup>,
+    table: &mut toml::Table,
+    secrets: &mut Vec<(String, String)>,
+) {
+    for (field_name, name) in secret_fields(stty, group_attr) {
+        let group = match group_attr {
+            Some(group) => group_table(table, group),
+            None => &mut *table,
+        };
+        group.remove(normalize_key(field_name).as_ref());
+        match strct
+            .field(field_name)
+            .and_then(|field| field.try_downcast_ref::<String>())
+        {
+            Some(secret) => secrets.push((name, secret.clone())),
+            None => warn!("Preferences: Secret field {} must be a String", name),
+        }
+    }
+}
+
+/// Collect the `PreferencesDoc` comments of a struct type, which documents its group table,
+/// and of its fields.
+fn collect_struct_comments(
+    stty: &StructInfo,
+    group: &PreferencesGroup,
+    comments: &mut Vec<PreferencesComment>,
+) {
+    let group = key_path(Some(group), None);
+    if let Some(doc) = stty.custom_attributes().get::<PreferencesDoc>() {
+        comments.push(PreferencesComment {
+            path: group.clone(),
+            text: doc.0,
+        });
+    }
+    for field in stty.iter() {
+        if let Some(doc) = field.custom_attributes().get::<PreferencesDoc>() {
+            let mut path = group.clone();
+            path.push(normalize_key(field.name()).into_owned());
+            comments.push(PreferencesComment { path, text: doc.0 });
+        }
+    }
+}
+
+/// Collect the `PreferencesOrder` and `PreferencesTableForm` annotations of a struct type's
+/// group table and of its fields.
+fn collect_group_layout(
+    stty: &StructInfo,
+    group: &PreferencesGroup,
+    layout: &mut PreferencesLayout,
+) {
+    let path = key_path(Some(group), None);
+    push_layout(&[stty.custom_attributes()], &path, layout);
+    collect_struct_layout(stty, &path, layout);
+}
+
+/// Collect the annotations of the fields of a struct stored in the table at `path`, and of the
+/// fields of its nested structs. A field's own annotations take precedence over its type's.
+fn collect_struct_layout(stty: &StructInfo, path: &[String], layout: &mut PreferencesLayout) {
+    for field in stty.iter() {
+        let mut field_path = path.to_vec();
+        field_path.push(normalize_key(field.name()).into_owned());
+        match field.type_info() {
+            Some(TypeInfo::Struct(field_stty)) => {
+                push_layout(
+                    &[field.custom_attributes(), field_stty.custom_attributes()],
+                    &field_path,
+                    layout,
+                );
+                collect_struct_layout(field_stty, &field_path, layout);
+            }
+            _ => push_layout(&[field.custom_attributes()], &field_path, layout),
+        }
+    }
+}
+
+/// Add the layout annotations of an item to the layout, from the first of the attribute sets
+/// which has each one.
+fn push_layout(attrs: &[&CustomAttributes], path: &[String], layout: &mut PreferencesLayout) {
+    if let Some(order) = attrs
+        .iter()
+        .find_map(|attrs| attrs.get::<PreferencesOrder>())
+    {
+        layout.order.push(PreferencesOrdering {
+            path: path.to_vec(),
+            order: order.0,
+        });
+    }
+    if let Some(form) = attrs
+        .iter()
+        .find_map(|attrs| attrs.get::<PreferencesTableForm>())
+    {
+        layout.tables.push(PreferencesTableStyle {
+            path: path.to_vec(),
+            form: *form,
+        });
+    }
+}
+
+/// Path in the document of a value stored under the given group and key.
+pub(crate) fn key_path(
+    group_attr: Option<&PreferencesGroup>,
+    key_attr: Option<&PreferencesKey>,
+) -> Vec<String> {
+    group_attr
+        .into_iter()
+        .flat_map(|group| group_path(group.0))
+        .chain(key_attr.map(|key| normalize_key(key.0)))
+        .map(|name| name.into_owned())
+        .collect()
+}
+
+fn maybe_save_struct(
+    strct: &dyn Struct,
+    group_attr: Option<&PreferencesGroup>,
+    key_attr: Option<&PreferencesKey>,
+    table: &mut toml::Table,
+    cx: EncodeContext,
+) {
+    if let Some(group) = group_attr {
+        let group = group_table(table, group);
+        if let Some(_key) = key_attr {
+            todo!();
+        } else {
+            // TODO: Need to derive key name from tuple struct name
+            save_struct(strct, group, cx);
+        }
+    } else if let Some(_key) = key_attr {
+        // save_struct(strct, key.0, table);
+        todo!();
+    }
+}
+
+/// Get the table for a group, creating it and any enclosing tables of a nested group.
+fn group_table<'a>(table: &'a mut toml::Table, group: &PreferencesGroup) -> &'a mut toml::Table {
+    group_path(group.0).fold(table, |table, key| {
+        table
+            .entry(key.into_owned())
+            .or_insert(toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .unwrap()
+    })
+}
+
+fn maybe_save_tuple_struct(
+    tuple_struct: &dyn TupleStruct,
+    group_attr: Option<&PreferencesGroup>,
+    key_attr: Option<&PreferencesKey>,
+    table: &mut toml::Table,
+    cx: EncodeContext,
+) {
+    if let Some(group) = group_attr {
+        let group = group_table(table, group);
+        if let Some(key) = key_attr {
+            save_tuple_struct(tuple_struct, key.0, group, cx);
+        } else {
+            // TODO: Need to derive key name from tuple struct name
+            todo!();
+        }
+    } else if let Some(key) = key_attr {
+        save_tuple_struct(tuple_struct, key.0, table, cx);
+    }
+}
+
+fn save_tuple_struct(
+    tuple_struct: &dyn TupleStruct,
+    key: &'static str,
+    table: &mut toml::Table,
+    cx: EncodeContext,
+) {
+    if tuple_struct.field_len() == 1 {
+        let field_reflect = tuple_struct.field(0).unwrap();
+        match field_reflect.reflect_ref() {
+            ReflectRef::Struct(_) => todo!(),
+            ReflectRef::TupleStruct(_) => todo!(),
+            ReflectRef::Set(_) => todo!(),
+            ReflectRef::Tuple(_)
+            | ReflectRef::Map(_)
+            | ReflectRef::List(_)
+            | ReflectRef::Array(_)
+            | ReflectRef::Enum(_)
+            | ReflectRef::Opaque(_) => {
+                store_prop(field_reflect, key, table, cx);
+            }
+        }
+    }
+}
+
+fn maybe_save_enum(
+    enum_ref: &dyn Enum,
+    group_attr: Option<&PreferencesGroup>,
+    key_attr: Option<&PreferencesKey>,
+    table: &mut toml::Table,
+) {
+    if let Some(group) = group_attr {
+        let group = group_table(table, group);
+        if let Some(key) = key_attr {
+            save_enum(enum_ref, key.0, group);
+        } else {
+            // TODO: Need to derive key name from tuple struct name
+            todo!();
+        }
+    } else if let Some(key) = key_attr {
+        save_enum(enum_ref, key.0, table);
+    }
+}
+
+fn save_enum(enum_ref: &dyn Enum, key: &'static str, table: &mut toml::Table) {
+    if enum_ref.variant_type() != VariantType::Unit {
+        todo!("Figure out how to encode non-unit enums in TOML");
+    }
+    table.insert(
+        normalize_key(key).into_owned(),
+        encode_unit_variant(enum_ref),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::app::AppLabel;
+    use toml::Table;
+
+    #[derive(Reflect)]
+    struct TestStruct {
+        field1: f32,
+        field2: String,
+    }
+
+    #[test]
+    fn test_store_prop_f32() {
+        let mut table = Table::new();
+        let value: &dyn PartialReflect = &42.0f32;
+        store_prop(value, "test_f32", &mut table, EncodeContext::default());
+        assert_eq!(table.get("test_f32").unwrap().as_float().unwrap(), 42.0);
+    }
+
+    #[test]
+    fn test_store_prop_string() {
+        let mut table = Table::new();
+        let value: &dyn PartialReflect = &"test_string".to_string();
+        store_prop(value, "test_string", &mut table, EncodeContext::default());
+        assert_eq!(
+            table.get("test_string").unwrap().as_str().unwrap(),
+            "test_string"
+        );
+    }
+
+    #[test]
+    fn test_store_prop_struct() {
+        let mut table = Table::new();
+        let test_struct = TestStruct {
+            field1: 3.1,
+            field2: "hello".to_string(),
+        };
+        let value: &dyn PartialReflect = &test_struct;
+        store_prop(value, "test_struct", &mut table, EncodeContext::default());
+        assert!(table.get("test_struct").is_some());
+        let struct_table = table.get("test_struct").unwrap().as_table().unwrap();
+        assert_eq!(struct_table.get("field1").unwrap().as_float().unwrap(), 3.1);
+        assert_eq!(
+            struct_table.get("field2").unwrap().as_str().unwrap(),
+            "hello"
+        );
+    }
+
+    #[test]
+    fn test_store_prop_bool() {
+        let mut table = Table::new();
+        store_prop(&true, "flag", &mut table, EncodeContext::default());
+        assert_eq!(table["flag"].as_bool(), Some(true));
+    }
+
+    #[test]
+    fn test_store_prop_array() {
+        let mut table = Table::new();
+        store_prop(&[1u8, 2, 3], "slots", &mut table, EncodeContext::default());
+        assert_eq!(table.to_string(), "slots = [1, 2, 3]\n");
+    }
+
+    #[test]
+    fn test_float_formatting() {
+        let mut table = Table::new();
+        store_prop(&0.1f32, "a", &mut table, EncodeContext::default());
+        store_prop(&(1.0f64 / 3.0), "b", &mut table, EncodeContext::default());
+        store_prop(&16777216.0f32, "c", &mut table, EncodeContext::default());
+        store_prop(&1e-7f32, "d", &mut table, EncodeContext::default());
+        store_prop(&f32::INFINITY, "e", &mut table, EncodeContext::default());
+        assert_eq!(
+            table.to_string(),
+            "a = 0.1\nb = 0.3333333333333333\nc = 16777216.0\nd = 0.0000001\ne = inf\n"
+        );
+        assert_eq!(table["a"].as_float().unwrap() as f32, 0.1f32);
+        assert_eq!(table["d"].as_float().unwrap() as f32, 1e-7f32);
+    }
+
+    #[test]
+    fn test_save_doc_comments() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"), @PreferencesDoc("Sound settings."))]
+        struct AudioSettings {
+            #[reflect(@PreferencesDoc("Master volume, from 0 to 1."))]
+            volume: f32,
+            device: String,
+        }
+
+        let dir = crate::tests::test_dir("doc_comments");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<AudioSettings>();
+        world.insert_resource(AudioSettings {
+            volume: 0.5,
+            device: "default".to_string(),
+        });
+        SavePreferences::Always.apply(&mut world);
+
+        let text = fs::read_to_string(dir.join("prefs.toml")).unwrap();
+        assert_eq!(
+            text,
+            "# Sound settings.\n[audio]\ndevice = \"default\"\n# Master volume, from 0 to 1.\nvolume = 0.5\n"
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_save_order() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("video"), @PreferencesOrder(0))]
+        struct VideoSettings {
+            #[reflect(@PreferencesOrder(0))]
+            resolution: String,
+            fullscreen: bool,
+        }
+
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"), @PreferencesOrder(1))]
+        struct AudioSettings {
+            volume: f32,
+        }
+
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("accessibility"))]
+        struct AccessibilitySettings {
+            subtitles: bool,
+        }
+
+        let dir = crate::tests::test_dir("save_order");
+        let mut world = crate::tests::test_world(&dir);
+        {
+            let mut registry = world.resource::<AppTypeRegistry>().write();
+            registry.register::<VideoSettings>();
+            registry.register::<AudioSettings>();
+            registry.register::<AccessibilitySettings>();
+        }
+        world.insert_resource(VideoSettings {
+            resolution: "1920x1080".to_string(),
+            fullscreen: true,
+        });
+        world.insert_resource(AudioSettings { volume: 0.5 });
+        world.insert_resource(AccessibilitySettings { subtitles: false });
+        SavePreferences::Always.apply(&mut world);
+
+        let text = fs::read_to_string(dir.join("prefs.toml")).unwrap();
+        assert_eq!(
+            text,
+            "[video]\nresolution = \"1920x1080\"\nfullscreen = true\n\n[audio]\nvolume = 0.5\n\n\
+             [accessibility]\nsubtitles = false\n"
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_save_table_forms() {
+        #[derive(Reflect)]
+        #[reflect(@PreferencesTableForm::Inline)]
+        struct Size {
+            width: i32,
+            height: i32,
+        }
+
+        #[derive(Reflect)]
+        struct Position {
+            x: i32,
+            y: i32,
+        }
+
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("window"))]
+        struct WindowSettings {
+            size: Size,
+            #[reflect(@PreferencesTableForm::Section)]
+            position: Position,
+            title: String,
+        }
+
+        let dir = crate::tests::test_dir("table_forms");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<WindowSettings>();
+        world.insert_resource(WindowSettings {
+            size: Size {
+                width: 800,
+                height: 600,
+            },
+            position: Position { x: 10, y: 20 },
+            title: "Game".to_string(),
+        });
+        // Without the annotations, both nested structs would be written as dotted keys.
+        world.insert_resource(PreferencesFileFormat::new(
+            TomlFormat::default().with_dotted_depth(1),
+        ));
+        SavePreferences::Always.apply(&mut world);
+
+        assert_eq!(
+            fs::read_to_string(dir.join("prefs.toml")).unwrap(),
+            "[window]\nsize = { height = 600, width = 800 }\ntitle = \"Game\"\n\n\
+             [window.position]\nx = 10\ny = 20\n"
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_save_array_of_tables() {
+        #[derive(Reflect)]
+        struct Profile {
+            name: String,
+            level: i32,
+        }
+
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesKey("profiles"))]
+        struct Profiles(Vec<Profile>);
+
+        let dir = crate::tests::test_dir("array_of_tables");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<Profiles>();
+        world.insert_resource(Profiles(vec![
+            Profile {
+                name: "alice".to_string(),
+                level: 3,
+            },
+            Profile {
+                name: "bob".to_string(),
+                level: 1,
+            },
+        ]));
+        let expected = "[[profiles]]\nlevel = 3\nname = \"alice\"\n\n\
+                        [[profiles]]\nlevel = 1\nname = \"bob\"\n";
+        for format in [
+            TomlFormat::default(),
+            TomlFormat::default().with_dotted_depth(1),
+        ] {
+            world.insert_resource(PreferencesFileFormat::new(format));
+            SavePreferences::Always.apply(&mut world);
+            assert_eq!(
+                fs::read_to_string(dir.join("prefs.toml")).unwrap(),
+                expected
+            );
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_generate_default_document() {
+        #[derive(Resource, Reflect)]
+        #[reflect(Resource, Default, @PreferencesGroup("audio"))]
+        struct AudioSettings {
+            volume: f32,
+            device: String,
+        }
+
+        impl Default for AudioSettings {
+            fn default() -> Self {
+                Self {
+                    volume: 0.5,
+                    device: "default".to_string(),
+                }
+            }
+        }
+
+        #[derive(Resource, Reflect, Default)]
+        #[reflect(Resource, Default, @PreferencesGroup("window"))]
+        struct WindowSettings {
+            width: i32,
+            fullscreen: bool,
+        }
+
+        let mut world = World::new();
+        world.init_resource::<AppTypeRegistry>();
+        {
+            let mut registry = world.resource::<AppTypeRegistry>().write();
+            registry.register::<AudioSettings>();
+            registry.register::<WindowSettings>();
+        }
+        world.insert_resource(AudioSettings {
+            volume: 1.0,
+            device: "headphones".to_string(),
+        });
+
+        let table = generate_default_document(&world);
+        assert_eq!(
+            table.to_string(),
+            "[audio]\ndevice = \"default\"\nvolume = 0.5\n\n\
+             [window]\nfullscreen = false\nwidth = 0\n"
+        );
+        assert_eq!(world.resource::<AudioSettings>().volume, 1.0);
+        assert!(!world.contains_resource::<WindowSettings>());
+    }
+
+    #[test]
+    fn test_save_nested_group() {
+        #[derive(Reflect)]
+        struct Shadows {
+            quality: i32,
+        }
+
+        let mut table = Table::new();
+        maybe_save_struct(
+            &Shadows { quality: 2 },
+            Some(&PreferencesGroup("game/graphics/shadows")),
+            None,
+            &mut table,
+            EncodeContext::default(),
+        );
+        let game = table["game"].as_table().unwrap();
+        let graphics = game["graphics"].as_table().unwrap();
+        let shadows = graphics["shadows"].as_table().unwrap();
+        assert_eq!(shadows["quality"].as_integer(), Some(2));
+        assert_eq!(table.to_string(), "[game.graphics.shadows]\nquality = 2\n");
+    }
+
+    #[test]
+    fn test_omit_empty_tables() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("editor/session"))]
+        struct Session {
+            last_file: std::path::PathBuf,
+        }
+
+        let mut world = crate::tests::test_world(&crate::tests::test_dir("omit_empty_tables"));
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<Session>();
+        world.insert_resource(Session {
+            last_file: "scene.ron".into(),
+        });
+        assert_eq!(
+            build_preferences_table(&world).to_string(),
+            "[editor.session]\n"
+        );
+
+        world.insert_resource(PreferencesEmptyTables::Omit);
+        assert!(build_preferences_table(&world).is_empty());
+    }
+
+    #[test]
+    fn test_dump_preferences() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"), @PreferencesKey("volume"))]
+        struct Volume(f32);
+
+        let dir = crate::tests::test_dir("dump_preferences");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<Volume>();
+        world.insert_resource(Volume(0.5));
+
+        assert!(dump_preferences(&world).contains("[audio]\nvolume = 0.5\n"));
+        DumpPreferences.apply(&mut world);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_preferences_hash() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"))]
+        struct AudioSettings {
+            volume: f32,
+            muted: bool,
+        }
+
+        let dir = crate::tests::test_dir("preferences_hash");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<AudioSettings>();
+        world.insert_resource(AudioSettings {
+            volume: 0.5,
+            muted: false,
+        });
+        let hash = preferences_hash(&world);
+        assert_eq!(preferences_hash(&world), hash);
+
+        world.resource_mut::<AudioSettings>().volume = 0.25;
+        assert_ne!(preferences_hash(&world), hash);
+        world.resource_mut::<AudioSettings>().volume = 0.5;
+        assert_eq!(preferences_hash(&world), hash);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_export_as_env() {
+        #[derive(Reflect)]
+        struct Bus {
+            volume: f32,
+            device: String,
+        }
+
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"))]
+        struct AudioSettings {
+            master: Bus,
+            muted: bool,
+        }
+
+        let dir = crate::tests::test_dir("export_as_env");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<AudioSettings>();
+        world.insert_resource(AudioSettings {
+            master: Bus {
+                volume: 0.5,
+                device: "default".to_string(),
+            },
+            muted: false,
+        });
+
+        assert_eq!(
+            export_as_env(&world, "myapp"),
+            "MYAPP_AUDIO_MASTER_DEVICE=default\n\
+             MYAPP_AUDIO_MASTER_VOLUME=0.5\n\
+             MYAPP_AUDIO_MUTED=false\n"
+        );
+
+        world.resource_mut::<AudioSettings>().master.device = "Living room\nTV's speakers".into();
+        assert_eq!(
+            export_as_env(&world, "myapp"),
+            "MYAPP_AUDIO_MASTER_DEVICE='Living room\nTV'\\''s speakers'\n\
+             MYAPP_AUDIO_MASTER_VOLUME=0.5\n\
+             MYAPP_AUDIO_MUTED=false\n"
+        );
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_prefs_path_is_directory() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"), @PreferencesKey("volume"))]
+        struct Volume(f32);
+
+        let dir = crate::tests::test_dir("path_is_directory");
+        let mut world = crate::tests::test_world(&dir);
+        world.init_resource::<Events<PreferencesError>>();
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<Volume>();
+        world.insert_resource(Volume(0.5));
+        std::fs::create_dir_all(dir.join("prefs.toml")).unwrap();
+
+        SavePreferences::Always.apply(&mut world);
+        crate::load::load_preferences(&mut world);
+        let events = world.resource::<Events<PreferencesError>>();
+        let errors: Vec<_> = events.iter_current_update_events().collect();
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(
+            |e| matches!(e, PreferencesError::PathIsDirectory(path) if path.ends_with("prefs.toml"))
+        ));
+        assert!(dir.join("prefs.toml").is_dir());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_failed_rename_removes_remaining_temp_files() {
+        let dir = crate::tests::test_dir("failed_rename");
+        let world = crate::tests::test_world(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let staged = ["prefs.toml", "prefs.player1.toml", "prefs.player2.toml"]
+            .into_iter()
+            .map(|file_name| {
+                stage_file(&world, file_name, b"[audio]\nvolume = 0.5\n")
+                    .unwrap()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let renames = std::cell::Cell::new(0);
+        let result = commit_staged(&staged, |from, to| {
+            renames.set(renames.get() + 1);
+            if renames.get() > 1 {
+                return Err(io::Error::other("injected failure"));
+            }
+            fs::rename(from, to)
+        });
+        assert!(matches!(
+            result,
+            Err(PreferencesError::WriteFailed { path, .. }) if path.ends_with("prefs.player1.toml")
+        ));
+        assert!(dir.join("prefs.toml").is_file());
+        assert!(!dir.join("prefs.player1.toml").exists());
+        assert!(!dir.join("prefs.player2.toml").exists());
+        let mut files = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, ["prefs.toml"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_failed_profile_write_keeps_main_file() {
+        #[derive(Resource, Reflect)]
+        struct Volume(f32);
+
+        #[derive(Resource, Reflect)]
+        struct Controls(String);
+
+        let dir = crate::tests::test_dir("failed_profile_write");
+        let mut world = crate::tests::test_world(&dir);
+        world.init_resource::<Events<PreferencesError>>();
+        {
+            let registry = world.resource::<AppTypeRegistry>().clone();
+            let mut registry = registry.write();
+            registry.register::<Volume>();
+            registry.register::<Controls>();
+        }
+        let mut registrations = PreferencesRegistrations::default();
+        registrations.register(
+            crate::PreferenceRegistration::of::<Volume>()
+                .with_group("audio")
+                .with_key("volume"),
+        );
+        registrations.register(
+            crate::PreferenceRegistration::of::<Controls>()
+                .with_group("controls")
+                .with_key("scheme")
+                .with_profile("player1"),
+        );
+        world.insert_resource(registrations);
+        world.insert_resource(Volume(0.5));
+        world.insert_resource(Controls("keyboard".to_string()));
+        std::fs::create_dir_all(dir.join("prefs.player1.toml")).unwrap();
+        std::fs::write(dir.join("prefs.toml"), "[audio]\nvolume = 1.0\n").unwrap();
+
+        SavePreferences::Always.apply(&mut world);
+        let events = world.resource::<Events<PreferencesError>>();
+        let errors: Vec<_> = events.iter_current_update_events().collect();
+        assert!(matches!(errors[..], [PreferencesError::PathIsDirectory(_)]));
+        let main = std::fs::read_to_string(dir.join("prefs.toml")).unwrap();
+        assert_eq!(main, "[audio]\nvolume = 1.0\n");
+        assert!(!dir.join("prefs.toml.new").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_temp_file_write_failed() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"), @PreferencesKey("volume"))]
+        struct Volume(f32);
+
+        let dir = crate::tests::test_dir("temp_write_failed");
+        let mut world = crate::tests::test_world(&dir);
+        world.init_resource::<Events<PreferencesError>>();
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<Volume>();
+        world.insert_resource(Volume(0.5));
+        std::fs::create_dir_all(dir.join("prefs.toml.new")).unwrap();
+
+        SavePreferences::Always.apply(&mut world);
+        let events = world.resource::<Events<PreferencesError>>();
+        let errors: Vec<_> = events.iter_current_update_events().collect();
+        assert!(matches!(
+            errors[..],
+            [PreferencesError::WriteFailed { path, .. }] if path.ends_with("prefs.toml.new")
+        ));
+        assert!(!dir.join("prefs.toml").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_prefs_dir_parent_is_file() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"), @PreferencesKey("volume"))]
+        struct Volume(f32);
+
+        let root = crate::tests::test_dir("parent_is_file");
+        let mut world = crate::tests::test_world(&root.join("config").join("my_game"));
+        world.init_resource::<Events<PreferencesError>>();
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<Volume>();
+        world.insert_resource(Volume(0.5));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("config"), "").unwrap();
+
+        SavePreferences::Always.apply(&mut world);
+        let events = world.resource::<Events<PreferencesError>>();
+        let errors: Vec<_> = events.iter_current_update_events().collect();
+        assert!(matches!(
+            errors[..],
+            [PreferencesError::PathComponentIsFile { file, .. }] if *file == root.join("config")
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_save_namespaces() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"), @PreferencesKey("volume"))]
+        struct Volume(f32);
+
+        let dir = crate::tests::test_dir("namespaces");
+        let mut editor = crate::tests::test_world(&dir);
+        let mut game = crate::tests::test_world(&dir);
+        for (world, namespace, volume) in [(&mut editor, "editor", 0.5), (&mut game, "game", 0.25)]
+        {
+            world
+                .resource::<AppTypeRegistry>()
+                .write()
+                .register::<Volume>();
+            world.insert_resource(PreferencesNamespace(namespace.to_string()));
+            world.insert_resource(Volume(volume));
+            SavePreferences::Always.apply(world);
+        }
+
+        let text = std::fs::read_to_string(dir.join("prefs.toml")).unwrap();
+        let table: Table = toml::from_str(&text).unwrap();
+        assert_eq!(table["editor"]["audio"]["volume"].as_float(), Some(0.5));
+        assert_eq!(table["game"]["audio"]["volume"].as_float(), Some(0.25));
+
+        editor.insert_resource(Volume(0.0));
+        crate::load::load_preferences(&mut editor);
+        assert_eq!(editor.resource::<Volume>().0, 0.5);
+        game.insert_resource(Volume(0.0));
+        crate::load::load_preferences(&mut game);
+        assert_eq!(game.resource::<Volume>().0, 0.25);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_max_size() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("history"), @PreferencesKey("recent"))]
+        struct Recent(Vec<String>);
+
+        let dir = crate::tests::test_dir("max_size");
+        let mut world = crate::tests::test_world(&dir);
+        world.init_resource::<Events<PreferencesError>>();
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<Recent>();
+        world.insert_resource(Recent(vec!["file.txt".to_string(); 100]));
+        let size = dump_preferences(&world).len();
+        assert!(matches!(
+            PreferencesMaxSize::warn(64).check(size),
+            Err(PreferencesError::TooLarge { max: 64, .. })
+        ));
+
+        world.insert_resource(PreferencesMaxSize::warn(64));
+        SavePreferences::Always.apply(&mut world);
+        assert!(dir.join("prefs.toml").exists());
+        std::fs::remove_file(dir.join("prefs.toml")).unwrap();
+
+        world.insert_resource(PreferencesMaxSize::strict(64));
+        SavePreferences::Always.apply(&mut world);
+        assert!(!dir.join("prefs.toml").exists());
+        let events = world.resource::<Events<PreferencesError>>();
+        let errors: Vec<_> = events.iter_current_update_events().collect();
+        assert!(
+            matches!(errors[..], [PreferencesError::TooLarge { size: actual, max: 64 }] if *actual == size)
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_skip_unchanged_file() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"), @PreferencesKey("volume"))]
+        struct Volume(f32);
+
+        let dir = crate::tests::test_dir("skip_unchanged");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<Volume>();
+        world.insert_resource(Volume(0.5));
+        SavePreferences::Always.apply(&mut world);
+
+        let prefs_file = dir.join("prefs.toml");
+        let marked = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        let modified = || std::fs::metadata(&prefs_file).unwrap().modified().unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&prefs_file)
+            .unwrap()
+            .set_modified(marked)
+            .unwrap();
+        SavePreferences::Always.apply(&mut world);
+        assert_eq!(modified(), marked);
+
+        world.insert_resource(Volume(0.25));
+        SavePreferences::Always.apply(&mut world);
+        assert_ne!(modified(), marked);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_random_temp_suffix() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"), @PreferencesKey("volume"))]
+        struct Volume(f32);
+
+        let strategy = PreferencesTempStrategy::RandomSuffix;
+        let dir = crate::tests::test_dir("random_temp_suffix");
+        assert_ne!(
+            strategy.temp_path(&dir, "prefs.toml"),
+            strategy.temp_path(&dir, "prefs.toml")
+        );
+
+        // Two processes sharing the directory, saving at the same time.
+        let mut worlds = [0.25, 0.5].map(|volume| {
+            let mut world = crate::tests::test_world(&dir);
+            world
+                .resource::<AppTypeRegistry>()
+                .write()
+                .register::<Volume>();
+            world.insert_resource(strategy.clone());
+            world.insert_resource(Volume(volume));
+            world
+        });
+        std::thread::scope(|scope| {
+            for world in &mut worlds {
+                scope.spawn(|| {
+                    for _ in 0..20 {
+                        SavePreferences::Always.apply(world);
+                        fs::remove_file(dir.join("prefs.toml")).ok();
+                    }
+                    SavePreferences::Always.apply(world);
+                });
+            }
+        });
+
+        let contents = fs::read_to_string(dir.join("prefs.toml")).unwrap();
+        assert!(
+            contents == "[audio]\nvolume = 0.25\n" || contents == "[audio]\nvolume = 0.5\n",
+            "{}",
+            contents
+        );
+        let leftover = fs::read_dir(&dir)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("new".as_ref()))
+            .count();
+        assert_eq!(leftover, 0);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_save_changed_since_load() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"))]
+        struct AudioSettings {
+            volume: f32,
+            device: String,
+        }
+
+        let dir = crate::tests::test_dir("changed_since_load");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<AudioSettings>();
+        world.insert_resource(PreferencesSaveMode::ChangedSinceLoad);
+        world.insert_resource(AudioSettings {
+            volume: 1.0,
+            device: String::new(),
+        });
+        let prefs_file = dir.join("prefs.toml");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            &prefs_file,
+            "[audio]\ndevice = \"speakers\"\nvolume = 0.5\n",
+        )
+        .unwrap();
+        crate::load::load_preferences(&mut world);
+
+        // The file is edited by hand while the app is running, and then one field is changed.
+        fs::write(
+            &prefs_file,
+            "[audio]\ndevice = \"headphones\"\nvolume = 0.5\n",
+        )
+        .unwrap();
+        world.resource_mut::<AudioSettings>().volume = 0.25;
+        SavePreferences::Always.apply(&mut world);
+        assert_eq!(
+            fs::read_to_string(&prefs_file).unwrap(),
+            "[audio]\ndevice = \"headphones\"\nvolume = 0.25\n"
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_pre_write_hook() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"), @PreferencesKey("volume"))]
+        struct Volume(f32);
+
+        let dir = crate::tests::test_dir("pre_write");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<Volume>();
+        world.insert_resource(Volume(0.5));
+        world.insert_resource(PreferencesPreWrite(Box::new(|table| {
+            table.insert("saved_by".to_string(), toml::Value::String("test".into()));
+        })));
+        SavePreferences::Always.apply(&mut world);
+
+        assert_eq!(
+            fs::read_to_string(dir.join("prefs.toml")).unwrap(),
+            "saved_by = \"test\"\n\n[audio]\nvolume = 0.5\n"
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_save_app_preferences() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"), @PreferencesKey("volume"))]
+        struct Volume(f32);
+
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("render"), @PreferencesKey("msaa"))]
+        struct Msaa(u32);
+
+        #[derive(AppLabel, Debug, Clone, PartialEq, Eq, Hash)]
+        struct RenderApp;
+
+        let dir = crate::tests::test_dir("sub_apps");
+        let mut app = App::new();
+        app.register_type::<Volume>()
+            .insert_resource(Volume(0.5))
+            .insert_resource(PreferencesDir(dir.clone()))
+            .insert_resource(PreferencesSubApps(vec![RenderApp.intern()]));
+        let mut render_app = SubApp::new();
+        render_app
+            .init_resource::<AppTypeRegistry>()
+            .register_type::<Msaa>()
+            .insert_resource(Msaa(4));
+        app.insert_sub_app(RenderApp, render_app);
+
+        save_app_preferences(&mut app);
+        assert_eq!(
+            fs::read_to_string(dir.join("prefs.toml")).unwrap(),
+            "[audio]\nvolume = 0.5\n\n[render]\nmsaa = 4\n"
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_include_if() {
+        #[derive(Resource, Reflect)]
+        struct NetworkSettings(String);
+
+        #[derive(Resource)]
+        struct Multiplayer;
+
+        let dir = crate::tests::test_dir("include_if");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<NetworkSettings>();
+        let mut registrations = PreferencesRegistrations::default();
+        registrations.register(
+            crate::PreferenceRegistration::of::<NetworkSettings>()
+                .with_group("network")
+                .with_key("server")
+                .with_include_if(|world| world.contains_resource::<Multiplayer>()),
+        );
+        world.insert_resource(registrations);
+        world.insert_resource(NetworkSettings("localhost".to_string()));
+
+        assert!(build_preferences_table(&world).is_empty());
+
+        world.insert_resource(Multiplayer);
+        let table = build_preferences_table(&world);
+        assert_eq!(table["network"]["server"].as_str(), Some("localhost"));
+    }
+
+    #[test]
+    fn test_skipped_resources() {
+        #[derive(Resource, Reflect)]
+        struct Volume(f32);
+
+        let dir = crate::tests::test_dir("skipped_resources");
+        let mut world = crate::tests::test_world(&dir);
+        let mut registrations = PreferencesRegistrations::default();
+        registrations.register(
+            crate::PreferenceRegistration::of::<Volume>()
+                .with_group("audio")
+                .with_key("volume"),
+        );
+        world.insert_resource(registrations);
+        world.insert_resource(Volume(0.5));
+
+        let document = build_profile_document(&world, None);
+        assert!(document.table.is_empty());
+        assert_eq!(document.skipped.len(), 1);
+        assert!(document.skipped[0].ends_with("Volume (not in the type registry)"));
+    }
+
+    #[test]
+    fn test_header() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"))]
+        struct AudioSettings {
+            volume: f32,
+        }
+
+        let dir = crate::tests::test_dir("header");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<AudioSettings>();
+        world.insert_resource(AudioSettings { volume: 0.5 });
+        world.insert_resource(PreferencesHeader(
+            "Generated by MyApp \u{2014} do not edit while the app is running".to_string(),
+        ));
+        SavePreferences::Always.apply(&mut world);
+        assert_eq!(
+            fs::read_to_string(dir.join("prefs.toml")).unwrap(),
+            "# Generated by MyApp \u{2014} do not edit while the app is running\n\
+             \n\
+             [audio]\n\
+             volume = 0.5\n"
+        );
+
+        world.insert_resource(AudioSettings { volume: 1.0 });
+        crate::load::load_preferences(&mut world);
+        assert_eq!(world.resource::<AudioSettings>().volume, 0.5);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_catch_panics() {
+        #[derive(Reflect)]
+        struct Meters(f32);
+
+        // Tuple struct fields aren't supported yet, so saving this panics, after its first
+        // field has been saved.
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("window"))]
+        struct WindowSettings {
+            height: u32,
+            width: Meters,
+        }
+
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("window"))]
+        struct WindowTitle {
+            title: String,
+        }
+
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"))]
+        struct AudioSettings {
+            volume: f32,
+        }
+
+        let dir = crate::tests::test_dir("catch_panics");
+        let mut world = crate::tests::test_world(&dir);
+        world.init_resource::<Events<PreferencesError>>();
+        {
+            let mut registry = world.resource::<AppTypeRegistry>().write();
+            registry.register::<WindowSettings>();
+            registry.register::<WindowTitle>();
+            registry.register::<AudioSettings>();
+        }
+        world.insert_resource(WindowSettings {
+            height: 600,
+            width: Meters(1.5),
+        });
+        world.insert_resource(WindowTitle {
+            title: "Editor".to_string(),
+        });
+        world.insert_resource(AudioSettings { volume: 0.5 });
+        world.insert_resource(PreferencesCatchPanics(true));
+        SavePreferences::Always.apply(&mut world);
+
+        // The other resource in the same group is kept, without the partly saved one.
+        assert_eq!(
+            fs::read_to_string(dir.join("prefs.toml")).unwrap(),
+            "[audio]\nvolume = 0.5\n\n[window]\ntitle = \"Editor\"\n"
+        );
+        let events = world.resource::<Events<PreferencesError>>();
+        let errors: Vec<_> = events.iter_current_update_events().collect();
+        assert!(matches!(
+            errors[..],
+            [PreferencesError::SerializationPanicked { resource, .. }]
+                if resource.ends_with("WindowSettings")
+        ));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_store_prop_option_some() {
+        let mut table = Table::new();
+        let value: &dyn PartialReflect = &Some(42i32);
+        store_prop(value, "test_option", &mut table, EncodeContext::default());
+        assert_eq!(table.get("test_option").unwrap().as_integer().unwrap(), 42);
+    }
+
+    #[test]
+    fn test_store_prop_option_none() {
+        let mut table = Table::new();
+        let value: &dyn PartialReflect = &Option::<i32>::None;
+        store_prop(value, "test_option", &mut table, EncodeContext::default());
+        assert!(table.get("test_option").is_none());
+    }
+
+    #[cfg(feature = "unicode-normalization")]
+    #[test]
+    fn test_save_normalizes_group_and_key() {
+        #[derive(Reflect)]
+        struct Volume(f32);
+
+        let mut table = Table::new();
+        maybe_save_tuple_struct(
+            &Volume(0.5),
+            Some(&PreferencesGroup("cafe\u{301}")),
+            Some(&PreferencesKey("volume\u{301}")),
+            &mut table,
+            EncodeContext::default(),
+        );
+        let group = table.get("caf\u{e9}").unwrap().as_table().unwrap();
+        assert_eq!(group.get("volum\u{e9}").unwrap().as_float().unwrap(), 0.5);
+        assert!(table.get("cafe\u{301}").is_none());
+    }
+
+    #[test]
+    fn test_verify_writes() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"))]
+        struct AudioSettings {
+            volume: f32,
+        }
+
+        let dir = crate::tests::test_dir("verify_writes");
+        let mut world = crate::tests::test_world(&dir);
+        world.init_resource::<Events<PreferencesError>>();
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<AudioSettings>();
+        world.insert_resource(AudioSettings { volume: 0.5 });
+        world.insert_resource(PreferencesVerifyWrites(true));
+        SavePreferences::Always.apply(&mut world);
+        assert!(world.resource::<Events<PreferencesError>>().is_empty());
+
+        // Simulate the file being corrupted on its way to the disk.
+        let format = PreferencesFileFormat::default();
+        let mut table = Table::new();
+        table.insert(
+            "audio".to_string(),
+            toml::Value::Table(toml::toml! { volume = 0.5 }),
+        );
+        assert!(verify_file(&world, &format, "prefs.toml", &table).is_ok());
+        fs::write(dir.join("prefs.toml"), "[audio]\nvolume = 0.25\n").unwrap();
+        assert!(matches!(
+            verify_file(&world, &format, "prefs.toml", &table),
+            Err(PreferencesError::VerificationFailed(path)) if path == dir.join("prefs.toml")
+        ));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_backup_rotation() {
+        #[derive(Resource, Reflect)]
+        #[reflect(@PreferencesGroup("audio"))]
+        struct AudioSettings {
+            volume: f32,
+        }
+
+        let dir = crate::tests::test_dir("backup_rotation");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<AudioSettings>();
+        world.insert_resource(PreferencesBackups(2));
+        world.insert_resource(PreferencesIntegrity::Crc32);
+        for volume in [0.25, 0.5, 0.75, 1.0] {
+            world.insert_resource(AudioSettings { volume });
+            SavePreferences::Always.apply(&mut world);
+        }
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("prefs.toml"), "[audio]\nvolume = 1.0\n");
+        assert_eq!(read("prefs.toml.bak"), "[audio]\nvolume = 0.75\n");
+        assert_eq!(read("prefs.toml.bak.1"), "[audio]\nvolume = 0.5\n");
+        assert!(!dir.join("prefs.toml.bak.2").exists());
+        assert!(dir.join("prefs.toml.bak.1.crc32").exists());
+
+        // A save which doesn't change the file leaves the backups alone.
+        SavePreferences::Always.apply(&mut world);
+        assert_eq!(read("prefs.toml.bak"), "[audio]\nvolume = 0.75\n");
+
+        // A corrupted file is recovered from the newest backup, checked against its own checksum.
+        fs::write(dir.join("prefs.toml"), "[audio]\nvolume = 0.0\n").unwrap();
+        crate::load::load_preferences(&mut world);
+        assert_eq!(world.resource::<AudioSettings>().volume, 0.75);
+        let _ = fs::remove_dir_all(&dir);
     }
 }
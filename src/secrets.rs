@@ -0,0 +1,199 @@
+use std::sync::Arc;
+
+use bevy::{
+    prelude::*,
+    reflect::{ReflectMut, StructInfo},
+};
+
+use crate::{
+    keys::{group_path, normalize_key},
+    PreferencesGroup, PreferencesSecret,
+};
+
+/// Storage for preferences which are too sensitive to be written to the preferences file.
+pub trait SecretStore: Send + Sync + 'static {
+    /// Look up a secret by name, returning `None` if it hasn't been stored.
+    fn get(&self, name: &str) -> Option<String>;
+
+    /// Store a secret, replacing any previous value.
+    fn set(&self, name: &str, secret: &str);
+}
+
+/// Resource which selects where fields annotated with [`PreferencesSecret`] are stored. If
+/// there is no such resource, secret fields are neither saved nor loaded.
+#[derive(Resource, Clone)]
+pub struct PreferencesSecrets(pub Arc<dyn SecretStore>);
+
+impl PreferencesSecrets {
+    pub fn new(store: impl SecretStore) -> Self {
+        Self(Arc::new(store))
+    }
+}
+
+/// Stores secrets in the operating system's keyring, under the given service name (usually
+/// the app name).
+#[cfg(feature = "keyring")]
+#[derive(Debug, Clone)]
+pub struct KeyringStore {
+    service: String,
+}
+
+#[cfg(feature = "keyring")]
+impl KeyringStore {
+    pub fn new(service: &str) -> Self {
+        Self {
+            service: service.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "keyring")]
+impl SecretStore for KeyringStore {
+    fn get(&self, name: &str) -> Option<String> {
+        match keyring::Entry::new(&self.service, name).and_then(|entry| entry.get_password()) {
+            Ok(secret) => Some(secret),
+            Err(keyring::Error::NoEntry) => None,
+            Err(e) => {
+                warn!("Could not read secret {} from keyring: {}", name, e);
+                None
+            }
+        }
+    }
+
+    fn set(&self, name: &str, secret: &str) {
+        if let Err(e) =
+            keyring::Entry::new(&self.service, name).and_then(|entry| entry.set_password(secret))
+        {
+            warn!("Could not write secret {} to keyring: {}", name, e);
+        }
+    }
+}
+
+/// The names of the secret fields of a struct, along with the name each one is stored under,
+/// which is its path in the preferences document joined with dots, e.g. `network.api_token`.
+pub(crate) fn secret_fields(
+    stty: &StructInfo,
+    group_attr: Option<&PreferencesGroup>,
+) -> Vec<(&'static str, String)> {
+    stty.iter()
+        .filter(|field| field.custom_attributes().contains::<PreferencesSecret>())
+        .map(|field| {
+            let name = group_attr
+                .into_iter()
+                .flat_map(|group| group_path(group.0))
+                .chain([normalize_key(field.name())])
+                .collect::<Vec<_>>()
+                .join(".");
+            (field.name(), name)
+        })
+        .collect()
+}
+
+/// Load the secret fields of a struct from the secret store. Within a namespace, the names
+/// of the secrets start with the namespace.
+pub(crate) fn load_secrets(
+    store: &dyn SecretStore,
+    namespace: Option<&str>,
+    stty: &StructInfo,
+    group_attr: Option<&PreferencesGroup>,
+    value: &mut dyn Reflect,
+) {
+    let ReflectMut::Struct(strct) = value.reflect_mut() else {
+        return;
+    };
+    for (field_name, mut name) in secret_fields(stty, group_attr) {
+        if let Some(namespace) = namespace {
+            name = format!("{}.{}", namespace, name);
+        }
+        let Some(secret) = store.get(&name) else {
+            continue;
+        };
+        match strct
+            .field_mut(field_name)
+            .and_then(|field| field.try_downcast_mut::<String>())
+        {
+            Some(field) => *field = secret,
+            None => warn!("Preferences: Secret field {} must be a String", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use bevy::{
+        ecs::world::Command,
+        reflect::{TypeInfo, Typed},
+        utils::HashMap,
+    };
+
+    use super::*;
+
+    /// In-memory stand-in for the OS keyring.
+    #[derive(Default)]
+    struct MockSecretStore(Mutex<HashMap<String, String>>);
+
+    impl SecretStore for Arc<MockSecretStore> {
+        fn get(&self, name: &str) -> Option<String> {
+            self.0.lock().unwrap().get(name).cloned()
+        }
+
+        fn set(&self, name: &str, secret: &str) {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(name.to_string(), secret.to_string());
+        }
+    }
+
+    #[derive(Resource, Reflect)]
+    #[reflect(@PreferencesGroup("network"))]
+    struct NetworkSettings {
+        server: String,
+        #[reflect(@PreferencesSecret)]
+        api_token: String,
+    }
+
+    #[test]
+    fn test_secret_fields() {
+        let TypeInfo::Struct(stty) = NetworkSettings::type_info() else {
+            panic!("Expected Struct");
+        };
+        assert_eq!(
+            secret_fields(stty, Some(&PreferencesGroup("game/network"))),
+            [("api_token", "game.network.api_token".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_secrets_round_trip() {
+        let dir = crate::tests::test_dir("secrets");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<NetworkSettings>();
+        let store = Arc::new(MockSecretStore::default());
+        world.insert_resource(PreferencesSecrets::new(store.clone()));
+        world.insert_resource(NetworkSettings {
+            server: "example.com".to_string(),
+            api_token: "hunter2".to_string(),
+        });
+        crate::SavePreferences::Always.apply(&mut world);
+
+        let text = std::fs::read_to_string(dir.join("prefs.toml")).unwrap();
+        assert_eq!(text, "[network]\nserver = \"example.com\"\n");
+        assert_eq!(store.get("network.api_token").as_deref(), Some("hunter2"));
+
+        world.insert_resource(NetworkSettings {
+            server: String::new(),
+            api_token: String::new(),
+        });
+        crate::load::load_preferences(&mut world);
+        let network = world.resource::<NetworkSettings>();
+        assert_eq!(network.server, "example.com");
+        assert_eq!(network.api_token, "hunter2");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
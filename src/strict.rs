@@ -0,0 +1,113 @@
+use bevy::{prelude::*, reflect::TypeInfo};
+
+use crate::{
+    keys::normalize_key,
+    registration::{
+        in_profile, is_transparent_wrapper, preference_names, state_preference_names,
+        PreferencesRegistrations,
+    },
+    save::key_path,
+    timestamps::PreferencesTimestamps,
+    PreferencesGroup, PreferencesKey, PreferencesSkip,
+};
+
+/// Find the keys and groups in a preferences document which don't belong to any preference
+/// resource of the given profile, such as a misspelled key in a file of bundled defaults. Each
+/// is returned as its dotted path, e.g. `audio.volumme`. Values nested within a known key
+/// aren't checked.
+pub(crate) fn unknown_keys(
+    world: &World,
+    table: &toml::Table,
+    profile: Option<&str>,
+) -> Vec<String> {
+    let known = known_paths(world, profile);
+    let mut unknown = Vec::new();
+    find_unknown(table, &known, &mut Vec::new(), &mut unknown);
+    unknown
+}
+
+/// The paths of all of the values which the preference resources of the profile load.
+fn known_paths(world: &World, profile: Option<&str>) -> Vec<Vec<String>> {
+    let registry = world.resource::<AppTypeRegistry>().read();
+    let registrations = world.get_resource::<PreferencesRegistrations>();
+    let mut known = Vec::new();
+    if let Some(timestamps) = world.get_resource::<PreferencesTimestamps>() {
+        known.push(vec![timestamps.0.clone()]);
+    }
+    for (res, _) in world.iter_resources() {
+        let Some(tid) = res.type_id() else {
+            continue;
+        };
+        let Some(treg) = registry.get(tid) else {
+            continue;
+        };
+        if !in_profile(registrations, tid, profile) {
+            continue;
+        }
+        let (group_attr, key_attr) = preference_names(registrations, treg.type_info());
+        match treg.type_info() {
+            TypeInfo::TupleStruct(tsty) if is_transparent_wrapper(tsty) => {
+                let Some(inner_info) = tsty
+                    .field_at(0)
+                    .and_then(|field| registry.get_type_info(field.type_id()))
+                else {
+                    continue;
+                };
+                let (group_attr, key_attr) = state_preference_names(registrations, tid, inner_info);
+                push_known_paths(inner_info, group_attr, key_attr, &mut known);
+            }
+            TypeInfo::Struct(_) | TypeInfo::TupleStruct(_) => {
+                push_known_paths(treg.type_info(), group_attr, key_attr, &mut known);
+            }
+            _ => {}
+        }
+    }
+    known
+}
+
+/// Add the paths of the values stored for a type under the given group and key: each field
+/// of a struct in a group, or otherwise the single value under the key.
+fn push_known_paths(
+    info: &TypeInfo,
+    group_attr: Option<&PreferencesGroup>,
+    key_attr: Option<&PreferencesKey>,
+    known: &mut Vec<Vec<String>>,
+) {
+    match (info, group_attr) {
+        (TypeInfo::Struct(stty), Some(group)) => {
+            for field in stty.iter() {
+                if field.custom_attributes().contains::<PreferencesSkip>() {
+                    continue;
+                }
+                let mut path = key_path(Some(group), None);
+                path.push(normalize_key(field.name()).into_owned());
+                known.push(path);
+            }
+        }
+        _ if group_attr.is_some() || key_attr.is_some() => {
+            known.push(key_path(group_attr, key_attr));
+        }
+        _ => {}
+    }
+}
+
+fn find_unknown(
+    table: &toml::Table,
+    known: &[Vec<String>],
+    prefix: &mut Vec<String>,
+    unknown: &mut Vec<String>,
+) {
+    for (key, value) in table {
+        prefix.push(key.clone());
+        // The contents of a known value, such as a nested struct, aren't checked.
+        if !known.contains(prefix) {
+            match value {
+                toml::Value::Table(inner) if known.iter().any(|path| path.starts_with(prefix)) => {
+                    find_unknown(inner, known, prefix, unknown);
+                }
+                _ => unknown.push(prefix.join(".")),
+            }
+        }
+        prefix.pop();
+    }
+}
@@ -0,0 +1,196 @@
+use bevy::{
+    prelude::*,
+    reflect::{TypeInfo, TypeRegistry, VariantInfo},
+};
+
+use crate::registration::{
+    is_transparent_wrapper, preference_names, state_preference_names, PreferencesRegistrations,
+};
+
+/// Type paths of the primitive types which can be stored as preference values.
+const SUPPORTED_TYPES: &[&str] = &[
+    "bool",
+    "i8",
+    "i16",
+    "i32",
+    "i64",
+    "u8",
+    "u16",
+    "u32",
+    "u64",
+    "usize",
+    "f32",
+    "f64",
+    "alloc::string::String",
+    "alloc::sync::Arc<str>",
+    "alloc::sync::Arc<alloc::string::String>",
+    "core::time::Duration",
+];
+
+/// The type paths of the primitive types which this build can store as preference values.
+/// Structs, arrays, lists and enums made of these types are also supported; use
+/// [`is_supported_preference_type`] to check a whole type.
+pub fn supported_preference_types() -> &'static [&'static str] {
+    SUPPORTED_TYPES
+}
+
+/// Check whether a type can be saved and loaded as a preference resource, e.g. so that an
+/// audit tool can flag fields which would otherwise be silently left out of the preferences
+/// file. A preference resource is a struct, a single-field tuple struct, or an enum of unit
+/// variants (for states). The types of its fields must be registered in `registry`.
+pub fn is_supported_preference_type(registry: &TypeRegistry, type_info: &TypeInfo) -> bool {
+    match type_info {
+        TypeInfo::Struct(stty) => stty
+            .iter()
+            .all(|field| is_supported_value(registry, field.type_id())),
+        TypeInfo::TupleStruct(tsty) => {
+            tsty.field_len() == 1
+                && is_supported_value(registry, tsty.field_at(0).unwrap().type_id())
+        }
+        TypeInfo::Enum(enty) => enty
+            .iter()
+            .all(|variant| matches!(variant, VariantInfo::Unit(_))),
+        _ => false,
+    }
+}
+
+/// List the names of the variants of the enum preference stored under the given group and key,
+/// e.g. to fill a dropdown in a settings screen. The group and key are the ones the preference
+/// was annotated or registered with; for a struct in a group, the key is the name of the field.
+/// Pass an empty group for a top-level key. Returns `None` if there is no such preference, or
+/// if it isn't an enum.
+pub fn enum_variants(world: &World, group: &str, key: &str) -> Option<Vec<String>> {
+    let registry = world.resource::<AppTypeRegistry>().read();
+    let registrations = world.get_resource::<PreferencesRegistrations>();
+    let variants = registry.iter().find_map(|treg| {
+        let (info, group_attr, key_attr) = match treg.type_info() {
+            TypeInfo::TupleStruct(tsty) if is_transparent_wrapper(tsty) => {
+                let inner = registry.get_type_info(tsty.field_at(0)?.type_id())?;
+                let (group_attr, key_attr) =
+                    state_preference_names(registrations, treg.type_id(), inner);
+                (inner, group_attr, key_attr)
+            }
+            info => {
+                let (group_attr, key_attr) = preference_names(registrations, info);
+                (info, group_attr, key_attr)
+            }
+        };
+        if group_attr.map_or("", |group| group.0) != group {
+            return None;
+        }
+        let value_type = match (info, key_attr) {
+            (TypeInfo::Enum(_), Some(key_attr)) if key_attr.0 == key => info,
+            (TypeInfo::TupleStruct(tsty), Some(key_attr)) if key_attr.0 == key => {
+                registry.get_type_info(tsty.field_at(0)?.type_id())?
+            }
+            (TypeInfo::Struct(stty), None) => registry.get_type_info(stty.field(key)?.type_id())?,
+            _ => return None,
+        };
+        match value_type {
+            TypeInfo::Enum(enty) => Some(
+                enty.variant_names()
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect(),
+            ),
+            _ => None,
+        }
+    });
+    variants
+}
+
+/// Check whether a value of the given type can be stored within a preference resource.
+fn is_supported_value(registry: &TypeRegistry, type_id: std::any::TypeId) -> bool {
+    let Some(type_info) = registry.get_type_info(type_id) else {
+        return false;
+    };
+    match type_info {
+        TypeInfo::Opaque(_) => SUPPORTED_TYPES.contains(&type_info.type_path()),
+        TypeInfo::Struct(stty) => stty
+            .iter()
+            .all(|field| is_supported_value(registry, field.type_id())),
+        TypeInfo::Array(arty) => is_supported_value(registry, arty.item_ty().id()),
+        TypeInfo::List(lsty) => is_supported_value(registry, lsty.item_ty().id()),
+        // `Option` is stored as its `Some` value, and other enums as either the name of a unit
+        // variant or a table holding the value of a newtype variant.
+        TypeInfo::Enum(enty) => enty.iter().all(|variant| match variant {
+            VariantInfo::Unit(_) => true,
+            VariantInfo::Tuple(variant) => {
+                variant.field_len() == 1
+                    && is_supported_value(registry, variant.field_at(0).unwrap().type_id())
+            }
+            VariantInfo::Struct(_) => false,
+        }),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{
+        reflect::{Reflect, TypePath, Typed},
+        utils::HashMap,
+    };
+
+    use super::*;
+
+    #[derive(Reflect)]
+    struct AudioSettings {
+        volume: f32,
+        device: Option<String>,
+        levels: [u8; 2],
+    }
+
+    #[derive(Reflect)]
+    struct RecentFiles {
+        paths: HashMap<String, u32>,
+    }
+
+    #[derive(Reflect, Default)]
+    enum Theme {
+        #[default]
+        Light,
+        Dark,
+        HighContrast,
+    }
+
+    #[derive(Resource, Reflect)]
+    #[reflect(@crate::PreferencesGroup("ui"))]
+    struct UiSettings {
+        theme: Theme,
+        scale: f32,
+    }
+
+    #[test]
+    fn test_enum_variants() {
+        let mut world = World::new();
+        world.init_resource::<AppTypeRegistry>();
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<UiSettings>();
+        assert_eq!(
+            enum_variants(&world, "ui", "theme").unwrap(),
+            ["Light", "Dark", "HighContrast"]
+        );
+        assert_eq!(enum_variants(&world, "ui", "scale"), None);
+        assert_eq!(enum_variants(&world, "audio", "theme"), None);
+    }
+
+    #[test]
+    fn test_supported_preference_types() {
+        assert!(supported_preference_types().contains(&String::type_path()));
+
+        let mut registry = TypeRegistry::new();
+        registry.register::<AudioSettings>();
+        registry.register::<RecentFiles>();
+        assert!(is_supported_preference_type(
+            &registry,
+            AudioSettings::type_info()
+        ));
+        assert!(!is_supported_preference_type(
+            &registry,
+            RecentFiles::type_info()
+        ));
+    }
+}
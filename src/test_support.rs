@@ -0,0 +1,169 @@
+//! Helpers for testing an app's preferences end-to-end, without touching the user's real
+//! preferences directory.
+
+use std::path::{Path, PathBuf};
+
+use bevy::{ecs::world::Command, prelude::*, reflect::GetTypeRegistration, time::TimePlugin};
+
+use crate::{
+    load::load_preferences, PreferencesAppExt, PreferencesDir, PreferencesFileFormat,
+    PreferencesPlugin, SavePreferences,
+};
+
+/// An [`App`] with the [`PreferencesPlugin`] installed, which keeps its preferences in a scratch
+/// directory that is removed again when the test app is dropped.
+///
+/// ```ignore
+/// let mut app = PreferencesTestApp::new("my_test")
+///     .with_file("[audio]\nvolume = 0.5\n")
+///     .with_preference::<AudioSettings>()
+///     .start();
+/// assert_eq!(app.world().resource::<AudioSettings>().volume, 0.5);
+/// app.world_mut().resource_mut::<AudioSettings>().volume = 0.25;
+/// app.save();
+/// assert_eq!(app.file_contents().unwrap(), "[audio]\nvolume = 0.25\n");
+/// ```
+pub struct PreferencesTestApp {
+    app: App,
+    dir: PathBuf,
+}
+
+impl PreferencesTestApp {
+    /// Create the app, with an empty preferences directory named after the test.
+    pub fn new(name: &str) -> Self {
+        Self::with_plugin(name, PreferencesPlugin::new(name))
+    }
+
+    /// Create the app with a customized plugin, e.g. one which uses a different format.
+    pub fn with_plugin(name: &str, plugin: PreferencesPlugin) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "bevy_basic_prefs_test_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut app = App::new();
+        app.add_plugins((TimePlugin, plugin))
+            .insert_resource(PreferencesDir(dir.clone()));
+        Self { app, dir }
+    }
+
+    /// Register a preference resource, initialized to its default value.
+    pub fn with_preference<T: Resource + Default + GetTypeRegistration>(mut self) -> Self {
+        self.app.init_preference::<T>();
+        self
+    }
+
+    /// Write the main preferences file before the app starts, so that it is loaded on startup.
+    pub fn with_file(self, contents: &str) -> Self {
+        self.write_file(contents);
+        self
+    }
+
+    /// Finish setting up the app, which loads the preferences as a real app would on startup.
+    pub fn start(mut self) -> Self {
+        self.app.finish();
+        self.app.cleanup();
+        self
+    }
+
+    pub fn app(&self) -> &App {
+        &self.app
+    }
+
+    pub fn app_mut(&mut self) -> &mut App {
+        &mut self.app
+    }
+
+    pub fn world(&self) -> &World {
+        self.app.world()
+    }
+
+    pub fn world_mut(&mut self) -> &mut World {
+        self.app.world_mut()
+    }
+
+    /// Run the app's schedules once.
+    pub fn update(&mut self) -> &mut Self {
+        self.app.update();
+        self
+    }
+
+    /// Save the preferences now, as [`SavePreferences::Always`] does.
+    pub fn save(&mut self) -> &mut Self {
+        SavePreferences::Always.apply(self.app.world_mut());
+        self
+    }
+
+    /// Load the preferences from the preferences directory again.
+    pub fn load(&mut self) -> &mut Self {
+        load_preferences(self.app.world_mut());
+        self
+    }
+
+    /// The scratch directory which the preferences are kept in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Path of the main preferences file.
+    pub fn file_path(&self) -> PathBuf {
+        let format = self
+            .app
+            .world()
+            .get_resource::<PreferencesFileFormat>()
+            .cloned()
+            .unwrap_or_default();
+        self.dir.join(format.file_name())
+    }
+
+    /// The contents of the main preferences file, or `None` if it hasn't been written.
+    pub fn file_contents(&self) -> Option<String> {
+        std::fs::read_to_string(self.file_path()).ok()
+    }
+
+    /// Replace the contents of the main preferences file, e.g. to simulate a hand edit.
+    pub fn write_file(&self, contents: &str) {
+        std::fs::create_dir_all(&self.dir).unwrap();
+        std::fs::write(self.file_path(), contents).unwrap();
+    }
+}
+
+impl Drop for PreferencesTestApp {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PreferencesGroup;
+
+    #[derive(Resource, Reflect, Default)]
+    #[reflect(Default, @PreferencesGroup("audio"))]
+    struct AudioSettings {
+        volume: f32,
+    }
+
+    #[test]
+    fn test_preferences_test_app() {
+        let mut app = PreferencesTestApp::new("test_app")
+            .with_file("[audio]\nvolume = 0.5\n")
+            .with_preference::<AudioSettings>()
+            .start();
+        assert_eq!(app.world().resource::<AudioSettings>().volume, 0.5);
+
+        app.world_mut().resource_mut::<AudioSettings>().volume = 0.25;
+        app.update().save();
+        assert_eq!(app.file_contents().unwrap(), "[audio]\nvolume = 0.25\n");
+
+        app.write_file("[audio]\nvolume = 1.0\n");
+        app.load();
+        assert_eq!(app.world().resource::<AudioSettings>().volume, 1.0);
+
+        let dir = app.dir().to_path_buf();
+        drop(app);
+        assert!(!dir.exists());
+    }
+}
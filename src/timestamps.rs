@@ -0,0 +1,140 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bevy::prelude::*;
+
+/// Resource which records when each value in the preferences file last changed, e.g. to merge
+/// the preferences of several devices by keeping the latest value of each key. The times are
+/// kept in a top-level table of the given name, which maps the dotted path of each value to the
+/// time of the first save which wrote its current value, in milliseconds since the Unix epoch:
+///
+/// ```toml
+/// [_timestamps]
+/// "audio.volume" = 1760431200000
+/// ```
+///
+/// The table is not loaded into any preference.
+#[derive(Resource, Debug, Clone, PartialEq, Eq)]
+pub struct PreferencesTimestamps(pub String);
+
+impl Default for PreferencesTimestamps {
+    fn default() -> Self {
+        Self("_timestamps".to_string())
+    }
+}
+
+/// Add the timestamps table to a document which is about to be written over `existing`. Values
+/// which are the same as in the existing file keep their timestamps, and the others are stamped
+/// with the current time.
+pub(crate) fn update_timestamps(
+    world: &World,
+    table: &mut toml::Table,
+    existing: Option<&toml::Table>,
+) {
+    let Some(timestamps) = world.get_resource::<PreferencesTimestamps>() else {
+        return;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as i64);
+    let previous = existing
+        .and_then(|existing| existing.get(&timestamps.0))
+        .and_then(|previous| previous.as_table());
+    table.remove(&timestamps.0);
+    let mut stamps = toml::Table::new();
+    let mut path = Vec::new();
+    stamp_values(table, existing, previous, now, &mut path, &mut stamps);
+    table.insert(timestamps.0.clone(), toml::Value::Table(stamps));
+}
+
+fn stamp_values(
+    table: &toml::Table,
+    existing: Option<&toml::Table>,
+    previous: Option<&toml::Table>,
+    now: i64,
+    path: &mut Vec<String>,
+    stamps: &mut toml::Table,
+) {
+    for (key, value) in table {
+        let existing_value = existing.and_then(|existing| existing.get(key));
+        path.push(key.clone());
+        match value {
+            toml::Value::Table(inner) => stamp_values(
+                inner,
+                existing_value.and_then(|existing| existing.as_table()),
+                previous,
+                now,
+                path,
+                stamps,
+            ),
+            _ => {
+                let dotted = path.join(".");
+                let stamp = match previous.and_then(|previous| previous.get(&dotted)) {
+                    Some(toml::Value::Integer(stamp)) if existing_value == Some(value) => *stamp,
+                    _ => now,
+                };
+                stamps.insert(dotted, toml::Value::Integer(stamp));
+            }
+        }
+        path.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::world::Command;
+
+    use super::*;
+    use crate::{PreferencesGroup, SavePreferences};
+
+    #[derive(Resource, Reflect)]
+    #[reflect(@PreferencesGroup("audio"))]
+    struct AudioSettings {
+        volume: f32,
+        muted: bool,
+    }
+
+    fn stamps(dir: &std::path::Path) -> toml::Table {
+        let file: toml::Table = std::fs::read_to_string(dir.join("prefs.toml"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        file["_timestamps"].as_table().unwrap().clone()
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let dir = crate::tests::test_dir("timestamps");
+        let mut world = crate::tests::test_world(&dir);
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<AudioSettings>();
+        world.insert_resource(PreferencesTimestamps::default());
+        world.insert_resource(AudioSettings {
+            volume: 0.5,
+            muted: false,
+        });
+        SavePreferences::Always.apply(&mut world);
+        let first = stamps(&dir);
+        assert_eq!(first.len(), 2);
+
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        world.resource_mut::<AudioSettings>().volume = 0.25;
+        SavePreferences::Always.apply(&mut world);
+        let second = stamps(&dir);
+        assert!(
+            second["audio.volume"].as_integer().unwrap()
+                > first["audio.volume"].as_integer().unwrap()
+        );
+        assert_eq!(second["audio.muted"], first["audio.muted"]);
+
+        // The table isn't mistaken for preferences when loading.
+        world.insert_resource(crate::PreferencesLoadStrict(true));
+        world.init_resource::<Events<crate::PreferencesError>>();
+        crate::load::load_preferences(&mut world);
+        assert_eq!(world.resource::<AudioSettings>().volume, 0.25);
+        let events = world.resource::<Events<crate::PreferencesError>>();
+        assert!(events.is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,229 @@
+use std::any::TypeId;
+
+use bevy::{
+    ecs::component::ComponentId,
+    prelude::*,
+    reflect::{ReflectFromPtr, TypeInfo},
+    utils::HashSet,
+};
+
+use crate::{
+    error::{report_error, PreferencesError},
+    registration::{is_transparent_wrapper, preference_names, PreferencesRegistrations},
+};
+
+type ValidateFn = dyn Fn(&World) -> Result<(), String> + Send + Sync;
+
+/// Resource holding a check which is run once all of the preferences have been loaded, for
+/// invariants which span several values, e.g. that a minimum is no greater than a maximum.
+/// A failure is logged and sent as a [`PreferencesError::ValidationFailed`]. With
+/// [`reverting`](Self::reverting), the preference resources are also restored to the values
+/// they had before the load.
+#[derive(Resource)]
+pub struct PreferencesPostLoadValidator {
+    validate: Box<ValidateFn>,
+    revert: bool,
+}
+
+impl PreferencesPostLoadValidator {
+    pub fn new(validate: impl Fn(&World) -> Result<(), String> + Send + Sync + 'static) -> Self {
+        Self {
+            validate: Box::new(validate),
+            revert: false,
+        }
+    }
+
+    /// Restore the preference resources to their values from before the load if validation
+    /// fails.
+    pub fn reverting(mut self) -> Self {
+        self.revert = true;
+        self
+    }
+}
+
+/// The values of the preference resources before a load, so that they can be restored.
+pub(crate) struct PreferencesSnapshot {
+    values: Vec<(ComponentId, TypeId, Box<dyn PartialReflect>)>,
+}
+
+/// Copy the preference resources, if a validator may need to revert them.
+pub(crate) fn take_snapshot(world: &World) -> Option<PreferencesSnapshot> {
+    if !world
+        .get_resource::<PreferencesPostLoadValidator>()
+        .is_some_and(|validator| validator.revert)
+    {
+        return None;
+    }
+    let registry = world.resource::<AppTypeRegistry>().read();
+    let registrations = world.get_resource::<PreferencesRegistrations>();
+    let mut values = Vec::new();
+    for (res, _) in world.iter_resources() {
+        let Some(tid) = res.type_id() else {
+            continue;
+        };
+        let Some(treg) = registry.get(tid) else {
+            continue;
+        };
+        if !is_preference(registrations, treg.type_info()) {
+            continue;
+        }
+        let (Some(ptr), Some(reflect_from_ptr)) = (
+            world.get_resource_by_id(res.id()),
+            treg.data::<ReflectFromPtr>(),
+        ) else {
+            continue;
+        };
+        let value = unsafe { reflect_from_ptr.as_reflect(ptr) }.clone_value();
+        values.push((res.id(), tid, value));
+    }
+    Some(PreferencesSnapshot { values })
+}
+
+/// Run the validator, reverting to the snapshot if it fails.
+pub(crate) fn validate(world: &mut World, snapshot: Option<PreferencesSnapshot>) {
+    let Some(validator) = world.get_resource::<PreferencesPostLoadValidator>() else {
+        return;
+    };
+    let Err(message) = (validator.validate)(world) else {
+        return;
+    };
+    report_error(world, PreferencesError::ValidationFailed(message));
+    let Some(snapshot) = snapshot else {
+        return;
+    };
+    let registry = world.resource::<AppTypeRegistry>().clone();
+    let registry = registry.read();
+    let restored: HashSet<TypeId> = snapshot.values.iter().map(|(_, tid, _)| *tid).collect();
+    for (res_id, tid, value) in snapshot.values {
+        let treg = registry.get(tid).unwrap();
+        let reflect_from_ptr = treg.data::<ReflectFromPtr>().unwrap();
+        let mut ptr = world.get_resource_mut_by_id(res_id).unwrap();
+        unsafe { reflect_from_ptr.as_reflect_mut(ptr.as_mut()) }.apply(value.as_ref());
+    }
+
+    // Remove the resources which were inserted with their default values during the load.
+    let inserted: Vec<TypeId> = world
+        .iter_resources()
+        .filter_map(|(res, _)| res.type_id())
+        .filter(|tid| !restored.contains(tid))
+        .filter(|tid| {
+            registry.get(*tid).is_some_and(|treg| {
+                is_preference(
+                    world.get_resource::<PreferencesRegistrations>(),
+                    treg.type_info(),
+                )
+            })
+        })
+        .collect();
+    for tid in inserted {
+        if let Some(reflect_resource) = registry.get_type_data::<ReflectResource>(tid) {
+            reflect_resource.remove(world);
+        }
+    }
+}
+
+/// Returns true for the types which are loaded from the preferences file.
+fn is_preference(registrations: Option<&PreferencesRegistrations>, info: &TypeInfo) -> bool {
+    let (group_attr, key_attr) = preference_names(registrations, info);
+    match info {
+        TypeInfo::Struct(_) => group_attr.is_some() || key_attr.is_some(),
+        TypeInfo::TupleStruct(tsty) => {
+            is_transparent_wrapper(tsty)
+                || group_attr.is_some()
+                || key_attr.is_some()
+                || tsty
+                    .type_path()
+                    .starts_with("bevy_state::state::resources::NextState<")
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PreferencesGroup, PreferencesKey};
+
+    #[derive(Resource, Reflect)]
+    #[reflect(@PreferencesGroup("zoom"), @PreferencesKey("min"))]
+    struct MinZoom(f32);
+
+    #[derive(Resource, Reflect)]
+    #[reflect(@PreferencesGroup("zoom"), @PreferencesKey("max"))]
+    struct MaxZoom(f32);
+
+    #[test]
+    fn test_post_load_validator_reverts() {
+        let dir = crate::tests::test_dir("post_load_validator");
+        let mut world = crate::tests::test_world(&dir);
+        world.init_resource::<Events<PreferencesError>>();
+        {
+            let mut registry = world.resource::<AppTypeRegistry>().write();
+            registry.register::<MinZoom>();
+            registry.register::<MaxZoom>();
+        }
+        world.insert_resource(MinZoom(0.5));
+        world.insert_resource(MaxZoom(2.0));
+        world.insert_resource(
+            PreferencesPostLoadValidator::new(|world| {
+                let (min, max) = (world.resource::<MinZoom>().0, world.resource::<MaxZoom>().0);
+                if min <= max {
+                    Ok(())
+                } else {
+                    Err(format!("zoom.min {} is greater than zoom.max {}", min, max))
+                }
+            })
+            .reverting(),
+        );
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("prefs.toml"), "[zoom]\nmin = 4.0\nmax = 1.5\n").unwrap();
+        crate::load::load_preferences(&mut world);
+
+        assert_eq!(world.resource::<MinZoom>().0, 0.5);
+        assert_eq!(world.resource::<MaxZoom>().0, 2.0);
+        let events = world.resource::<Events<PreferencesError>>();
+        let errors: Vec<_> = events.iter_current_update_events().collect();
+        assert!(matches!(
+            errors[..],
+            [PreferencesError::ValidationFailed(_)]
+        ));
+
+        // A valid document is kept.
+        std::fs::write(dir.join("prefs.toml"), "[zoom]\nmin = 1.0\nmax = 1.5\n").unwrap();
+        crate::load::load_preferences(&mut world);
+        assert_eq!(world.resource::<MinZoom>().0, 1.0);
+        assert_eq!(world.resource::<MaxZoom>().0, 1.5);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_post_load_validator_runs_after_load_from_bytes() {
+        let dir = crate::tests::test_dir("post_load_validator_bytes");
+        let mut world = crate::tests::test_world(&dir);
+        world.init_resource::<Events<PreferencesError>>();
+        {
+            let mut registry = world.resource::<AppTypeRegistry>().write();
+            registry.register::<MinZoom>();
+            registry.register::<MaxZoom>();
+        }
+        world.insert_resource(MinZoom(0.5));
+        world.insert_resource(MaxZoom(2.0));
+        world.insert_resource(
+            PreferencesPostLoadValidator::new(|world| {
+                if world.resource::<MinZoom>().0 <= world.resource::<MaxZoom>().0 {
+                    Ok(())
+                } else {
+                    Err("zoom.min is greater than zoom.max".to_string())
+                }
+            })
+            .reverting(),
+        );
+        crate::load::load_preferences_from_bytes(&mut world, b"[zoom]\nmin = 4.0\nmax = 1.5\n")
+            .unwrap();
+
+        assert_eq!(world.resource::<MinZoom>().0, 0.5);
+        assert_eq!(world.resource::<MaxZoom>().0, 2.0);
+        let events = world.resource::<Events<PreferencesError>>();
+        assert_eq!(events.iter_current_update_events().count(), 1);
+    }
+}
@@ -1,6 +1,11 @@
 use bevy::{ecs::component::Tick, prelude::*, reflect::TypeInfo};
 
-use crate::{PreferencesChanged, PreferencesGroup, PreferencesKey, PreferencesSaveTick};
+use crate::{
+    registration::{
+        is_transparent_wrapper, preference_names, state_preference_names, PreferencesRegistrations,
+    },
+    PreferencesChanged, PreferencesSaveTick,
+};
 
 /// Watches for changes in preferences and sets the `PreferencesChanged` resource to true if any.
 pub fn watch_prefs_changes(world: &mut World) {
@@ -16,6 +21,7 @@ pub fn watch_prefs_changes(world: &mut World) {
 /// Returns true if any preference item changed since the given tick.
 pub fn is_changed_prefs(world: &World, last_run: Tick, this_run: Tick) -> bool {
     let registry = world.get_resource::<AppTypeRegistry>().unwrap().clone();
+    let registrations = world.get_resource::<PreferencesRegistrations>();
     for (res, _) in world.iter_resources() {
         if let Some(tid) = res.type_id() {
             if let Some(treg) = registry.read().get(tid) {
@@ -26,73 +32,23 @@ pub fn is_changed_prefs(world: &World, last_run: Tick, this_run: Tick) -> bool {
                 if !is_changed {
                     continue;
                 }
-                match treg.type_info() {
-                    TypeInfo::Struct(stty) => {
-                        let group_attr = stty.custom_attributes().get::<PreferencesGroup>();
-                        let key_attr = stty.custom_attributes().get::<PreferencesKey>();
-                        if group_attr.is_some() || key_attr.is_some() {
-                            return true;
-                        }
-                    }
-
-                    TypeInfo::TupleStruct(tsty) => {
-                        let group_attr = tsty.custom_attributes().get::<PreferencesGroup>();
-                        let key_attr = tsty.custom_attributes().get::<PreferencesKey>();
-                        if group_attr.is_some() || key_attr.is_some() {
-                            return true;
-                        } else if tsty
-                            .type_path()
-                            .starts_with("bevy_state::state::resources::State<")
-                        {
-                            let state_field = tsty.field_at(0).unwrap();
-                            let rr = registry.read();
-                            let Some(state_type) = rr.get(state_field.type_id()) else {
-                                continue;
-                            };
-                            match state_type.type_info() {
-                                TypeInfo::Struct(ts) => {
-                                    let group_attr =
-                                        ts.custom_attributes().get::<PreferencesGroup>();
-                                    let key_attr = ts.custom_attributes().get::<PreferencesKey>();
-                                    if (group_attr.is_some() || key_attr.is_some()) && is_changed {
-                                        return true;
-                                    }
-                                }
-
-                                TypeInfo::TupleStruct(ts) => {
-                                    let group_attr =
-                                        ts.custom_attributes().get::<PreferencesGroup>();
-                                    let key_attr = ts.custom_attributes().get::<PreferencesKey>();
-                                    if (group_attr.is_some() || key_attr.is_some()) && is_changed {
-                                        return true;
-                                    }
-                                }
-
-                                TypeInfo::Enum(ety) => {
-                                    let group_attr =
-                                        ety.custom_attributes().get::<PreferencesGroup>();
-                                    let key_attr = ety.custom_attributes().get::<PreferencesKey>();
-                                    if group_attr.is_some() || key_attr.is_some() {
-                                        return true;
-                                    }
-                                }
-
-                                // Other types cannot be preferences since they don't have attributes.
-                                _ => {}
-                            }
-                        }
-                    }
-
-                    TypeInfo::Enum(ety) => {
-                        let group_attr = ety.custom_attributes().get::<PreferencesGroup>();
-                        let key_attr = ety.custom_attributes().get::<PreferencesKey>();
+                let (group_attr, key_attr) = preference_names(registrations, treg.type_info());
+                if group_attr.is_some() || key_attr.is_some() {
+                    return true;
+                }
+                if let TypeInfo::TupleStruct(tsty) = treg.type_info() {
+                    if is_transparent_wrapper(tsty) {
+                        let state_field = tsty.field_at(0).unwrap();
+                        let rr = registry.read();
+                        let Some(state_type) = rr.get(state_field.type_id()) else {
+                            continue;
+                        };
+                        let (group_attr, key_attr) =
+                            state_preference_names(registrations, tid, state_type.type_info());
                         if group_attr.is_some() || key_attr.is_some() {
                             return true;
                         }
                     }
-
-                    // Other types cannot be preferences since they don't have attributes.
-                    _ => {}
                 }
             }
         }